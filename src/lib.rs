@@ -0,0 +1,91 @@
+//! Reusable pieces of the {{project-name}} MCP client.
+//!
+//! The functions here take a connected [`ClientSession`] and return the
+//! server's results instead of printing them, so other programs can embed
+//! the client rather than shelling out to the binary. They live in
+//! [`listing`], [`tools`], [`resources`] and [`prompts`], and are all
+//! re-exported here:
+//!
+//! ```no_run
+//! use mcp_protocol_sdk::{client::{ClientSession, McpClient}, transport::stdio::StdioClientTransport};
+//! use std::time::Duration;
+//!
+//! # async fn example() -> anyhow::Result<()> {
+//! let client = McpClient::with_capabilities("my-app".to_string(), "0.1.0".to_string(), Default::default());
+//! let session = ClientSession::new(client);
+//! let transport = StdioClientTransport::from_command(tokio::process::Command::new("./server")).await?;
+//! session.connect(transport).await?;
+//!
+//! let timeout = Duration::from_secs(30);
+//! for tool in {{crate_name}}::list_tools(&session, timeout, None).await?.tools {
+//!     println!("{}", tool.name);
+//! }
+//! let args = {{crate_name}}::parse_args(r#"{"message": "hi"}"#)?;
+//! let result = {{crate_name}}::call_tool(&session, timeout, "echo", args).await?;
+//! println!("{:?}", {{crate_name}}::tool_text(&result));
+//! # Ok(())
+//! # }
+//! ```
+
+use anyhow::Result;
+use mcp_protocol_sdk::client::{ClientSession, McpClient};
+use serde_json::{json, Value};
+use std::future::Future;
+use std::time::Duration;
+
+pub mod listing;
+pub mod prompts;
+pub mod resources;
+pub mod tools;
+
+pub use listing::{
+    collect_pages, list_prompts, list_resource_templates, list_resources, list_tools, Page,
+};
+pub use prompts::{chat_messages, get_prompt};
+pub use resources::{read_resource, read_resources};
+pub use tools::{
+    call_tool, call_tool_with, is_transient_error, is_transient_tool_error, parse_args,
+    retry_tool_call, tool_text, validate_args, validate_tool_args, CallOptions,
+};
+
+/// A request took longer than its timeout
+#[derive(Debug)]
+pub struct TimedOut(pub Duration);
+
+impl std::fmt::Display for TimedOut {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "request timed out after {} seconds", self.0.as_secs())
+    }
+}
+
+impl std::error::Error for TimedOut {}
+
+/// Await a request, failing with [`TimedOut`] if it takes longer than `timeout`
+pub async fn with_timeout<T, E, F>(timeout: Duration, fut: F) -> Result<T>
+where
+    F: Future<Output = std::result::Result<T, E>>,
+    anyhow::Error: From<E>,
+{
+    match tokio::time::timeout(timeout, fut).await {
+        Ok(result) => Ok(result?),
+        Err(_) => Err(TimedOut(timeout).into()),
+    }
+}
+
+/// The session's client, to send requests with. The session's lock is only
+/// held while taking the handle: clones share the connection, so a slow
+/// request doesn't keep other callers from sending theirs.
+pub async fn client_handle(session: &ClientSession) -> McpClient {
+    session.client().lock().await.clone()
+}
+
+/// Send `notifications/cancelled` for the request with id `request_id`, once
+/// the caller has stopped waiting for it. Servers may ignore the
+/// notification or have finished already; a late response is discarded.
+pub async fn cancel_request(client: &McpClient, request_id: Value, reason: &str) -> Result<()> {
+    let params = json!({ "requestId": request_id, "reason": reason });
+    client
+        .send_notification("notifications/cancelled".to_string(), Some(params))
+        .await?;
+    Ok(())
+}
@@ -0,0 +1,250 @@
+//! Listing what a server offers, following pagination
+
+use anyhow::Result;
+use mcp_protocol_sdk::{
+    client::ClientSession,
+    protocol::types::{
+        ListPromptsResult, ListResourceTemplatesResult, ListResourcesResult, ListToolsResult,
+    },
+};
+use std::collections::HashSet;
+use std::future::Future;
+use std::time::Duration;
+use tracing::{debug, warn};
+
+use crate::{client_handle, with_timeout};
+
+/// A page of a paginated list response
+pub trait Page {
+    fn next_cursor(&self) -> Option<String>;
+    /// Append the items of the following page
+    fn extend(&mut self, next: Self);
+    /// Forget the cursor to a further page, once no more will be fetched
+    fn clear_next_cursor(&mut self);
+}
+
+impl Page for ListToolsResult {
+    fn next_cursor(&self) -> Option<String> {
+        self.next_cursor.clone()
+    }
+
+    fn extend(&mut self, next: Self) {
+        self.tools.extend(next.tools);
+        self.next_cursor = next.next_cursor;
+    }
+
+    fn clear_next_cursor(&mut self) {
+        self.next_cursor = None;
+    }
+}
+
+impl Page for ListResourcesResult {
+    fn next_cursor(&self) -> Option<String> {
+        self.next_cursor.clone()
+    }
+
+    fn extend(&mut self, next: Self) {
+        self.resources.extend(next.resources);
+        self.next_cursor = next.next_cursor;
+    }
+
+    fn clear_next_cursor(&mut self) {
+        self.next_cursor = None;
+    }
+}
+
+impl Page for ListResourceTemplatesResult {
+    fn next_cursor(&self) -> Option<String> {
+        self.next_cursor.clone()
+    }
+
+    fn extend(&mut self, next: Self) {
+        self.resource_templates.extend(next.resource_templates);
+        self.next_cursor = next.next_cursor;
+    }
+
+    fn clear_next_cursor(&mut self) {
+        self.next_cursor = None;
+    }
+}
+
+impl Page for ListPromptsResult {
+    fn next_cursor(&self) -> Option<String> {
+        self.next_cursor.clone()
+    }
+
+    fn extend(&mut self, next: Self) {
+        self.prompts.extend(next.prompts);
+        self.next_cursor = next.next_cursor;
+    }
+
+    fn clear_next_cursor(&mut self) {
+        self.next_cursor = None;
+    }
+}
+
+/// Fetch the page at `cursor` if one is given; otherwise start at the first
+/// page and keep following `next_cursor` until the listing is complete, or
+/// until the server hands back a cursor it already gave. `timeout` applies
+/// to each page.
+pub async fn collect_pages<P, E, F, Fut>(
+    timeout: Duration,
+    cursor: Option<String>,
+    mut fetch: F,
+) -> Result<P>
+where
+    P: Page,
+    F: FnMut(Option<String>) -> Fut,
+    Fut: Future<Output = std::result::Result<P, E>>,
+    anyhow::Error: From<E>,
+{
+    let follow = cursor.is_none();
+    let mut result = with_timeout(timeout, fetch(cursor)).await?;
+    let mut seen = HashSet::new();
+    while follow {
+        let Some(next) = result.next_cursor() else {
+            break;
+        };
+        // A server that keeps returning the same cursor would otherwise be
+        // asked for the same page forever
+        if !seen.insert(next.clone()) {
+            warn!("Server repeated cursor {}, stopping pagination", next);
+            result.clear_next_cursor();
+            break;
+        }
+        debug!("Fetching next page at cursor {}", next);
+        let page = with_timeout(timeout, fetch(Some(next))).await?;
+        result.extend(page);
+    }
+    Ok(result)
+}
+
+/// Every tool the server offers, following pagination to the end, or just
+/// the page at `cursor` when one is given
+pub async fn list_tools(
+    session: &ClientSession,
+    timeout: Duration,
+    cursor: Option<String>,
+) -> Result<ListToolsResult> {
+    let client = client_handle(session).await;
+    collect_pages(timeout, cursor, |cursor| {
+        client.list_tools_with_cursor(cursor)
+    })
+    .await
+}
+
+/// Every resource the server offers, following pagination to the end, or just
+/// the page at `cursor` when one is given
+pub async fn list_resources(
+    session: &ClientSession,
+    timeout: Duration,
+    cursor: Option<String>,
+) -> Result<ListResourcesResult> {
+    let client = client_handle(session).await;
+    collect_pages(timeout, cursor, |cursor| {
+        client.list_resources_with_cursor(cursor)
+    })
+    .await
+}
+
+/// Every resource URI template the server offers, following pagination to
+/// the end, or just the page at `cursor` when one is given
+pub async fn list_resource_templates(
+    session: &ClientSession,
+    timeout: Duration,
+    cursor: Option<String>,
+) -> Result<ListResourceTemplatesResult> {
+    let client = client_handle(session).await;
+    collect_pages(timeout, cursor, |cursor| {
+        client.list_resource_templates_with_cursor(cursor)
+    })
+    .await
+}
+
+/// Every prompt the server offers, following pagination to the end, or just
+/// the page at `cursor` when one is given
+pub async fn list_prompts(
+    session: &ClientSession,
+    timeout: Duration,
+    cursor: Option<String>,
+) -> Result<ListPromptsResult> {
+    let client = client_handle(session).await;
+    collect_pages(timeout, cursor, |cursor| {
+        client.list_prompts_with_cursor(cursor)
+    })
+    .await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use anyhow::bail;
+
+    /// A listing of numbers, for exercising `collect_pages`
+    struct Numbers {
+        numbers: Vec<u32>,
+        next_cursor: Option<String>,
+    }
+
+    impl Page for Numbers {
+        fn next_cursor(&self) -> Option<String> {
+            self.next_cursor.clone()
+        }
+
+        fn extend(&mut self, next: Self) {
+            self.numbers.extend(next.numbers);
+            self.next_cursor = next.next_cursor;
+        }
+
+        fn clear_next_cursor(&mut self) {
+            self.next_cursor = None;
+        }
+    }
+
+    /// Pages `1`, `2`, `3` behind cursors `None`, `"2"`, `"3"`, where the
+    /// last page points back at `last_cursor`
+    async fn numbers_page(cursor: Option<String>, last_cursor: Option<&str>) -> Result<Numbers> {
+        let (number, next_cursor) = match cursor.as_deref() {
+            None => (1, Some("2")),
+            Some("2") => (2, Some("3")),
+            Some("3") => (3, last_cursor),
+            Some(other) => bail!("unknown cursor {}", other),
+        };
+        Ok(Numbers {
+            numbers: vec![number],
+            next_cursor: next_cursor.map(str::to_string),
+        })
+    }
+
+    #[tokio::test]
+    async fn test_collect_pages() {
+        let timeout = Duration::from_secs(1);
+        let all: Numbers = collect_pages(timeout, None, |cursor| numbers_page(cursor, None))
+            .await
+            .unwrap();
+        assert_eq!(all.numbers, [1, 2, 3]);
+
+        // A cursor only fetches that page
+        let page: Numbers = collect_pages(timeout, Some("2".to_string()), |cursor| {
+            numbers_page(cursor, None)
+        })
+        .await
+        .unwrap();
+        assert_eq!(page.numbers, [2]);
+        assert_eq!(page.next_cursor.as_deref(), Some("3"));
+    }
+
+    #[tokio::test]
+    async fn test_collect_pages_stops_at_repeated_cursor() {
+        let timeout = Duration::from_secs(1);
+        // The last page hands back its own cursor, then one from earlier
+        for repeated in ["3", "2"] {
+            let all: Numbers =
+                collect_pages(timeout, None, |cursor| numbers_page(cursor, Some(repeated)))
+                    .await
+                    .unwrap();
+            assert_eq!(all.numbers, [1, 2, 3]);
+            assert_eq!(all.next_cursor, None);
+        }
+    }
+}
@@ -2,53 +2,792 @@
 //!
 //! {{description}}
 
-use anyhow::Result;
-use clap::{Parser, Subcommand};
+use anyhow::{anyhow, bail, Context, Result};
+use base64::{engine::general_purpose::STANDARD as BASE64, Engine as _};
+use clap::{
+    parser::ValueSource, ArgMatches, CommandFactory, FromArgMatches, Parser, Subcommand, ValueEnum,
+};
+use {{crate_name}}::{
+    chat_messages, client_handle, list_prompts as fetch_prompts,
+    list_resource_templates as fetch_resource_templates, list_resources as fetch_resources,
+    list_tools as fetch_tools, parse_args, retry_tool_call, tool_text, validate_tool_args,
+    with_timeout, TimedOut,
+};
+use indicatif::ProgressBar;
+#[cfg(feature = "http")]
+use mcp_protocol_sdk::transport::http::HttpClientTransport;
+#[cfg(feature = "websocket")]
+use mcp_protocol_sdk::transport::websocket::WebSocketClientTransport;
 use mcp_protocol_sdk::{
     client::{ClientSession, McpClient},
-    transport::stdio::StdioClientTransport,
+    core::error::{McpError, McpResult},
+    protocol::types::{
+        CallToolResult, ClientCapabilities, CompletionArgument, CompletionReference,
+        InitializeResult, JsonRpcNotification, JsonRpcRequest, JsonRpcResponse, ListPromptsResult,
+        ListResourceTemplatesResult, ListResourcesResult, ListToolsResult, ReadResourceResult,
+        RootsCapability, SamplingCapability, Tool,
+    },
+    transport::{stdio::StdioClientTransport, traits::Transport as McpTransport},
+};
+use owo_colors::{OwoColorize, Style};
+use rustyline::{
+    completion::{Completer, Pair},
+    error::ReadlineError,
+    highlight::Highlighter,
+    hint::Hinter,
+    history::DefaultHistory,
+    validate::Validator,
+    Editor, ExternalPrinter, Helper,
 };
+use serde::{Deserialize, Serialize};
 use serde_json::{json, Value};
-use std::collections::HashMap;
-use tracing::{error, info};
+use std::borrow::Cow;
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::future::Future;
+use std::io::{BufWriter, IsTerminal, Read, Write};
+use std::path::{Path, PathBuf};
+use std::pin::Pin;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::{Arc, OnceLock};
+use std::time::{Duration, Instant};
+use tokio::io::{AsyncBufReadExt, BufReader};
+use tokio::sync::{mpsc, Mutex, Semaphore};
+use tokio::task::{JoinHandle, JoinSet};
+use tracing::{debug, error, info, warn};
+
+/// Like `println!`, but writes to the command output: stdout, or the file
+/// given with `--output-file`
+macro_rules! outln {
+    () => {
+        write_output(format_args!(""))
+    };
+    ($($arg:tt)*) => {
+        write_output(format_args!($($arg)*))
+    };
+}
 
 #[derive(Parser)]
 #[command(name = "{{project-name}}")]
 #[command(about = "{{description}}")]
 #[command(version)]
 struct Cli {
-    /// Server command to execute
+    /// Config file with default settings [default: <config dir>/{{project-name}}/config.toml]
+    #[arg(long, value_name = "PATH")]
+    config: Option<PathBuf>,
+
+    /// Server to connect to: a command, or an endpoint with a stdio:, http://,
+    /// https://, ws://, wss://, or tcp:// scheme (repeat to connect to several)
     #[arg(short, long, default_value = "./server")]
-    server: String,
+    server: Vec<String>,
+
+    /// Extra argument passed to the server process (repeat for multiple)
+    #[arg(long = "server-arg", value_name = "ARG", allow_hyphen_values = true)]
+    server_args: Vec<String>,
+
+    /// Environment variable for the server process, as KEY=VALUE (repeatable)
+    #[arg(long = "env", value_name = "KEY=VALUE", value_parser = parse_env_var)]
+    server_env: Vec<(String, String)>,
+
+    /// Seconds to wait for the initialize handshake, separate from --timeout
+    /// [default: no limit]
+    #[arg(long, value_name = "SECONDS")]
+    init_timeout: Option<u64>,
+
+    /// Directory to offer the server as a root it may work in (repeatable)
+    #[arg(long = "root", value_name = "PATH")]
+    roots: Vec<PathBuf>,
+
+    /// Wait for the server to print this text on stderr before initializing
+    /// (stdio transport only)
+    #[arg(long, value_name = "SUBSTRING")]
+    server_ready_probe: Option<String>,
+
+    /// Where a stdio server's stderr goes: inherit (shown with the client's
+    /// own), null (discarded), or a file path to append it to
+    #[arg(long, value_name = "inherit|null|PATH", default_value = "inherit", value_parser = parse_server_stderr)]
+    server_stderr: ServerStderr,
+
+    /// Working directory for the server process [default: the current directory]
+    #[arg(long, value_name = "PATH")]
+    working_dir: Option<PathBuf>,
+
+    /// Transport used to connect to the server
+    #[arg(long, value_enum, default_value_t = Transport::Stdio)]
+    transport: Transport,
+
+    /// Server URL (required for the http and websocket transports)
+    #[arg(long)]
+    url: Option<String>,
+
+    /// Server address as host:port (required for the tcp transport)
+    #[arg(long, value_name = "HOST:PORT")]
+    addr: Option<String>,
+
+    /// Header to send with http and websocket requests, as "Name: Value" (repeatable)
+    #[arg(long = "header", value_name = "NAME: VALUE", value_parser = parse_header)]
+    headers: Vec<Header>,
+
+    /// Token to send as an "Authorization: Bearer" header
+    #[arg(long, value_name = "TOKEN")]
+    bearer: Option<String>,
 
     /// Enable verbose logging
     #[arg(short, long)]
     verbose: bool,
 
+    /// Log each JSON-RPC request and its response in full, whatever the log level
+    #[arg(long)]
+    trace_protocol: bool,
+
+    /// With --trace-protocol, hide the values of fields whose names contain
+    /// any of these, ignoring case (comma-separated or repeated)
+    #[arg(
+        long,
+        value_name = "NAMES",
+        value_delimiter = ',',
+        requires = "trace_protocol"
+    )]
+    redact: Vec<String>,
+
+    /// Only log warnings and errors and skip listing summaries, leaving just
+    /// the command results
+    #[arg(short, long, conflicts_with = "verbose")]
+    quiet: bool,
+
+    /// Append log output to this file instead of stderr
+    #[arg(long, value_name = "PATH")]
+    log_file: Option<PathBuf>,
+
+    /// How log lines are written
+    #[arg(long, value_enum, default_value_t = LogFormat::Text)]
+    log_format: LogFormat,
+
+    /// Write command results to this file instead of stdout
+    #[arg(long, value_name = "PATH")]
+    output_file: Option<PathBuf>,
+
+    /// Append to --output-file instead of truncating it
+    #[arg(long, requires = "output_file")]
+    append: bool,
+
+    /// Record every request sent to the server and its response to this
+    /// file as NDJSON, for `replay`
+    #[arg(long, value_name = "PATH")]
+    record: Option<PathBuf>,
+
+    /// Output format for command results
+    #[arg(short, long, value_enum, default_value_t = OutputFormat::Text)]
+    output: OutputFormat,
+
+    /// Indent json output [default: when writing to a terminal]
+    #[arg(long, conflicts_with = "compact")]
+    pretty: bool,
+
+    /// Print json output on a single line [default: when piped or with --output-file]
+    #[arg(long)]
+    compact: bool,
+
+    /// When to colorize text output
+    #[arg(long, value_enum, default_value_t = ColorChoice::Auto)]
+    color: ColorChoice,
+
+    /// Never colorize output; shorthand for --color never
+    #[arg(long, conflicts_with = "color")]
+    no_color: bool,
+
+    /// How to display text content returned by tools
+    #[arg(long, value_enum, default_value_t = ContentFormat::Raw)]
+    format_content: ContentFormat,
+
+    /// Print text content as-is even when --format-content would render it
+    #[arg(long)]
+    no_render: bool,
+
+    /// Print how long each command took, and for one-shot commands how long
+    /// starting the transport and the initialize handshake took
+    #[arg(long)]
+    timing: bool,
+
+    /// Run the command this many times on one session and report latency
+    #[arg(long, value_name = "N", value_parser = clap::value_parser!(u32).range(1..))]
+    repeat: Option<u32>,
+
+    /// Seconds to wait for each request before giving up
+    #[arg(long, default_value_t = 30)]
+    timeout: u64,
+
+    /// Cut printed text content off after this many bytes (json and yaml
+    /// output is never truncated)
+    #[arg(long, value_name = "BYTES")]
+    max_response_bytes: Option<usize>,
+
+    /// Print only the value at this path in the result, e.g. content[0].text
+    #[arg(long, value_name = "PATH", value_parser = parse_json_path)]
+    select: Option<JsonPath>,
+
+    /// Send tool arguments without checking them against the tool's input schema
+    #[arg(long)]
+    no_validate: bool,
+
+    /// Call tools annotated as destructive without asking first
+    #[arg(short = 'y', long)]
+    assume_yes: bool,
+
+    /// Shell command that gets each tool result on stdin; what it prints is
+    /// shown instead of the result
+    #[arg(long, value_name = "CMD")]
+    post_process: Option<String>,
+
+    /// On Ctrl-C, ask the server to cancel the tool call in flight and
+    /// disconnect cleanly instead of exiting at once. Always on in interactive mode.
+    #[arg(long)]
+    cancel_on_interrupt: bool,
+
+    /// Times to try reconnecting when the connection to a server drops
+    #[arg(long, default_value_t = 3)]
+    max_retries: u32,
+
+    /// Times to retry a tool call that fails with a transient error
+    #[arg(long, value_name = "N", default_value_t = 0)]
+    retry: u32,
+
+    /// Answer the server's sampling requests by piping each one, as JSON, to this
+    /// program and reading the result JSON from its stdout
+    #[arg(long, value_name = "PROGRAM")]
+    sampling_command: Option<PathBuf>,
+
+    /// Print text chunks the server sends as this notification while a tool
+    /// call runs, instead of waiting for the result
+    #[arg(long, value_name = "METHOD")]
+    stream_notification: Option<String>,
+
+    /// Answer the server's sampling requests by typing a reply on the terminal
+    #[arg(long, conflicts_with = "sampling_command")]
+    sampling_prompt: bool,
+
+    /// Substitute ${VAR} and ${VAR:-default} in tool and prompt arguments
+    /// from the environment
+    #[arg(long)]
+    expand_env: bool,
+
+    /// Character encoding of --args-file, e.g. latin1 or windows-1252
+    #[arg(long, value_name = "ENCODING", default_value = "utf-8", value_parser = parse_encoding)]
+    input_encoding: &'static encoding_rs::Encoding,
+
+    /// Save complete tool, resource and prompt listings here for --offline
+    #[arg(long, value_name = "PATH")]
+    cache_dir: Option<PathBuf>,
+
+    /// Answer list commands from --cache-dir instead of connecting to the server
+    #[arg(long, requires = "cache_dir")]
+    offline: bool,
+
+    /// Print the JSON-RPC request for tool calls, resource reads and prompts
+    /// instead of sending it
+    #[arg(long)]
+    dry_run: bool,
+
+    /// Ask the server to send log messages at this level and above
+    #[arg(long, value_enum, value_name = "LEVEL")]
+    server_log_level: Option<ServerLogLevel>,
+
+    /// Protocol revision to send in the initialize request, e.g. 2024-11-05
+    #[arg(long, value_name = "VERSION")]
+    protocol_version: Option<String>,
+
+    /// Client name to report to the server in the initialize request
+    #[arg(long, value_name = "NAME", default_value = "{{project-name}}")]
+    client_name: String,
+
+    /// Client version to report to the server in the initialize request
+    #[arg(long, value_name = "VERSION", default_value = env!("CARGO_PKG_VERSION"))]
+    client_version: String,
+
+    /// Debugging aid: number requests PREFIX-1, PREFIX-2, ... instead of
+    /// 1, 2, ..., so this run's requests can be found in server logs
+    #[arg(long, value_name = "PREFIX", hide = true)]
+    json_rpc_id: Option<String>,
+
+    /// With several servers, report any that can't be reached or fail a
+    /// listing, carry on with the rest, and exit non-zero at the end
+    #[arg(long, conflicts_with = "fail_fast")]
+    keep_going: bool,
+
+    /// Stop at the first server that fails [default]
+    #[arg(long)]
+    fail_fast: bool,
+
+    /// With text output, explain well-known JSON-RPC error codes below the
+    /// server's error message
+    #[arg(long)]
+    pretty_errors: bool,
+
+    /// Times to try the initial connection, for servers that are slow to start
+    #[arg(long, default_value_t = 3, value_parser = clap::value_parser!(u32).range(1..))]
+    connect_attempts: u32,
+
+    /// Milliseconds to wait between initial connection attempts
+    #[arg(long, default_value_t = 500, value_name = "MS")]
+    connect_delay: u64,
+
+    /// Also treat tool results without any content, or calls during which
+    /// the server logged a warning or worse, as failures
+    #[arg(long)]
+    strict: bool,
+
+    /// Directory to write image and binary content to instead of summarizing it
+    #[arg(long)]
+    out_dir: Option<PathBuf>,
+
     #[command(subcommand)]
     command: Commands,
 }
 
-#[derive(Subcommand)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq, ValueEnum, Deserialize)]
+#[serde(rename_all = "lowercase")]
+enum Transport {
+    /// Spawn the server as a subprocess and talk over stdin/stdout
+    Stdio,
+    /// Connect to a server exposed over HTTP
+    Http,
+    /// Connect to a server behind a ws:// or wss:// endpoint
+    Websocket,
+    /// Connect to a server listening on a TCP port (see --addr)
+    Tcp,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, ValueEnum, Deserialize)]
+#[serde(rename_all = "lowercase")]
+enum OutputFormat {
+    /// Human-readable output
+    Text,
+    /// Raw result structs serialized as JSON
+    Json,
+    /// Raw result structs serialized as YAML
+    Yaml,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, ValueEnum)]
+enum ColorChoice {
+    /// Colorize only when writing to a terminal
+    Auto,
+    Always,
+    Never,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, ValueEnum)]
+enum LogFormat {
+    /// Human-readable lines
+    Text,
+    /// One JSON object per line, with timestamp, level, target and fields
+    Json,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, ValueEnum)]
+enum ContentFormat {
+    /// Print text exactly as the server sent it
+    Raw,
+    /// Render Markdown (headers, emphasis, lists, code blocks) for the terminal
+    Markdown,
+}
+
+/// Severity levels of the MCP logging capability (RFC 5424)
+#[derive(Clone, Copy, Debug, PartialEq, Eq, ValueEnum, Serialize)]
+#[serde(rename_all = "lowercase")]
+enum ServerLogLevel {
+    Debug,
+    Info,
+    Notice,
+    Warning,
+    Error,
+    Critical,
+    Alert,
+    Emergency,
+}
+
+impl ColorChoice {
+    fn enabled(self, is_terminal: bool) -> bool {
+        self.resolve(is_terminal, |name| std::env::var(name).ok())
+    }
+
+    /// Decide whether to color, following the NO_COLOR and CLICOLOR
+    /// conventions when the choice is left to `auto`
+    fn resolve(self, is_terminal: bool, env: impl Fn(&str) -> Option<String>) -> bool {
+        match self {
+            ColorChoice::Always => true,
+            ColorChoice::Never => false,
+            ColorChoice::Auto => {
+                if env("NO_COLOR").is_some_and(|v| !v.is_empty()) {
+                    false
+                } else if env("CLICOLOR_FORCE").is_some_and(|v| !v.is_empty() && v != "0") {
+                    true
+                } else if env("CLICOLOR").is_some_and(|v| v == "0") {
+                    false
+                } else {
+                    is_terminal
+                }
+            }
+        }
+    }
+}
+
+impl Cli {
+    /// Whether this is a `call-tool --stdin-json` subprocess call
+    fn stdin_json(&self) -> bool {
+        matches!(
+            self.command,
+            Commands::CallTool {
+                stdin_json: true,
+                ..
+            }
+        )
+    }
+
+    /// The color choice after applying --no-color
+    fn color_choice(&self) -> ColorChoice {
+        if self.no_color {
+            ColorChoice::Never
+        } else {
+            self.color
+        }
+    }
+}
+
+/// Defaults read from the config file; flags given on the command line win
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+struct Config {
+    server: Option<ServerList>,
+    transport: Option<Transport>,
+    url: Option<String>,
+    timeout: Option<u64>,
+    output: Option<OutputFormat>,
+}
+
+/// `server = "./server"` or `server = ["./a", "./b"]`
+#[derive(Debug, Deserialize)]
+#[serde(untagged)]
+enum ServerList {
+    One(String),
+    Many(Vec<String>),
+}
+
+impl Config {
+    /// Load `path`, or the default config file if it exists
+    fn load(path: Option<&Path>) -> Result<Self> {
+        let path = match path {
+            Some(path) => path.to_path_buf(),
+            None => match default_config_path() {
+                Some(path) if path.exists() => path,
+                _ => return Ok(Self::default()),
+            },
+        };
+        let contents = std::fs::read_to_string(&path)
+            .with_context(|| format!("failed to read config file {}", path.display()))?;
+        toml::from_str(&contents)
+            .with_context(|| format!("failed to parse config file {}", path.display()))
+    }
+}
+
+fn default_config_path() -> Option<PathBuf> {
+    dirs::config_dir().map(|dir| dir.join("{{project-name}}").join("config.toml"))
+}
+
+impl Cli {
+    /// Parse the command line and fill in anything not given there from the config file
+    fn load() -> Result<Self> {
+        let matches = Cli::command().get_matches();
+        let mut cli = Cli::from_arg_matches(&matches)?;
+        let config = Config::load(cli.config.as_deref())?;
+        cli.apply_config(config, &matches);
+        Ok(cli)
+    }
+
+    fn apply_config(&mut self, config: Config, matches: &ArgMatches) {
+        // Only values that came from clap's defaults may be replaced
+        let defaulted = |id: &str| matches.value_source(id) != Some(ValueSource::CommandLine);
+
+        if let Some(server) = config.server {
+            if defaulted("server") {
+                self.server = match server {
+                    ServerList::One(server) => vec![server],
+                    ServerList::Many(servers) => servers,
+                };
+            }
+        }
+        if let Some(transport) = config.transport {
+            if defaulted("transport") {
+                self.transport = transport;
+            }
+        }
+        if config.url.is_some() && defaulted("url") {
+            self.url = config.url;
+        }
+        if let Some(timeout) = config.timeout {
+            if defaulted("timeout") {
+                self.timeout = timeout;
+            }
+        }
+        if let Some(output) = config.output {
+            if defaulted("output") {
+                self.output = output;
+            }
+        }
+    }
+}
+
+/// Settings shared by every command handler
+#[derive(Clone, Debug)]
+struct Options {
+    output: OutputFormat,
+    /// Indent json output rather than printing it on one line
+    pretty: bool,
+    timeout: Duration,
+    out_dir: Option<PathBuf>,
+    strict: bool,
+    color: bool,
+    max_retries: u32,
+    /// Retries for tool calls failing with a transient error
+    tool_retries: u32,
+    timing: bool,
+    validate: bool,
+    /// Call destructive tools without confirmation
+    assume_yes: bool,
+    /// Render text tool results as Markdown
+    markdown: bool,
+    /// Syntax-highlight resource text by MIME type
+    highlight: bool,
+    /// Animate a spinner on stderr while a request is in flight
+    spinner: bool,
+    server_log_level: Option<ServerLogLevel>,
+    dry_run: bool,
+    expand_env: bool,
+    /// `--input-encoding`, for argument files
+    input_encoding: &'static encoding_rs::Encoding,
+    cache_dir: Option<PathBuf>,
+    max_response_bytes: Option<usize>,
+    /// `--select`: print one value from the result instead of all of it
+    select: Option<JsonPath>,
+    /// `--quiet`: leave out extras such as listing summaries
+    quiet: bool,
+    /// Shell command tool results are piped through before printing
+    post_process: Option<String>,
+    /// `--keep-going`: skip servers that fail instead of stopping
+    keep_going: bool,
+    /// `--pretty-errors`: explain JSON-RPC error codes in text output
+    pretty_errors: bool,
+}
+
+impl Options {
+    fn from_cli(cli: &Cli) -> Self {
+        // Escape codes would end up in the file, whatever the environment says
+        let color = cli.color_choice().enabled(std::io::stdout().is_terminal())
+            && cli.output_file.is_none();
+        Self {
+            // `--stdin-json` promises JSON on stdout, failures included
+            output: if cli.stdin_json() {
+                OutputFormat::Json
+            } else {
+                cli.output
+            },
+            pretty: cli.pretty
+                || (!cli.compact && cli.output_file.is_none() && std::io::stdout().is_terminal()),
+            timeout: Duration::from_secs(cli.timeout),
+            out_dir: cli.out_dir.clone(),
+            strict: cli.strict,
+            color,
+            max_retries: cli.max_retries,
+            tool_retries: cli.retry,
+            timing: cli.timing,
+            validate: !cli.no_validate,
+            assume_yes: cli.assume_yes,
+            // Rendering and highlighting are colored output too, so they
+            // follow the same choice; piped output stays raw
+            markdown: cli.format_content == ContentFormat::Markdown && !cli.no_render && color,
+            highlight: color,
+            // Only the REPL waits on a person; one-shot commands are often scripted
+            spinner: matches!(cli.command, Commands::Interactive { .. })
+                && !cli.quiet
+                && cli.output_file.is_none()
+                && std::io::stdout().is_terminal()
+                && std::io::stderr().is_terminal(),
+            server_log_level: cli.server_log_level,
+            dry_run: cli.dry_run,
+            expand_env: cli.expand_env,
+            input_encoding: cli.input_encoding,
+            cache_dir: cli.cache_dir.clone(),
+            max_response_bytes: cli.max_response_bytes,
+            select: cli.select.clone(),
+            quiet: cli.quiet,
+            post_process: cli.post_process.clone(),
+            keep_going: cli.keep_going,
+            pretty_errors: cli.pretty_errors,
+        }
+    }
+
+    /// Text content as it should be printed, cut off at --max-response-bytes
+    fn clip<'a>(&self, text: &'a str) -> Cow<'a, str> {
+        match self.max_response_bytes {
+            Some(limit) => truncate_text(text, limit),
+            None => Cow::Borrowed(text),
+        }
+    }
+
+    /// Whether results are serialized (json or yaml, or for `--select`)
+    /// rather than formatted for humans
+    fn structured(&self) -> bool {
+        self.output != OutputFormat::Text || self.select.is_some()
+    }
+
+    fn paint(&self, text: &str, style: Style) -> String {
+        if self.color {
+            text.style(style).to_string()
+        } else {
+            text.to_string()
+        }
+    }
+
+    /// Names of tools, resources, prompts and servers
+    fn name(&self, text: &str) -> String {
+        self.paint(text, Style::new().bold())
+    }
+
+    /// Secondary text such as descriptions
+    fn dim(&self, text: &str) -> String {
+        self.paint(text, Style::new().dimmed())
+    }
+
+    fn failure(&self, text: &str) -> String {
+        self.paint(text, Style::new().red().bold())
+    }
+}
+
+#[derive(Subcommand, Clone)]
 enum Commands {
     /// List available tools from the server
-    ListTools,
+    ListTools {
+        /// Also print each tool's input schema
+        #[arg(long)]
+        schemas: bool,
+        /// Fetch only the page starting at this cursor instead of every page
+        #[arg(long)]
+        cursor: Option<String>,
+        /// Print only how many there are
+        #[arg(long, conflicts_with = "cursor")]
+        count: bool,
+    },
+    /// List tools whose name or description contains a search term
+    SearchTools {
+        /// Text to look for, ignoring case
+        query: String,
+        /// Treat the query as a regular expression
+        #[arg(long)]
+        regex: bool,
+    },
+    /// Print every tool with its schemas and annotations as one document,
+    /// sorted by name so exports can be diffed
+    ExportTools {
+        /// Describe the tools as an OpenAPI 3.1 document, one operation per tool
+        #[arg(long)]
+        openapi: bool,
+    },
     /// List available resources from the server
-    ListResources,
+    ListResources {
+        /// Fetch only the page starting at this cursor instead of every page
+        #[arg(long)]
+        cursor: Option<String>,
+        /// Print only how many there are
+        #[arg(long, conflicts_with = "cursor")]
+        count: bool,
+    },
+    /// List resource URI templates from the server
+    ListResourceTemplates {
+        /// Fetch only the page starting at this cursor instead of every page
+        #[arg(long)]
+        cursor: Option<String>,
+        /// Print only how many there are
+        #[arg(long, conflicts_with = "cursor")]
+        count: bool,
+    },
     /// List available prompts from the server
-    ListPrompts,
+    ListPrompts {
+        /// Fetch only the page starting at this cursor instead of every page
+        #[arg(long)]
+        cursor: Option<String>,
+        /// Print only how many there are
+        #[arg(long, conflicts_with = "cursor")]
+        count: bool,
+    },
     /// Call a tool with the given arguments
     CallTool {
         /// Tool name to call
-        tool: String,
+        #[arg(required_unless_present = "stdin_json")]
+        tool: Option<String>,
         /// JSON arguments for the tool
         #[arg(short, long, default_value = "{}")]
         args: String,
+        /// Read JSON arguments from a file ("-" for stdin)
+        #[arg(long, conflicts_with = "args")]
+        args_file: Option<PathBuf>,
+        /// Read the whole call as one `{"name", "arguments"}` JSON object from
+        /// stdin and write the result as JSON, logging only errors
+        #[arg(long, conflicts_with_all = ["tool", "args", "args_file"])]
+        stdin_json: bool,
+        /// Read a file ("-" for stdin), base64-encode it, and pass it as the
+        /// argument NAME (repeatable)
+        #[arg(long = "arg-file", value_name = "NAME=PATH", value_parser = parse_arg_file, conflicts_with = "stdin_json")]
+        arg_files: Vec<ArgFile>,
+        /// Call the tool again until the value at PATH in its result equals
+        /// VALUE (JSON, or a plain string)
+        #[arg(long, value_name = "PATH=VALUE", value_parser = parse_until)]
+        until: Option<Until>,
+        /// Seconds between calls with --until
+        #[arg(long, value_name = "SECONDS", default_value_t = 2, requires = "until")]
+        interval: u64,
+        /// Calls to make with --until before giving up
+        #[arg(long, value_name = "N", default_value_t = 30, requires = "until", value_parser = clap::value_parser!(u32).range(1..))]
+        max_attempts: u32,
+    },
+    /// Call several tools in order over one connection
+    CallSequence {
+        /// Tool call as TOOL or TOOL:{JSON arguments} (repeatable)
+        #[arg(
+            long = "call",
+            value_name = "TOOL:ARGS",
+            required_unless_present = "file"
+        )]
+        calls: Vec<String>,
+        /// JSON array of {"name", "arguments"} objects to call after any --call
+        #[arg(long, value_name = "PATH")]
+        file: Option<PathBuf>,
     },
-    /// Read a resource
+    /// Call a tool once per JSON argument object read from stdin (NDJSON)
+    CallMany {
+        /// Tool name to call
+        tool: String,
+        /// Maximum number of calls in flight at once
+        #[arg(long, default_value_t = 1)]
+        concurrency: usize,
+    },
+    /// Read one or more resources
     ReadResource {
-        /// Resource URI to read
-        uri: String,
+        /// Resource URIs to read
+        #[arg(required_unless_present = "uris_file")]
+        uris: Vec<String>,
+        /// Also read the URIs listed in this file, one per line ("-" for
+        /// stdin); blank lines and lines starting with # are skipped
+        #[arg(long, value_name = "PATH")]
+        uris_file: Option<PathBuf>,
+        /// Print only the first N lines of text content
+        #[arg(long, value_name = "N", conflicts_with = "tail")]
+        head: Option<usize>,
+        /// Print only the last N lines of text content
+        #[arg(long, value_name = "N")]
+        tail: Option<usize>,
+        /// Write each resource's content to its own file in this directory,
+        /// named after its URI, and print a manifest instead
+        #[arg(long, value_name = "PATH")]
+        split_dir: Option<PathBuf>,
     },
     /// Get a prompt
     GetPrompt {
@@ -57,324 +796,9109 @@ enum Commands {
         /// JSON arguments for the prompt
         #[arg(short, long, default_value = "{}")]
         args: String,
+        /// Read JSON arguments from a file ("-" for stdin)
+        #[arg(long, conflicts_with = "args")]
+        args_file: Option<PathBuf>,
+        /// Print only the messages, as a chat API style `[{role, content}]` array
+        #[arg(long)]
+        as_messages: bool,
+    },
+    /// Ask the server to suggest values for a prompt or resource template argument
+    Complete {
+        /// Prompt name, or resource template URI with --resource
+        ref_name: String,
+        /// Name of the argument to complete
+        argument: String,
+        /// Partial value typed so far
+        #[arg(default_value = "")]
+        value: String,
+        /// Treat the reference as a resource template URI instead of a prompt name
+        #[arg(long)]
+        resource: bool,
+    },
+    /// Re-send the requests in a `--record` file and report responses that differ
+    Replay {
+        /// NDJSON recording made with --record
+        path: PathBuf,
+    },
+    /// Compare two servers (the first two --server targets), such as an
+    /// original and its rewrite: their tools and schemas, and the results of
+    /// the given calls
+    DiffServers {
+        /// Tool call to make on both servers, as TOOL or TOOL:{JSON arguments}
+        /// (repeatable). No other tools are called, since calls can have side effects
+        #[arg(long = "call", value_name = "TOOL:ARGS")]
+        calls: Vec<String>,
+    },
+    /// Send an arbitrary JSON-RPC request and print the raw response
+    Raw {
+        /// JSON-RPC method name, e.g. "tools/list"
+        method: String,
+        /// JSON params for the request
+        params: Option<String>,
+    },
+    /// Print a resource and re-print it whenever it changes, until Ctrl-C
+    Watch {
+        /// Resource URI to watch
+        uri: String,
+        /// Polling interval when the server doesn't support subscriptions
+        #[arg(long, value_name = "SECONDS", default_value_t = 5, value_parser = clap::value_parser!(u64).range(1..))]
+        interval: u64,
+    },
+    /// Check that the server is responsive and report round-trip latency
+    Ping,
+    /// Show the capabilities the server advertised when connecting
+    Capabilities,
+    /// Show the roots offered to servers with --root
+    Roots,
+    /// Only perform the initialize handshake and report its details; exits
+    /// non-zero if the server fails to initialize
+    Init,
+    /// Print a shell completion script for this client to stdout
+    Completions {
+        /// Shell to generate completions for
+        #[arg(value_enum)]
+        shell: clap_complete::Shell,
+    },
+    /// Run interactive-style commands from a script file, one per line
+    Batch {
+        /// Script to execute; lines starting with `#` are comments
+        script: PathBuf,
+        /// Keep going after a command fails instead of stopping
+        #[arg(long)]
+        continue_on_error: bool,
     },
     /// Interactive mode
-    Interactive,
+    Interactive {
+        /// Ping the server every N seconds to keep idle sessions alive
+        #[arg(long, value_name = "SECONDS", value_parser = clap::value_parser!(u64).range(1..))]
+        keepalive: Option<u64>,
+        /// Most background jobs (commands ending in `&`) to run at once; the rest wait their turn
+        #[arg(long, default_value_t = 4)]
+        concurrency: usize,
+        /// Disconnect and exit after N seconds without a command
+        #[arg(long, value_name = "SECONDS", value_parser = clap::value_parser!(u64).range(1..))]
+        idle_timeout: Option<u64>,
+    },
 }
 
 #[tokio::main]
 async fn main() -> Result<()> {
-    let cli = Cli::parse();
+    let cli = Cli::load()?;
 
-    // Initialize logging
-    let log_level = if cli.verbose { "debug" } else { "info" };
-    tracing_subscriber::fmt()
-        .with_env_filter(format!("{{project-name}}={},mcp_protocol_sdk=info", log_level))
-        .init();
+    init_logging(&cli)?;
+    init_output(cli.output_file.as_deref(), cli.append)?;
+    init_recording(cli.record.as_deref())?;
 
-    info!("Starting {{project-name}} MCP client...");
+    let opts = Options::from_cli(&cli);
+    let result = run(cli).await;
 
-    // Create client and session
-    let client = McpClient::new("{{project-name}}".to_string(), "0.1.0".to_string());
-    let session = ClientSession::new(client);
+    // Scripts using structured output get the failure on stdout in the same format
+    if let Err(e) = &result {
+        let reported = e.chain().any(|cause| cause.is::<ToolFailed>());
+        if opts.output != OutputFormat::Text && !reported {
+            error!("{:#}", e);
+            print_formatted(&opts, &error_envelope(e))?;
+            flush_output()?;
+            std::process::exit(1);
+        }
+        if let Some(hint) = opts.error_hint(e) {
+            flush_output()?;
+            eprintln!("Error: {:#}\n\n{}", e, hint);
+            std::process::exit(1);
+        }
+    }
+    flush_output()?;
+    result
+}
 
-    // Connect to server
-    info!("Connecting to server: {}", cli.server);
-    let transport = StdioClientTransport::new(cli.server).await?;
-    let init_result = session.connect(transport).await?;
+fn print_completions(shell: clap_complete::Shell) {
+    let mut script = Vec::new();
+    clap_complete::generate(shell, &mut Cli::command(), "{{project-name}}", &mut script);
+    outln!("{}", String::from_utf8_lossy(&script).trim_end());
+}
 
-    info!(
-        "Connected to server: {} v{}",
-        init_result.server_info.name, init_result.server_info.version
-    );
+/// A tool reported failure; its result, including `isError`, was already printed
+#[derive(Debug)]
+struct ToolFailed(String);
 
-    // Execute command
-    match cli.command {
-        Commands::ListTools => list_tools(&session).await?,
-        Commands::ListResources => list_resources(&session).await?,
-        Commands::ListPrompts => list_prompts(&session).await?,
-        Commands::CallTool { tool, args } => call_tool(&session, &tool, &args).await?,
-        Commands::ReadResource { uri } => read_resource(&session, &uri).await?,
-        Commands::GetPrompt { name, args } => get_prompt(&session, &name, &args).await?,
-        Commands::Interactive => interactive_mode(&session).await?,
+impl std::fmt::Display for ToolFailed {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(&self.0)
     }
-
-    info!("{{project-name}} client finished");
-    Ok(())
 }
 
-async fn list_tools(session: &ClientSession) -> Result<()> {
-    let client = session.client();
-    let client_guard = client.lock().await;
+impl std::error::Error for ToolFailed {}
 
-    info!("Listing available tools...");
-    let tools = client_guard.list_tools().await?;
-
-    if tools.tools.is_empty() {
-        println!("No tools available");
-    } else {
-        println!("Available tools:");
-        for tool in tools.tools {
-            println!("  - {}: {}", tool.name, tool.description.unwrap_or_default());
+/// `{"error": {"kind", "code", "message"}}` for `--output json`/`yaml`. `kind` is
+/// `protocol` (the server answered with a JSON-RPC error, whose code is
+/// included), `transport`, `timeout`, or `client` for everything else.
+fn error_envelope(error: &anyhow::Error) -> Value {
+    let mcp = error
+        .chain()
+        .find_map(|cause| cause.downcast_ref::<McpError>());
+    let (kind, code) = match mcp {
+        Some(McpError::Transport(_)) | Some(McpError::Connection(_)) => ("transport", None),
+        Some(McpError::Protocol { code, .. }) => ("protocol", Some(*code)),
+        _ if error.chain().any(|cause| cause.is::<TimedOut>()) => ("timeout", None),
+        _ => ("client", None),
+    };
+    json!({
+        "error": {
+            "kind": kind,
+            "code": code,
+            "message": format!("{:#}", error),
         }
-    }
-
-    Ok(())
+    })
 }
 
-async fn list_resources(session: &ClientSession) -> Result<()> {
-    let client = session.client();
-    let client_guard = client.lock().await;
-
-    info!("Listing available resources...");
-    let resources = client_guard.list_resources().await?;
+/// What a JSON-RPC error code means, for the standard codes and the ones MCP
+/// servers use. Other server-defined codes (-32000 to -32099) only have the
+/// server's own message to go on.
+fn explain_error_code(code: i64) -> Option<&'static str> {
+    Some(match code {
+        -32700 => "parse error: the server couldn't parse the request as JSON",
+        -32600 => "invalid request: the message isn't a valid JSON-RPC request",
+        -32601 => {
+            "method not found: the server doesn't implement this request; \
+             check its capabilities with `capabilities`"
+        }
+        -32602 => {
+            "invalid params: arguments are missing or don't match what the server \
+             expects; check the tool's input schema with `list-tools`"
+        }
+        -32603 => {
+            "internal error: the server failed while handling the request; its logs may say why"
+        }
+        -32001 => "request timeout: the server gave up before finishing the request",
+        -32002 => {
+            "resource not found: the server has no resource with this URI; see `list-resources`"
+        }
+        _ => return None,
+    })
+}
 
-    if resources.resources.is_empty() {
-        println!("No resources available");
-    } else {
-        println!("Available resources:");
-        for resource in resources.resources {
-            println!("  - {}: {}", resource.uri, resource.description.unwrap_or_default());
+impl Options {
+    /// With `--pretty-errors`, an explanation of the JSON-RPC error code behind
+    /// `error`, if it has a well-known one
+    fn error_hint(&self, error: &anyhow::Error) -> Option<String> {
+        if !self.pretty_errors || self.output != OutputFormat::Text {
+            return None;
         }
+        let code = error
+            .chain()
+            .find_map(|cause| match cause.downcast_ref::<McpError>() {
+                Some(McpError::Protocol { code, .. }) => Some(i64::from(*code)),
+                _ => None,
+            })?;
+        explain_error_code(code)
+            .map(|explanation| format!("JSON-RPC error {}: {}", code, explanation))
     }
-
-    Ok(())
 }
 
-async fn list_prompts(session: &ClientSession) -> Result<()> {
-    let client = session.client();
-    let client_guard = client.lock().await;
-
-    info!("Listing available prompts...");
-    let prompts = client_guard.list_prompts().await?;
+async fn run(cli: Cli) -> Result<()> {
+    info!("Starting {{project-name}} MCP client...");
+    let opts = Options::from_cli(&cli);
 
-    if prompts.prompts.is_empty() {
-        println!("No prompts available");
-    } else {
-        println!("Available prompts:");
-        for prompt in prompts.prompts {
-            println!("  - {}: {}", prompt.name, prompt.description.unwrap_or_default());
+    // Completions describe the client itself, so no server is needed
+    if let Commands::Completions { shell } = cli.command {
+        print_completions(shell);
+        return Ok(());
+    }
+    // Roots come from the client's own flags
+    if let Commands::Roots = cli.command {
+        return print_roots(&opts, &cli.roots);
+    }
+    if cli.offline {
+        return run_offline(&cli, &opts);
+    }
+    if opts.dry_run {
+        if let Some(result) = dry_run(&cli.command, &opts) {
+            return result;
         }
     }
 
-    Ok(())
-}
-
-async fn call_tool(session: &ClientSession, tool_name: &str, args_json: &str) -> Result<()> {
-    let client = session.client();
-    let client_guard = client.lock().await;
-
-    info!("Calling tool: {} with args: {}", tool_name, args_json);
+    // Connect to every server; single-target commands use the first one
+    let connector = Connector::from_cli(&cli);
+    let mut servers = connect_all(&cli, &connector).await?;
 
-    // Parse arguments
-    let args: HashMap<String, Value> = if args_json.trim().is_empty() || args_json == "{}" {
-        HashMap::new()
+    // Execute command, then shut every session down whether or not it succeeded
+    let result = match configure_server_logging(&servers, &opts).await {
+        Ok(()) => {
+            let command = async {
+                match cli.repeat {
+                    Some(times) => {
+                        repeat_command(cli.command, &mut servers, &opts, &connector, times).await
+                    }
+                    None => run_command(cli.command, &mut servers, &opts, &connector).await,
+                }
+            };
+            if cli.cancel_on_interrupt {
+                tokio::select! {
+                    result = command => result,
+                    _ = tokio::signal::ctrl_c() => {
+                        cancel_in_flight().await;
+                        Err(anyhow!("cancelled"))
+                    }
+                }
+            } else {
+                command.await
+            }
+        }
+        Err(e) => Err(e),
+    };
+    disconnect_all(&servers).await;
+    // The summary is shown even when the command itself failed
+    let outcomes = if cli.keep_going {
+        report_server_outcomes(&servers)
     } else {
-        serde_json::from_str(args_json)?
+        Ok(())
     };
+    result?;
+    outcomes?;
 
-    let result = client_guard
-        .call_tool(tool_name.to_string(), if args.is_empty() { None } else { Some(args) })
-        .await?;
+    info!("{{project-name}} client finished");
+    Ok(())
+}
 
-    println!("Tool result:");
-    for content in result.content {
-        match content {
-            mcp_protocol_sdk::protocol::types::Content::Text { text } => {
-                println!("  Text: {}", text);
-            }
-            mcp_protocol_sdk::protocol::types::Content::Image { data, mime_type } => {
-                println!("  Image: {} bytes, type: {}", data.len(), mime_type);
-            }
-            mcp_protocol_sdk::protocol::types::Content::Resource { .. } => {
-                println!("  Resource content");
-            }
-        }
+/// Run `command` `times` times on the same session for `--repeat`. Only the
+/// last run's output is printed, followed by a latency summary on stderr.
+async fn repeat_command(
+    command: Commands,
+    servers: &mut Vec<Server>,
+    opts: &Options,
+    connector: &Connector,
+    times: u32,
+) -> Result<()> {
+    if matches!(
+        command,
+        Commands::Batch { .. }
+            | Commands::Interactive { .. }
+            | Commands::Watch { .. }
+            | Commands::CallMany { .. }
+    ) {
+        bail!("--repeat works with single requests such as call-tool, read-resource or ping");
     }
 
-    if let Some(is_error) = result.is_error {
-        if is_error {
-            error!("Tool returned an error");
+    let mut latencies = Vec::new();
+    let mut failures = 0;
+    for run in 1..=times {
+        let started = Instant::now();
+        let result = if run < times {
+            JOB_OUTPUT
+                .scope(
+                    Default::default(),
+                    run_command(command.clone(), servers, opts, connector),
+                )
+                .await
+        } else {
+            run_command(command.clone(), servers, opts, connector).await
+        };
+        latencies.push(started.elapsed());
+        if let Err(e) = result {
+            warn!("Run {}/{} failed: {:#}", run, times, e);
+            failures += 1;
         }
     }
 
+    eprintln!("{}", latency_summary(&latencies, failures));
+    if failures > 0 {
+        bail!("{} of {} runs failed", failures, times);
+    }
     Ok(())
 }
 
-async fn read_resource(session: &ClientSession, uri: &str) -> Result<()> {
-    let client = session.client();
-    let client_guard = client.lock().await;
+/// e.g. `10 runs (0 failed): min 12ms, mean 20ms, max 41ms`
+fn latency_summary(latencies: &[Duration], failures: usize) -> String {
+    let (Some(min), Some(max)) = (latencies.iter().min(), latencies.iter().max()) else {
+        return "0 runs".to_string();
+    };
+    let mean = latencies.iter().sum::<Duration>() / latencies.len() as u32;
+    format!(
+        "{} runs ({} failed): min {}, mean {}, max {}",
+        latencies.len(),
+        failures,
+        format_duration(*min),
+        format_duration(mean),
+        format_duration(*max)
+    )
+}
 
-    info!("Reading resource: {}", uri);
+async fn run_command(
+    command: Commands,
+    servers: &mut Vec<Server>,
+    opts: &Options,
+    connector: &Connector,
+) -> Result<()> {
+    // Interactive and batch sessions time each line instead
+    let started = Instant::now();
+    let timed = !matches!(
+        command,
+        Commands::Batch { .. } | Commands::Interactive { .. } | Commands::Watch { .. }
+    );
+    let result = dispatch_command(command, servers, opts, connector).await;
+    // A failed command is when the breakdown helps most, so it's shown either way
+    if timed && opts.timing {
+        let phases: Vec<(&str, ConnectTiming)> = servers
+            .iter()
+            .map(|server| (server.name.as_str(), server.timing()))
+            .collect();
+        eprintln!(
+            "{}",
+            phase_timings(&phases, started.elapsed(), opts.structured())
+        );
+    }
+    result
+}
 
-    let result = client_guard
-        .read_resource(uri.to_string(), None)
-        .await?;
+async fn dispatch_command(
+    command: Commands,
+    servers: &mut Vec<Server>,
+    opts: &Options,
+    connector: &Connector,
+) -> Result<()> {
+    let session = &servers[0].session;
 
-    println!("Resource content:");
-    for content in result.contents {
-        println!("  URI: {}", content.uri);
-        if let Some(mime_type) = content.mime_type {
-            println!("  MIME type: {}", mime_type);
+    match command {
+        Commands::ListTools { count: true, .. } => print_counts(
+            opts,
+            ListingKind::Tools,
+            count_all(servers, opts, ListingKind::Tools).await?,
+        )?,
+        Commands::ListResources { count: true, .. } => {
+            let counts = count_all(&servers[..1], opts, ListingKind::Resources).await?;
+            print_counts(opts, ListingKind::Resources, counts)?
         }
-        if let Some(text) = content.text {
-            println!("  Text content: {}", text);
+        Commands::ListResourceTemplates { count: true, .. } => {
+            let counts = count_all(&servers[..1], opts, ListingKind::ResourceTemplates).await?;
+            print_counts(opts, ListingKind::ResourceTemplates, counts)?
         }
-        if let Some(blob) = content.blob {
-            println!("  Binary content: {} bytes", blob.len());
+        Commands::ListPrompts { count: true, .. } => print_counts(
+            opts,
+            ListingKind::Prompts,
+            count_all(&servers[..1], opts, ListingKind::Prompts).await?,
+        )?,
+        Commands::ListTools {
+            schemas, cursor, ..
+        } => list_tools_all(servers, opts, schemas, cursor).await?,
+        Commands::ExportTools { openapi } => export_tools(&servers[0], opts, openapi).await?,
+        Commands::SearchTools { query, regex } => {
+            search_tools(servers, opts, &ToolQuery::new(&query, regex)?).await?
+        }
+        Commands::ListResources { cursor, .. } => list_resources(&servers[0], opts, cursor).await?,
+        Commands::ListResourceTemplates { cursor, .. } => {
+            list_resource_templates(&servers[0], opts, cursor).await?
+        }
+        Commands::ListPrompts { cursor, .. } => list_prompts(&servers[0], opts, cursor).await?,
+        Commands::CallTool {
+            tool,
+            args,
+            args_file,
+            stdin_json,
+            arg_files,
+            until,
+            interval,
+            max_attempts,
+        } => {
+            check_stdin_readers(args_file.as_deref(), &arg_files)?;
+            let (tool, args) = tool_call_input(
+                tool,
+                args,
+                args_file.as_deref(),
+                stdin_json,
+                opts.input_encoding,
+            )?;
+            if opts.validate && !arg_files.is_empty() {
+                check_arg_file_types(servers, opts, &tool, &arg_files).await?;
+            }
+            let args = with_arg_files(&args, &arg_files)?;
+            match until {
+                Some(until) => {
+                    let interval = Duration::from_secs(interval);
+                    poll_tool(servers, opts, &tool, &args, &until, interval, max_attempts).await?
+                }
+                None => {
+                    call_tool_on(servers, opts, &tool, &args).await?;
+                }
+            }
+        }
+        Commands::CallSequence { calls, file } => {
+            let calls = sequence_calls(&calls, file.as_deref())?;
+            call_sequence(servers, opts, &calls).await?
+        }
+        Commands::CallMany { tool, concurrency } => {
+            let stdin = BufReader::new(tokio::io::stdin());
+            call_many(servers, opts, &tool, concurrency, stdin).await?
+        }
+        Commands::ReadResource {
+            uris,
+            uris_file,
+            head,
+            tail,
+            split_dir,
+        } => {
+            let uris = resource_uris(uris, uris_file.as_deref())?;
+            let lines = head.map(LineRange::Head).or(tail.map(LineRange::Tail));
+            match split_dir {
+                Some(dir) => split_resources(session, opts, &uris, lines, &dir).await?,
+                None => read_resources(session, opts, &uris, lines).await?,
+            }
+        }
+        Commands::GetPrompt {
+            name,
+            args,
+            args_file,
+            as_messages,
+        } => {
+            let args = load_args(args, args_file.as_deref(), opts.input_encoding)?;
+            get_prompt(session, opts, &name, &args, as_messages).await?
+        }
+        Commands::Complete {
+            ref_name,
+            argument,
+            value,
+            resource,
+        } => {
+            let reference = if resource {
+                CompletionReference::Resource { uri: ref_name }
+            } else {
+                CompletionReference::Prompt { name: ref_name }
+            };
+            complete(session, opts, reference, &argument, &value).await?
+        }
+        Commands::Replay { path } => replay(session, opts, &path).await?,
+        Commands::DiffServers { calls } => {
+            let calls: Vec<(String, String)> =
+                calls.iter().map(|spec| parse_call_spec(spec)).collect();
+            diff_servers(servers, opts, &calls).await?
+        }
+        Commands::Raw { method, params } => {
+            raw_request(session, opts, &method, params.as_deref()).await?
+        }
+        Commands::Watch { uri, interval } => {
+            watch_resource(&servers[0], opts, &uri, Duration::from_secs(interval)).await?
+        }
+        Commands::Ping => ping(session, opts).await?,
+        Commands::Capabilities => print_capabilities(servers, opts)?,
+        Commands::Roots => print_roots(opts, &connector.roots)?,
+        Commands::Init => print_init(servers, opts)?,
+        Commands::Completions { shell } => print_completions(shell),
+        Commands::Batch {
+            script,
+            continue_on_error,
+        } => run_script(servers, opts, &script, continue_on_error).await?,
+        Commands::Interactive {
+            keepalive,
+            concurrency,
+            idle_timeout,
+        } => {
+            interactive_mode(
+                servers,
+                opts,
+                connector,
+                keepalive.map(Duration::from_secs),
+                concurrency,
+                idle_timeout.map(Duration::from_secs),
+            )
+            .await?
         }
     }
 
     Ok(())
 }
 
-async fn get_prompt(session: &ClientSession, prompt_name: &str, args_json: &str) -> Result<()> {
-    let client = session.client();
-    let client_guard = client.lock().await;
+/// Initialize tracing, writing to `--log-file` if given and stderr otherwise
+fn init_logging(cli: &Cli) -> Result<()> {
+    let (log_level, sdk_level) = if cli.stdin_json() {
+        // A program reading the result shouldn't have to sift through logs
+        ("error", "error")
+    } else if cli.quiet {
+        ("warn", "warn")
+    } else if cli.verbose {
+        ("debug", "info")
+    } else {
+        ("info", "info")
+    };
+    let mut filter = format!(
+        "{{project-name}}={},mcp_protocol_sdk={}",
+        log_level, sdk_level
+    );
+    if cli.trace_protocol {
+        filter.push_str(",protocol=debug");
+        let redact = cli.redact.iter().map(|name| name.to_lowercase()).collect();
+        let _ = PROTOCOL_TRACE.set(redact);
+    }
+    let builder = tracing_subscriber::fmt().with_env_filter(filter);
 
-    info!("Getting prompt: {} with args: {}", prompt_name, args_json);
+    let json = cli.log_format == LogFormat::Json;
 
-    // Parse arguments
-    let args: HashMap<String, Value> = if args_json.trim().is_empty() || args_json == "{}" {
-        HashMap::new()
-    } else {
-        serde_json::from_str(args_json)?
+    match &cli.log_file {
+        Some(path) => {
+            let file = std::fs::OpenOptions::new()
+                .create(true)
+                .append(true)
+                .open(path)
+                .with_context(|| format!("failed to open log file {}", path.display()))?;
+            let builder = builder
+                .with_ansi(false)
+                .with_writer(std::sync::Mutex::new(file));
+            if json {
+                builder.json().init();
+            } else {
+                builder.init();
+            }
+        }
+        // Log collectors parse JSON lines, so they never get color codes
+        None if json => builder
+            .with_ansi(false)
+            .with_writer(std::io::stderr)
+            .json()
+            .init(),
+        None => builder
+            .with_ansi(cli.color_choice().enabled(std::io::stderr().is_terminal()))
+            .with_writer(std::io::stderr)
+            .init(),
+    }
+
+    Ok(())
+}
+
+/// Where command results are written; stdout unless `--output-file` is given
+static OUTPUT: OnceLock<std::sync::Mutex<Box<dyn Write + Send>>> = OnceLock::new();
+
+fn init_output(path: Option<&Path>, append: bool) -> Result<()> {
+    let writer: Box<dyn Write + Send> = match path {
+        Some(path) => {
+            let file = std::fs::OpenOptions::new()
+                .create(true)
+                .write(true)
+                .append(append)
+                .truncate(!append)
+                .open(path)
+                .with_context(|| format!("failed to open output file {}", path.display()))?;
+            Box::new(BufWriter::new(file))
+        }
+        None => Box::new(std::io::stdout()),
+    };
+    let _ = OUTPUT.set(std::sync::Mutex::new(writer));
+    Ok(())
+}
+
+fn write_output(args: std::fmt::Arguments<'_>) {
+    // Background jobs keep their output until it is collected
+    let captured = JOB_OUTPUT.try_with(|output| {
+        let mut output = output.borrow_mut();
+        output.push_str(&args.to_string());
+        output.push('\n');
+    });
+    if captured.is_ok() {
+        return;
+    }
+
+    let Some(output) = OUTPUT.get() else {
+        println!("{}", args);
+        return;
     };
+    let mut output = output
+        .lock()
+        .unwrap_or_else(|poisoned| poisoned.into_inner());
+    // Like a closed stdout pipe, a failed write shouldn't abort the command
+    let _ = output
+        .write_fmt(args)
+        .and_then(|()| output.write_all(b"\n"));
+}
 
-    let result = client_guard
-        .get_prompt(prompt_name.to_string(), if args.is_empty() { None } else { Some(args) })
-        .await?;
+/// Write streamed text as it comes, without the newline `outln!` adds
+fn write_chunk(text: &str) {
+    hide_spinner();
+    match OUTPUT.get() {
+        Some(output) => {
+            let mut output = output
+                .lock()
+                .unwrap_or_else(|poisoned| poisoned.into_inner());
+            let _ = output
+                .write_all(text.as_bytes())
+                .and_then(|()| output.flush());
+        }
+        None => {
+            print!("{}", text);
+            let _ = std::io::stdout().flush();
+        }
+    }
+}
 
-    println!("Prompt result:");
-    if let Some(description) = result.description {
-        println!("  Description: {}", description);
+fn flush_output() -> Result<()> {
+    if let Some(output) = OUTPUT.get() {
+        let mut output = output
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner());
+        output.flush().context("failed to flush command output")?;
     }
+    Ok(())
+}
 
-    for message in result.messages {
-        println!("  {} role: {}", message.role, 
-            match message.content {
-                mcp_protocol_sdk::protocol::types::PromptContent::Text { text } => text,
-                mcp_protocol_sdk::protocol::types::PromptContent::Image { .. } => "[Image content]".to_string(),
-                mcp_protocol_sdk::protocol::types::PromptContent::Resource { .. } => "[Resource content]".to_string(),
+/// Where `--record` appends each request and response
+static RECORDING: OnceLock<std::sync::Mutex<std::fs::File>> = OnceLock::new();
+
+fn init_recording(path: Option<&Path>) -> Result<()> {
+    if let Some(path) = path {
+        let file = std::fs::File::create(path)
+            .with_context(|| format!("failed to create recording {}", path.display()))?;
+        let _ = RECORDING.set(std::sync::Mutex::new(file));
+    }
+    Ok(())
+}
+
+/// Set with `--trace-protocol`, holding the lowercased `--redact` names
+static PROTOCOL_TRACE: OnceLock<Vec<String>> = OnceLock::new();
+
+/// A transport that shows every message passing through it to
+/// `--trace-protocol` and `--record`, so they see all the session sends and
+/// receives: the SDK's own `initialize` and `notifications/initialized`,
+/// pings, page fetches, and requests made along the way, not just the one a
+/// command is about
+struct Traced<T>(T);
+
+#[async_trait::async_trait]
+impl<T: McpTransport> McpTransport for Traced<T> {
+    async fn send_request(&mut self, request: JsonRpcRequest) -> McpResult<JsonRpcResponse> {
+        let _ = CALL_REQUEST.try_with(|slot| {
+            *slot.lock().unwrap_or_else(|poisoned| poisoned.into_inner()) =
+                serde_json::to_value(&request.id).ok();
+        });
+        if RECORDING.get().is_none() && PROTOCOL_TRACE.get().is_none() {
+            return self.0.send_request(request).await;
+        }
+        let sent = serde_json::to_value(&request).unwrap_or_default();
+        trace_message("-->", &sent);
+        let response = self.0.send_request(request).await;
+        let received = match &response {
+            Ok(response) => serde_json::to_value(response).unwrap_or_default(),
+            // Nothing came back, so there is no response to show; say why instead
+            Err(e) => json!({ "id": sent["id"], "error": { "message": e.to_string() } }),
+        };
+        trace_message("<--", &received);
+        record_message(&sent, &received);
+        response
+    }
+
+    async fn send_notification(&mut self, notification: JsonRpcNotification) -> McpResult<()> {
+        if PROTOCOL_TRACE.get().is_some() {
+            trace_message(
+                "-->",
+                &serde_json::to_value(&notification).unwrap_or_default(),
+            );
+        }
+        self.0.send_notification(notification).await
+    }
+
+    async fn receive_notification(&mut self) -> McpResult<Option<JsonRpcNotification>> {
+        let notification = self.0.receive_notification().await;
+        if let (Some(_), Ok(Some(notification))) = (PROTOCOL_TRACE.get(), &notification) {
+            trace_message(
+                "<--",
+                &serde_json::to_value(notification).unwrap_or_default(),
+            );
+        }
+        notification
+    }
+
+    async fn close(&mut self) -> McpResult<()> {
+        self.0.close().await
+    }
+
+    fn is_connected(&self) -> bool {
+        self.0.is_connected()
+    }
+
+    fn connection_info(&self) -> String {
+        self.0.connection_info()
+    }
+}
+
+/// Log a JSON-RPC message as sent or received, pretty-printed, under the
+/// `protocol` target
+fn trace_message(direction: &str, message: &Value) {
+    let Some(redact) = PROTOCOL_TRACE.get() else {
+        return;
+    };
+    let mut message = message.clone();
+    redact_fields(&mut message, redact);
+    let label = match (message["method"].as_str(), &message["id"]) {
+        (Some(method), Value::Null) => method.to_string(),
+        (Some(method), id) => format!("{} (id {})", method, id),
+        (None, id) => format!("response (id {})", id),
+    };
+    let pretty = serde_json::to_string_pretty(&message).unwrap_or_default();
+    debug!(target: "protocol", "{} {}\n{}", direction, label, pretty);
+}
+
+/// Append a request and the response to it to the `--record` file
+fn record_message(request: &Value, response: &Value) {
+    let Some(recording) = RECORDING.get() else {
+        return;
+    };
+    let line = format!("{}\n", recorded_exchange(request, response));
+    let mut file = recording
+        .lock()
+        .unwrap_or_else(|poisoned| poisoned.into_inner());
+    if let Err(e) = file.write_all(line.as_bytes()) {
+        warn!("Failed to write to the recording: {}", e);
+    }
+}
+
+/// Replace the value of every field whose name contains one of `names`
+fn redact_fields(value: &mut Value, names: &[String]) {
+    match value {
+        Value::Object(fields) => {
+            for (key, field) in fields.iter_mut() {
+                let key = key.to_lowercase();
+                if names.iter().any(|name| key.contains(name.as_str())) {
+                    *field = json!("[REDACTED]");
+                } else {
+                    redact_fields(field, names);
+                }
             }
+        }
+        Value::Array(items) => items.iter_mut().for_each(|item| redact_fields(item, names)),
+        _ => {}
+    }
+}
+
+/// One line of a recording: the request's method and params, and either the
+/// response's result or its error message
+fn recorded_exchange(request: &Value, response: &Value) -> Value {
+    let mut exchange = json!({ "method": request["method"], "params": request["params"] });
+    match response.get("result") {
+        Some(result) => exchange["result"] = result.clone(),
+        None => {
+            let error = &response["error"];
+            exchange["error"] = match error["message"].as_str() {
+                Some(message) => json!(message),
+                None => json!(error.to_string()),
+            };
+        }
+    }
+    exchange
+}
+
+/// A connected server and the name used to address it as `server::tool`
+struct Server {
+    name: String,
+    endpoint: Endpoint,
+    session: ClientSession,
+    /// What the server said when the current connection was initialized;
+    /// replaced on reconnect, since the new server may be a newer build
+    init: std::sync::RwLock<InitializeResult>,
+    /// How long the current connection took, for `--timing`
+    timing: std::sync::Mutex<ConnectTiming>,
+    /// Resource URIs subscribed to from the interactive prompt
+    subscriptions: std::sync::Mutex<BTreeSet<String>>,
+    /// Tool listing used to check calls, fetched on the first call and
+    /// dropped when the server's tools change
+    tools: Arc<std::sync::Mutex<Option<Vec<Tool>>>>,
+}
+
+impl Server {
+    fn init(&self) -> InitializeResult {
+        self.init
+            .read()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+            .clone()
+    }
+
+    fn timing(&self) -> ConnectTiming {
+        *self
+            .timing
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+
+    /// Take on what a new connection to the server reported
+    fn reconnected(&self, init: InitializeResult, timing: ConnectTiming) {
+        *self
+            .init
+            .write()
+            .unwrap_or_else(|poisoned| poisoned.into_inner()) = init;
+        *self
+            .timing
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner()) = timing;
+    }
+}
+
+/// Everything needed to (re)connect to one server
+#[derive(Clone, Debug)]
+struct Endpoint {
+    transport: Transport,
+    /// Command to spawn for stdio, URL for network transports
+    target: String,
+    server_args: Vec<String>,
+    server_env: Vec<(String, String)>,
+    working_dir: Option<PathBuf>,
+    /// Sent with every http and websocket request
+    headers: Vec<Header>,
+    /// Limit on connecting and completing the initialize handshake
+    init_timeout: Option<Duration>,
+    /// Text a stdio server prints on stderr once it can take requests
+    ready_probe: Option<String>,
+    /// Where a stdio server's stderr goes
+    server_stderr: ServerStderr,
+}
+
+/// `--server-stderr`: what to do with a spawned server's stderr
+#[derive(Clone, Debug, Default, PartialEq)]
+enum ServerStderr {
+    #[default]
+    Inherit,
+    Null,
+    /// Appended to, so reconnects and later runs keep earlier output
+    File(PathBuf),
+}
+
+fn parse_server_stderr(arg: &str) -> std::result::Result<ServerStderr, String> {
+    match arg {
+        "inherit" => Ok(ServerStderr::Inherit),
+        "null" => Ok(ServerStderr::Null),
+        "" => Err("expected inherit, null, or a file path".to_string()),
+        path => Ok(ServerStderr::File(PathBuf::from(path))),
+    }
+}
+
+impl ServerStderr {
+    fn open(path: &Path) -> Result<std::fs::File> {
+        std::fs::OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path)
+            .with_context(|| format!("failed to open {} for the server's stderr", path.display()))
+    }
+
+    /// The child process's stderr
+    fn stdio(&self) -> Result<std::process::Stdio> {
+        Ok(match self {
+            Self::Inherit => std::process::Stdio::inherit(),
+            Self::Null => std::process::Stdio::null(),
+            Self::File(path) => Self::open(path)?.into(),
+        })
+    }
+
+    /// Where lines read from a piped stderr are passed on to
+    fn sink(&self) -> Result<Box<dyn Write + Send>> {
+        Ok(match self {
+            Self::Inherit => Box::new(std::io::stderr()),
+            Self::Null => Box::new(std::io::sink()),
+            Self::File(path) => Box::new(Self::open(path)?),
+        })
+    }
+}
+
+/// An HTTP header for network transports. The value is often a credential,
+/// so `Debug` leaves it out.
+#[derive(Clone)]
+struct Header {
+    name: String,
+    value: String,
+}
+
+impl std::fmt::Debug for Header {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}: <redacted>", self.name)
+    }
+}
+
+fn parse_header(arg: &str) -> std::result::Result<Header, String> {
+    match arg.split_once(':') {
+        Some((name, value)) if !name.trim().is_empty() && !name.contains(' ') => Ok(Header {
+            name: name.to_string(),
+            value: value.trim().to_string(),
+        }),
+        _ => Err(format!("expected \"Name: Value\", got '{}'", arg)),
+    }
+}
+
+/// `--header` values plus the `Authorization` header for `--bearer`
+fn request_headers(cli: &Cli) -> Vec<Header> {
+    let mut headers = cli.headers.clone();
+    if let Some(token) = &cli.bearer {
+        headers.push(Header {
+            name: "Authorization".to_string(),
+            value: format!("Bearer {}", token),
+        });
+    }
+    headers
+}
+
+/// The servers named on the command line or in the config file
+fn endpoints(cli: &Cli) -> Result<Vec<Endpoint>> {
+    let targets = match cli.transport {
+        // Each --server picks its own transport from its scheme
+        Transport::Stdio => cli
+            .server
+            .iter()
+            .map(|server| parse_target(server))
+            .collect::<Result<Vec<_>>>()?,
+        // Network transports talk to the single server at --url
+        Transport::Http => vec![(Transport::Http, cli.url.clone().unwrap_or_default())],
+        Transport::Websocket => {
+            let url = cli.url.clone().unwrap_or_default();
+            check_websocket_url(&url)?;
+            vec![(Transport::Websocket, url)]
+        }
+        Transport::Tcp => match &cli.addr {
+            Some(addr) => vec![(Transport::Tcp, addr.clone())],
+            None => bail!("--addr is required when using the tcp transport"),
+        },
+    };
+    let headers = request_headers(cli);
+    if !headers.is_empty()
+        && targets
+            .iter()
+            .all(|(transport, _)| matches!(transport, Transport::Stdio | Transport::Tcp))
+    {
+        warn!(
+            "--header and --bearer only apply to the http and websocket transports; ignoring them"
         );
     }
 
+    Ok(targets
+        .into_iter()
+        .map(|(transport, target)| Endpoint {
+            transport,
+            target,
+            server_args: cli.server_args.clone(),
+            server_env: cli.server_env.clone(),
+            working_dir: cli.working_dir.clone(),
+            headers: headers.clone(),
+            init_timeout: cli.init_timeout.map(Duration::from_secs),
+            ready_probe: cli.server_ready_probe.clone(),
+            server_stderr: cli.server_stderr.clone(),
+        })
+        .collect())
+}
+
+/// Connect to every configured server, naming each after its command or URL
+async fn connect_all(cli: &Cli, connector: &Connector) -> Result<Vec<Server>> {
+    let mut servers: Vec<Server> = Vec::new();
+    for endpoint in endpoints(cli)? {
+        let mut name = server_name(&endpoint.target);
+        if servers.iter().any(|server| server.name == name) {
+            name = format!("{}-{}", name, servers.len() + 1);
+        }
+        match connector.connect(endpoint, name.clone()).await {
+            Ok(server) => servers.push(server),
+            Err(e) if cli.keep_going => note_server_failure(&name, &e),
+            Err(e) => return Err(e),
+        }
+    }
+    if servers.is_empty() && cli.keep_going {
+        bail!("could not connect to any server");
+    }
+
+    Ok(servers)
+}
+
+/// Servers skipped with `--keep-going`, with the reason, for the summary at the end
+static SERVER_FAILURES: std::sync::Mutex<Vec<(String, String)>> = std::sync::Mutex::new(Vec::new());
+
+fn note_server_failure(name: &str, error: &anyhow::Error) {
+    warn!("Skipping {}: {:#}", name, error);
+    SERVER_FAILURES
+        .lock()
+        .unwrap_or_else(|poisoned| poisoned.into_inner())
+        .push((name.to_string(), format!("{:#}", error)));
+}
+
+/// A failed listing from one of several servers: with `--keep-going` it is
+/// noted and `None` returned so the caller skips the server
+fn skip_failed<T>(opts: &Options, server: &Server, result: Result<T>) -> Result<Option<T>> {
+    match result {
+        Ok(value) => Ok(Some(value)),
+        Err(e) if opts.keep_going => {
+            note_server_failure(&server.name, &e);
+            Ok(None)
+        }
+        Err(e) => Err(e),
+    }
+}
+
+/// With `--keep-going`, say which servers worked and which were skipped,
+/// failing if any were
+fn report_server_outcomes(servers: &[Server]) -> Result<()> {
+    let failed = std::mem::take(
+        &mut *SERVER_FAILURES
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner()),
+    );
+    let succeeded: Vec<&str> = servers
+        .iter()
+        .map(|server| server.name.as_str())
+        .filter(|name| !failed.iter().any(|(failed, _)| failed == name))
+        .collect();
+    eprintln!("{}", server_summary(&succeeded, &failed));
+    if !failed.is_empty() {
+        return Err(ToolFailed(format!(
+            "{} of {} servers failed",
+            failed.len(),
+            succeeded.len() + failed.len()
+        ))
+        .into());
+    }
     Ok(())
 }
 
-async fn interactive_mode(session: &ClientSession) -> Result<()> {
-    println!("Entering interactive mode. Type 'help' for commands, 'exit' to quit.");
+/// e.g. `Servers: 2 succeeded (files, search), 1 failed (db: connection refused)`
+fn server_summary(succeeded: &[&str], failed: &[(String, String)]) -> String {
+    let mut summary = format!("Servers: {} succeeded", succeeded.len());
+    if !succeeded.is_empty() {
+        summary.push_str(&format!(" ({})", succeeded.join(", ")));
+    }
+    if !failed.is_empty() {
+        let reasons: Vec<String> = failed
+            .iter()
+            .map(|(name, error)| format!("{}: {}", name, error))
+            .collect();
+        summary.push_str(&format!(
+            ", {} failed ({})",
+            failed.len(),
+            reasons.join("; ")
+        ));
+    }
+    summary
+}
 
-    loop {
-        print!("> ");
-        use std::io::{self, Write};
-        io::stdout().flush()?;
+/// How new sessions are set up, shared by startup and the interactive `connect` command
+struct Connector {
+    sampler: Option<Arc<dyn Sampler>>,
+    attempts: u32,
+    delay: Duration,
+    /// `--env` variables, for servers started from the interactive prompt
+    server_env: Vec<(String, String)>,
+    /// `--working-dir`, for servers started from the interactive prompt
+    working_dir: Option<PathBuf>,
+    /// `--header` and `--bearer`, for servers connected from the interactive prompt
+    headers: Vec<Header>,
+    init_timeout: Option<Duration>,
+    /// Notification carrying streamed tool output
+    stream_notification: Option<String>,
+    /// Protocol revision to request instead of the SDK's default
+    protocol_version: Option<String>,
+    /// `--json-rpc-id`: prefix for request ids instead of the SDK's bare counter
+    request_id_prefix: Option<String>,
+    /// Name and version sent as `clientInfo` when initializing
+    client_name: String,
+    client_version: String,
+    /// `--root` directories, answered when the server asks for `roots/list`
+    roots: Vec<PathBuf>,
+    /// `--server-ready-probe`, for servers started from the interactive prompt
+    ready_probe: Option<String>,
+    /// `--server-stderr`, for servers started from the interactive prompt
+    server_stderr: ServerStderr,
+}
 
-        let mut input = String::new();
-        io::stdin().read_line(&mut input)?;
-        let input = input.trim();
+impl Connector {
+    fn from_cli(cli: &Cli) -> Self {
+        Self {
+            sampler: sampler_from_cli(cli),
+            attempts: cli.connect_attempts,
+            delay: Duration::from_millis(cli.connect_delay),
+            server_env: cli.server_env.clone(),
+            working_dir: cli.working_dir.clone(),
+            headers: request_headers(cli),
+            init_timeout: cli.init_timeout.map(Duration::from_secs),
+            stream_notification: cli.stream_notification.clone(),
+            protocol_version: cli.protocol_version.clone(),
+            request_id_prefix: cli.json_rpc_id.clone(),
+            client_name: cli.client_name.clone(),
+            client_version: cli.client_version.clone(),
+            roots: cli.roots.clone(),
+            ready_probe: cli.server_ready_probe.clone(),
+            server_stderr: cli.server_stderr.clone(),
+        }
+    }
 
-        if input.is_empty() {
-            continue;
+    /// Start a session with `endpoint` and register the handlers every session gets
+    async fn connect(&self, endpoint: Endpoint, name: String) -> Result<Server> {
+        // Sampling and roots have to be advertised during initialize for the server to use them
+        let roots = client_roots(&self.roots)?;
+        let capabilities = ClientCapabilities {
+            sampling: self.sampler.as_ref().map(|_| SamplingCapability::default()),
+            roots: (!roots.is_empty()).then(RootsCapability::default),
+            ..Default::default()
+        };
+        let mut client = McpClient::with_capabilities(
+            self.client_name.clone(),
+            self.client_version.clone(),
+            capabilities,
+        );
+        if let Some(version) = &self.protocol_version {
+            client.set_protocol_version(version.clone());
+        }
+        if let Some(prefix) = &self.request_id_prefix {
+            client.set_request_id_prefix(prefix.clone());
+        }
+        let session = ClientSession::new(client);
+        if let Some(sampler) = &self.sampler {
+            handle_sampling(&session, Arc::clone(sampler)).await;
+        }
+        if !roots.is_empty() {
+            handle_roots(&session, roots).await;
+        }
+        let (init_result, timing) =
+            connect_with_retries(&session, &endpoint, self.attempts, self.delay).await?;
+        watch_progress(&session).await;
+        if let Some(method) = &self.stream_notification {
+            watch_streamed_text(&session, method).await;
         }
 
-        if input == "exit" || input == "quit" {
-            break;
+        info!(
+            "Connected to server: {} v{}",
+            init_result.server_info.name, init_result.server_info.version
+        );
+        if let Some(requested) = &self.protocol_version {
+            if &init_result.protocol_version != requested {
+                warn!(
+                    "Requested protocol version {} but {} negotiated {}",
+                    requested, name, init_result.protocol_version
+                );
+            }
         }
 
-        if input == "help" {
-            println!("Available commands:");
-            println!("  tools - List available tools");
-            println!("  resources - List available resources");
-            println!("  prompts - List available prompts");
-            println!("  call <tool> [args] - Call a tool");
-            println!("  read <uri> - Read a resource");
-            println!("  prompt <name> [args] - Get a prompt");
-            println!("  help - Show this help");
-            println!("  exit - Exit interactive mode");
-            continue;
+        watch_server_logs(&session, name.clone()).await;
+        Ok(Server {
+            name,
+            endpoint,
+            session,
+            init: std::sync::RwLock::new(init_result),
+            timing: std::sync::Mutex::new(timing),
+            subscriptions: Default::default(),
+            tools: Default::default(),
+        })
+    }
+}
+
+/// Pick the transport for a server from its scheme, returning it with the
+/// target the transport expects. Targets without a scheme are commands.
+fn parse_target(target: &str) -> Result<(Transport, String)> {
+    if let Some(command) = target.strip_prefix("stdio:") {
+        if command.is_empty() {
+            bail!("stdio: must be followed by a server command");
         }
+        return Ok((Transport::Stdio, command.to_string()));
+    }
+    let Some((scheme, rest)) = target.split_once("://") else {
+        return Ok((Transport::Stdio, target.to_string()));
+    };
+    match scheme {
+        "http" | "https" => Ok((Transport::Http, target.to_string())),
+        "ws" | "wss" => Ok((Transport::Websocket, target.to_string())),
+        "tcp" if !rest.is_empty() => Ok((Transport::Tcp, rest.trim_end_matches('/').to_string())),
+        "tcp" => bail!("tcp:// must be followed by host:port"),
+        _ => bail!(
+            "unknown scheme {}:// in {}; use stdio:, http://, https://, ws://, wss://, or tcp://",
+            scheme,
+            target
+        ),
+    }
+}
 
-        let parts: Vec<&str> = input.splitn(3, ' ').collect();
-        let command = parts[0];
+/// Short name for a server: the command's file stem or the URL's host
+fn server_name(target: &str) -> String {
+    if let Some((_, rest)) = target.split_once("://") {
+        return sanitize_file_name(rest.split('/').next().unwrap_or(rest));
+    }
+    Path::new(target)
+        .file_stem()
+        .and_then(|stem| stem.to_str())
+        .map(sanitize_file_name)
+        .unwrap_or_else(|| "server".to_string())
+}
 
-        match command {
-            "tools" => {
-                if let Err(e) = list_tools(session).await {
-                    error!("Error listing tools: {}", e);
-                }
-            }
-            "resources" => {
-                if let Err(e) = list_resources(session).await {
-                    error!("Error listing resources: {}", e);
+/// Build the command used to spawn a stdio server
+fn server_command(endpoint: &Endpoint) -> Result<tokio::process::Command> {
+    let mut program = PathBuf::from(&endpoint.target);
+    // A relative server path like ./server means the one next to the client,
+    // not one inside the server's working directory
+    if endpoint.working_dir.is_some() && program.components().count() > 1 {
+        if let Ok(absolute) = std::path::absolute(&program) {
+            program = absolute;
+        }
+    }
+    let mut command = tokio::process::Command::new(program);
+    command.args(&endpoint.server_args);
+    command.envs(endpoint.server_env.iter().map(|(key, value)| (key, value)));
+    if let Some(dir) = &endpoint.working_dir {
+        command.current_dir(dir);
+    }
+    command.stderr(endpoint.server_stderr.stdio()?);
+    // Abandoning a connection attempt drops the transport, which must not
+    // leave the server running
+    command.kill_on_drop(true);
+    Ok(command)
+}
+
+/// Parse a `--env KEY=VALUE` argument
+fn parse_env_var(arg: &str) -> std::result::Result<(String, String), String> {
+    match arg.split_once('=') {
+        Some((key, value)) if !key.is_empty() => Ok((key.to_string(), value.to_string())),
+        _ => Err(format!("expected KEY=VALUE, got '{}'", arg)),
+    }
+}
+
+/// Headers in the form the network transports take
+#[cfg(any(feature = "http", feature = "websocket"))]
+fn header_map(headers: &[Header]) -> HashMap<String, String> {
+    headers
+        .iter()
+        .map(|header| (header.name.clone(), header.value.clone()))
+        .collect()
+}
+
+/// How long connecting to a server took, split by phase, for `--timing`
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+struct ConnectTiming {
+    /// Spawning the server process or opening the connection
+    transport: Duration,
+    /// The initialize request and the server's response
+    handshake: Duration,
+}
+
+/// Connect and initialize, giving up after `--init-timeout` if one is set
+async fn connect(
+    session: &ClientSession,
+    endpoint: &Endpoint,
+) -> Result<(InitializeResult, ConnectTiming)> {
+    let Some(limit) = endpoint.init_timeout else {
+        return connect_transport(session, endpoint).await;
+    };
+    match tokio::time::timeout(limit, connect_transport(session, endpoint)).await {
+        Ok(result) => result,
+        Err(_) => {
+            // The half-open transport goes with the session, killing a spawned server
+            let _ = session.disconnect().await;
+            bail!(
+                "server failed to initialize in time (waited {}, see --init-timeout)",
+                format_duration(limit)
+            )
+        }
+    }
+}
+
+async fn connect_transport(
+    session: &ClientSession,
+    endpoint: &Endpoint,
+) -> Result<(InitializeResult, ConnectTiming)> {
+    let started = Instant::now();
+    match endpoint.transport {
+        Transport::Stdio => {
+            if let Some(dir) = &endpoint.working_dir {
+                if !dir.is_dir() {
+                    bail!(
+                        "--working-dir {} is not an existing directory",
+                        dir.display()
+                    );
                 }
             }
-            "prompts" => {
-                if let Err(e) = list_prompts(session).await {
-                    error!("Error listing prompts: {}", e);
-                }
+            info!(
+                "Connecting to server: {} {:?}",
+                endpoint.target, endpoint.server_args
+            );
+            let transport = match &endpoint.ready_probe {
+                Some(marker) => spawn_when_ready(endpoint, marker).await?,
+                None => StdioClientTransport::from_command(server_command(endpoint)?).await?,
+            };
+            timed_handshake(started, session.connect(Traced(transport))).await
+        }
+        #[cfg(feature = "http")]
+        Transport::Http => {
+            let url = endpoint.target.as_str();
+            if url.is_empty() {
+                bail!("--url is required when using the http transport");
             }
-            "call" => {
-                if parts.len() < 2 {
-                    println!("Usage: call <tool> [args]");
-                } else {
-                    let tool = parts[1];
-                    let args = parts.get(2).unwrap_or("{}");
-                    if let Err(e) = call_tool(session, tool, args).await {
-                        error!("Error calling tool: {}", e);
-                    }
-                }
+            info!("Connecting to server: {}", url);
+            debug!("Request headers: {:?}", endpoint.headers);
+            let transport =
+                HttpClientTransport::with_headers(url, header_map(&endpoint.headers)).await?;
+            timed_handshake(started, session.connect(Traced(transport))).await
+        }
+        #[cfg(not(feature = "http"))]
+        Transport::Http => Err(anyhow!(
+            "http transport is not available; rebuild with `--features http`"
+        )),
+        #[cfg(feature = "websocket")]
+        Transport::Websocket => {
+            let url = endpoint.target.as_str();
+            info!("Connecting to server: {}", url);
+            // The transport answers the gateway's ping frames itself; a closed
+            // socket surfaces as a transport error, which triggers reconnection
+            debug!("Handshake headers: {:?}", endpoint.headers);
+            let transport =
+                WebSocketClientTransport::with_headers(url, header_map(&endpoint.headers))
+                    .await
+                    .with_context(|| format!("websocket handshake with {} failed", url))?;
+            timed_handshake(started, session.connect(Traced(transport))).await
+        }
+        #[cfg(not(feature = "websocket"))]
+        Transport::Websocket => Err(anyhow!(
+            "websocket transport is not available; rebuild with `--features websocket`"
+        )),
+        Transport::Tcp => {
+            let stream = connect_tcp(&endpoint.target).await?;
+            let (reader, writer) = stream.into_split();
+            // A daemon speaks the same newline-delimited JSON-RPC as a spawned server
+            let transport = StdioClientTransport::from_streams(reader, writer).await?;
+            timed_handshake(started, session.connect(Traced(transport))).await
+        }
+    }
+}
+
+/// Run the initialize `handshake`, timing it separately from the transport
+/// setup that went on between `started` and now
+async fn timed_handshake<E, F>(
+    started: Instant,
+    handshake: F,
+) -> Result<(InitializeResult, ConnectTiming)>
+where
+    F: Future<Output = std::result::Result<InitializeResult, E>>,
+    anyhow::Error: From<E>,
+{
+    let transport = started.elapsed();
+    let init_result = handshake.await?;
+    let timing = ConnectTiming {
+        transport,
+        handshake: started.elapsed() - transport,
+    };
+    Ok((init_result, timing))
+}
+
+/// How long `--server-ready-probe` waits when `--init-timeout` isn't given
+const READY_PROBE_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Spawn a stdio server and wait for `marker` on its stderr before handing its
+/// stdin and stdout to the transport. Its stderr is still passed through to ours.
+async fn spawn_when_ready(endpoint: &Endpoint, marker: &str) -> Result<StdioClientTransport> {
+    let mut command = server_command(endpoint)?;
+    command
+        .stdin(std::process::Stdio::piped())
+        .stdout(std::process::Stdio::piped())
+        .stderr(std::process::Stdio::piped());
+    let mut child = command
+        .spawn()
+        .with_context(|| format!("failed to start server {}", endpoint.target))?;
+    let (Some(stdin), Some(stdout), Some(stderr)) =
+        (child.stdin.take(), child.stdout.take(), child.stderr.take())
+    else {
+        bail!("server {} was started without pipes", endpoint.target);
+    };
+
+    // Returning early drops `child`, which kills the server
+    let limit = endpoint.init_timeout.unwrap_or(READY_PROBE_TIMEOUT);
+    let mut lines = BufReader::new(stderr).lines();
+    let mut sink = endpoint.server_stderr.sink()?;
+    info!("Waiting for the server to print {:?}", marker);
+    match tokio::time::timeout(limit, wait_for_line(&mut lines, marker, &mut sink)).await {
+        Ok(result) => result?,
+        Err(_) => bail!(
+            "server did not print {:?} within {} (see --server-ready-probe)",
+            marker,
+            format_duration(limit)
+        ),
+    }
+
+    // The server exits once the session closes its stdin
+    tokio::spawn(async move {
+        while let Ok(Some(line)) = lines.next_line().await {
+            let _ = writeln!(sink, "{}", line);
+        }
+        let _ = child.wait().await;
+    });
+    Ok(StdioClientTransport::from_streams(stdout, stdin).await?)
+}
+
+/// Pass lines through to `sink` until one contains `marker`
+async fn wait_for_line<R>(
+    lines: &mut tokio::io::Lines<R>,
+    marker: &str,
+    sink: &mut dyn Write,
+) -> Result<()>
+where
+    R: tokio::io::AsyncBufRead + Unpin,
+{
+    while let Some(line) = lines
+        .next_line()
+        .await
+        .context("failed to read server stderr")?
+    {
+        let _ = writeln!(sink, "{}", line);
+        if line.contains(marker) {
+            return Ok(());
+        }
+    }
+    bail!("server exited before printing {:?}", marker)
+}
+
+/// Open a TCP connection to `addr` (`host:port`), explaining the usual failures
+async fn connect_tcp(addr: &str) -> Result<tokio::net::TcpStream> {
+    let Some((host, port)) = addr.rsplit_once(':') else {
+        bail!("expected --addr as host:port, got '{}'", addr);
+    };
+    if port.parse::<u16>().is_err() {
+        bail!("invalid port '{}' in --addr {}", port, addr);
+    }
+
+    info!("Connecting to server: {}", addr);
+    let resolved: Vec<std::net::SocketAddr> = tokio::net::lookup_host(addr)
+        .await
+        .with_context(|| format!("could not resolve host '{}'", host))?
+        .collect();
+    if resolved.is_empty() {
+        bail!("host '{}' did not resolve to any address", host);
+    }
+
+    match tokio::net::TcpStream::connect(resolved.as_slice()).await {
+        Ok(stream) => Ok(stream),
+        Err(e) if e.kind() == std::io::ErrorKind::ConnectionRefused => Err(anyhow!(
+            "connection to {} refused; is the server running and listening on that port?",
+            addr
+        )),
+        Err(e) => Err(e).with_context(|| format!("failed to connect to {}", addr)),
+    }
+}
+
+/// Catch a missing or http:// URL before the websocket handshake fails obscurely
+fn check_websocket_url(url: &str) -> Result<()> {
+    if url.is_empty() {
+        bail!("--url is required when using the websocket transport");
+    }
+    if !url.starts_with("ws://") && !url.starts_with("wss://") {
+        bail!(
+            "websocket URLs must start with ws:// or wss://, got {}",
+            url
+        );
+    }
+    Ok(())
+}
+
+/// Connect, trying again after `delay` if the transport fails before the
+/// handshake completes. Protocol errors such as a rejected initialize are
+/// returned straight away.
+async fn connect_with_retries(
+    session: &ClientSession,
+    endpoint: &Endpoint,
+    attempts: u32,
+    delay: Duration,
+) -> Result<(InitializeResult, ConnectTiming)> {
+    let mut attempt = 1;
+    loop {
+        match connect(session, endpoint).await {
+            Ok(connected) => return Ok(connected),
+            Err(e) if attempt < attempts && is_transport_error(&e) => {
+                debug!(
+                    "Connection attempt {}/{} to {} failed: {:#}",
+                    attempt, attempts, endpoint.target, e
+                );
+                tokio::time::sleep(delay).await;
+                attempt += 1;
             }
-            "read" => {
-                if parts.len() < 2 {
-                    println!("Usage: read <uri>");
-                } else {
-                    let uri = parts[1];
-                    if let Err(e) = read_resource(session, uri).await {
-                        error!("Error reading resource: {}", e);
-                    }
-                }
+            Err(e) => {
+                return Err(e).with_context(|| format!("failed to connect to {}", endpoint.target))
             }
-            "prompt" => {
-                if parts.len() < 2 {
-                    println!("Usage: prompt <name> [args]");
-                } else {
-                    let name = parts[1];
-                    let args = parts.get(2).unwrap_or("{}");
-                    if let Err(e) = get_prompt(session, name, args).await {
-                        error!("Error getting prompt: {}", e);
-                    }
+        }
+    }
+}
+
+/// Shut down every session so spawned servers see a clean disconnect and exit
+async fn disconnect_all(servers: &[Server]) {
+    for server in servers {
+        match server.session.disconnect().await {
+            Ok(()) => info!("Disconnected from {}", server.name),
+            Err(e) => warn!("Failed to disconnect from {}: {}", server.name, e),
+        }
+    }
+}
+
+/// Whether `error` means the connection itself failed rather than the request
+fn is_transport_error(error: &anyhow::Error) -> bool {
+    error.chain().any(|cause| {
+        matches!(
+            cause.downcast_ref::<McpError>(),
+            Some(McpError::Transport(_)) | Some(McpError::Connection(_))
+        )
+    })
+}
+
+/// Re-establish the connection to `server`, retrying with exponential backoff
+async fn reconnect(server: &Server, opts: &Options) -> Result<()> {
+    let mut delay = Duration::from_millis(500);
+    for attempt in 1..=opts.max_retries {
+        warn!(
+            "Reconnecting to {} in {}ms (attempt {}/{})",
+            server.name,
+            delay.as_millis(),
+            attempt,
+            opts.max_retries
+        );
+        tokio::time::sleep(delay).await;
+
+        match connect(&server.session, &server.endpoint).await {
+            Ok((init_result, timing)) => {
+                info!(
+                    "Reconnected to server: {} v{}",
+                    init_result.server_info.name, init_result.server_info.version
+                );
+                server.reconnected(init_result, timing);
+                // The server may have been replaced by one with other tools
+                server
+                    .tools
+                    .lock()
+                    .unwrap_or_else(|poisoned| poisoned.into_inner())
+                    .take();
+                resubscribe(server, opts).await;
+                if let Err(e) = configure_server_logging(std::slice::from_ref(server), opts).await {
+                    warn!("{:#}", e);
                 }
+                return Ok(());
             }
-            _ => {
-                println!("Unknown command: {}. Type 'help' for available commands.", command);
-            }
+            Err(e) => warn!("Reconnect attempt {} failed: {:#}", attempt, e),
         }
+        delay = (delay * 2).min(Duration::from_secs(30));
     }
 
-    println!("Exiting interactive mode");
-    Ok(())
+    bail!(
+        "could not reconnect to {} after {} attempts",
+        server.name,
+        opts.max_retries
+    )
 }
 
-#[cfg(test)]
-mod tests {
-    use super::*;
+/// Restore the interactive subscriptions after a reconnect; the new session starts with none
+async fn resubscribe(server: &Server, opts: &Options) {
+    let uris: Vec<String> = match server.subscriptions.lock() {
+        Ok(subscriptions) => subscriptions.iter().cloned().collect(),
+        Err(_) => return,
+    };
+    let client = client_handle(&server.session).await;
+    for uri in uris {
+        if let Err(e) = request(opts, client.subscribe_resource(uri.clone())).await {
+            warn!("Failed to resubscribe to {}: {}", uri, e);
+        }
+    }
+}
 
-    #[test]
-    fn test_cli_parsing() {
-        let cli = Cli::try_parse_from(&["test", "list-tools"]).unwrap();
-        assert!(!cli.verbose);
-        assert_eq!(cli.server, "./server");
-        assert!(matches!(cli.command, Commands::ListTools));
+/// Reconnect every server that no longer answers a ping
+async fn recover_connections(servers: &[Server], opts: &Options) -> Result<()> {
+    for server in servers {
+        let alive = {
+            let client = client_handle(&server.session).await;
+            request(opts, client.ping()).await.is_ok()
+        };
+        if !alive {
+            reconnect(server, opts).await?;
+        }
     }
+    Ok(())
+}
 
-    #[test]
-    fn test_args_parsing() {
-        let args_json = r#"{"key": "value", "number": 42}"#;
-        let args: HashMap<String, Value> = serde_json::from_str(args_json).unwrap();
+/// Set while a progress line is on screen so it can be cleared afterwards
+static PROGRESS_SHOWN: AtomicBool = AtomicBool::new(false);
+
+/// Show `notifications/progress` from the server as a live line on stderr
+async fn watch_progress(session: &ClientSession) {
+    session
+        .set_notification_handler("notifications/progress", |params: Option<Value>| {
+            let Some(line) = params.as_ref().and_then(format_progress) else {
+                return;
+            };
+            hide_spinner();
+            if std::io::stderr().is_terminal() {
+                eprint!("\r\x1b[2K{}", line);
+                PROGRESS_SHOWN.store(true, Ordering::Relaxed);
+            } else {
+                eprintln!("{}", line);
+            }
+        })
+        .await;
+}
+
+/// Text streamed so far by the tool call in flight; `None` when no call is
+/// printing streamed output
+static STREAMED: std::sync::Mutex<Option<Streamed>> = std::sync::Mutex::new(None);
+
+/// Text streamed by one attempt at a tool call
+#[derive(Default)]
+struct Streamed {
+    /// Every chunk received, to compare with the final result
+    text: String,
+    /// Bytes of `text` printed so far
+    printed: usize,
+    /// Whether a chunk was cut short; nothing after it is printed
+    cut: bool,
+    /// `--max-response-bytes`: chunks past it are received but not printed
+    limit: Option<usize>,
+}
+
+impl Streamed {
+    fn new(limit: Option<usize>) -> Self {
+        Self {
+            limit,
+            ..Default::default()
+        }
+    }
+
+    /// Add a chunk, returning the part of it that fits under the limit
+    fn push<'a>(&mut self, chunk: &'a str) -> &'a str {
+        self.text.push_str(chunk);
+        if self.cut {
+            return "";
+        }
+        let room = self
+            .limit
+            .map_or(chunk.len(), |limit| limit.saturating_sub(self.printed));
+        let mut end = room.min(chunk.len());
+        while !chunk.is_char_boundary(end) {
+            end -= 1;
+        }
+        self.printed += end;
+        self.cut = end < chunk.len();
+        &chunk[..end]
+    }
+
+    /// End the streamed text's last line, saying how much of it was cut off
+    /// the way `--max-response-bytes` does for other text
+    fn finish(&self) {
+        if self.text.is_empty() {
+            return;
+        }
+        match self.text.len() - self.printed {
+            0 => outln!(),
+            clipped => outln!("... (truncated, {} more bytes)", clipped),
+        }
+    }
+}
+
+fn take_streamed() -> Option<Streamed> {
+    STREAMED
+        .lock()
+        .unwrap_or_else(|poisoned| poisoned.into_inner())
+        .take()
+}
+
+/// The foreground tool call in flight, so Ctrl-C can cancel it. Background
+/// jobs don't register theirs; they run until `wait` or exit.
+static IN_FLIGHT: std::sync::Mutex<Option<InFlight>> = std::sync::Mutex::new(None);
+
+struct InFlight {
+    client: McpClient,
+    /// Id of the request the call sent, filled in by `Traced` as it goes out
+    request_id: RequestSlot,
+}
+
+type RequestSlot = Arc<std::sync::Mutex<Option<Value>>>;
+
+tokio::task_local! {
+    /// Where `Traced` notes the id of each request sent from this task.
+    /// Only a registered call sets it, so a background job's requests, sent
+    /// from other tasks, can't be mistaken for the foreground call's.
+    static CALL_REQUEST: RequestSlot;
+}
+
+/// Run the foreground tool call `call`, registered in `IN_FLIGHT` with the id
+/// of the request it sends. A call that completes unregisters itself; one
+/// dropped by Ctrl-C is left for `cancel_in_flight`.
+async fn track_call<F: Future>(session: &ClientSession, call: F) -> F::Output {
+    if in_background_job() {
+        return call.await;
+    }
+    let request_id = RequestSlot::default();
+    let in_flight = InFlight {
+        client: client_handle(session).await,
+        request_id: Arc::clone(&request_id),
+    };
+    *IN_FLIGHT
+        .lock()
+        .unwrap_or_else(|poisoned| poisoned.into_inner()) = Some(in_flight);
+    let output = CALL_REQUEST.scope(request_id, call).await;
+    IN_FLIGHT
+        .lock()
+        .unwrap_or_else(|poisoned| poisoned.into_inner())
+        .take();
+    output
+}
+
+/// After the foreground command has been dropped, tell the server to stop
+/// the tool call it was waiting on, if any, and tidy up its partial output
+async fn cancel_in_flight() {
+    hide_spinner();
+    clear_progress();
+    if let Some(streamed) = take_streamed() {
+        streamed.finish();
+    }
+
+    let Some(in_flight) = IN_FLIGHT
+        .lock()
+        .unwrap_or_else(|poisoned| poisoned.into_inner())
+        .take()
+    else {
+        return;
+    };
+    let request_id = in_flight
+        .request_id
+        .lock()
+        .unwrap_or_else(|poisoned| poisoned.into_inner())
+        .take();
+    // Cancelled before the request went out: there is nothing to stop
+    let Some(request_id) = request_id else {
+        return;
+    };
+    if let Err(e) =
+        {{crate_name}}::cancel_request(&in_flight.client, request_id, "cancelled by the user").await
+    {
+        warn!("Failed to send the cancellation: {:#}", e);
+    }
+}
+
+/// Print text chunks from `method` notifications as they arrive during a tool call
+async fn watch_streamed_text(session: &ClientSession, method: &str) {
+    session
+        .set_notification_handler(method, |params: Option<Value>| {
+            let chunk = params.as_ref().map(stream_chunk_text).unwrap_or_default();
+            if chunk.is_empty() {
+                return;
+            }
+            let mut streamed = STREAMED
+                .lock()
+                .unwrap_or_else(|poisoned| poisoned.into_inner());
+            let Some(buffer) = streamed.as_mut() else {
+                debug!("Ignoring streamed text outside a tool call");
+                return;
+            };
+            if buffer.text.is_empty() {
+                clear_progress();
+            }
+            let printed = buffer.push(&chunk);
+            if !printed.is_empty() {
+                write_chunk(printed);
+            }
+        })
+        .await;
+}
+
+/// The text of a streamed chunk: its `text`, or its text `content` blocks
+fn stream_chunk_text(params: &Value) -> String {
+    if let Some(text) = params.get("text").and_then(Value::as_str) {
+        return text.to_string();
+    }
+    params
+        .get("content")
+        .and_then(Value::as_array)
+        .map(|blocks| {
+            blocks
+                .iter()
+                .filter(|block| block.get("type").and_then(Value::as_str) == Some("text"))
+                .filter_map(|block| block.get("text").and_then(Value::as_str))
+                .collect()
+        })
+        .unwrap_or_default()
+}
+
+type SamplingFuture<'a> = Pin<Box<dyn Future<Output = Result<Value>> + Send + 'a>>;
+
+/// Answers `sampling/createMessage` requests from the server.
+///
+/// `params` is the request's params (`messages`, `systemPrompt`, `maxTokens`, ...)
+/// and the returned value is sent back as the result, so it needs at least
+/// `role`, `content` and `model`. Implement this to plug in your own model and
+/// return it from `sampler_from_cli`.
+trait Sampler: Send + Sync {
+    fn create_message(&self, params: Value) -> SamplingFuture<'_>;
+}
+
+fn sampler_from_cli(cli: &Cli) -> Option<Arc<dyn Sampler>> {
+    if let Some(program) = &cli.sampling_command {
+        Some(Arc::new(CommandSampler {
+            program: program.clone(),
+        }))
+    } else if cli.sampling_prompt {
+        Some(Arc::new(TerminalSampler))
+    } else {
+        None
+    }
+}
+
+async fn handle_sampling(session: &ClientSession, sampler: Arc<dyn Sampler>) {
+    session
+        .set_request_handler("sampling/createMessage", move |params: Option<Value>| {
+            let sampler = Arc::clone(&sampler);
+            async move {
+                info!("Server requested sampling");
+                sampler
+                    .create_message(params.unwrap_or(Value::Null))
+                    .await
+                    .map_err(|e| McpError::Internal(format!("sampling failed: {:#}", e)))
+            }
+        })
+        .await;
+}
+
+/// Answer the server's `roots/list` requests with the `--root` directories
+async fn handle_roots(session: &ClientSession, roots: Vec<Value>) {
+    session
+        .set_request_handler("roots/list", move |_params: Option<Value>| {
+            let roots = roots.clone();
+            async move {
+                info!("Server requested roots");
+                Ok(json!({ "roots": roots }))
+            }
+        })
+        .await;
+}
+
+/// The `--root` directories as MCP `Root` objects, each named after its directory
+fn client_roots(paths: &[PathBuf]) -> Result<Vec<Value>> {
+    paths
+        .iter()
+        .map(|path| {
+            let dir = path
+                .canonicalize()
+                .with_context(|| format!("root {} does not exist", path.display()))?;
+            if !dir.is_dir() {
+                bail!("root {} is not a directory", path.display());
+            }
+            let name = dir
+                .file_name()
+                .map(|name| name.to_string_lossy().into_owned())
+                .unwrap_or_else(|| dir.display().to_string());
+            Ok(json!({ "uri": file_uri(&dir), "name": name }))
+        })
+        .collect()
+}
+
+/// A `file://` URI for an absolute path, percent-encoding anything outside
+/// the characters URIs allow in a path
+fn file_uri(path: &Path) -> String {
+    let path = path.to_string_lossy().replace('\\', "/");
+    let mut uri = String::from("file://");
+    if !path.starts_with('/') {
+        // Windows drive paths such as C:/dir
+        uri.push('/');
+    }
+    for byte in path.bytes() {
+        match byte {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'.' | b'_' | b'~' | b'/' | b':' => {
+                uri.push(byte as char)
+            }
+            _ => uri.push_str(&format!("%{:02X}", byte)),
+        }
+    }
+    uri
+}
+
+fn print_roots(opts: &Options, paths: &[PathBuf]) -> Result<()> {
+    let roots = client_roots(paths)?;
+    if opts.structured() {
+        return print_structured(opts, &json!({ "roots": roots }));
+    }
+    if roots.is_empty() {
+        outln!("No roots offered; pass --root <PATH> to share a directory with servers");
+        return Ok(());
+    }
+    outln!("Roots offered to servers:");
+    for root in &roots {
+        let name = root["name"].as_str().unwrap_or_default();
+        let uri = root["uri"].as_str().unwrap_or_default();
+        outln!("  - {}: {}", opts.name(name), opts.dim(uri));
+    }
+    Ok(())
+}
+
+/// Forwards sampling requests to an external program: request params on its
+/// stdin, result JSON on its stdout
+struct CommandSampler {
+    program: PathBuf,
+}
+
+impl Sampler for CommandSampler {
+    fn create_message(&self, params: Value) -> SamplingFuture<'_> {
+        Box::pin(async move {
+            let mut child = tokio::process::Command::new(&self.program)
+                .stdin(std::process::Stdio::piped())
+                .stdout(std::process::Stdio::piped())
+                .spawn()
+                .with_context(|| format!("failed to run {}", self.program.display()))?;
+
+            let mut stdin = child
+                .stdin
+                .take()
+                .context("sampling command has no stdin")?;
+            tokio::io::AsyncWriteExt::write_all(&mut stdin, &serde_json::to_vec(&params)?).await?;
+            drop(stdin);
+
+            let output = child.wait_with_output().await?;
+            if !output.status.success() {
+                bail!("{} exited with {}", self.program.display(), output.status);
+            }
+            serde_json::from_slice(&output.stdout)
+                .with_context(|| format!("{} did not print a JSON result", self.program.display()))
+        })
+    }
+}
+
+/// Shows the conversation on stderr and reads the reply from stdin
+struct TerminalSampler;
+
+impl Sampler for TerminalSampler {
+    fn create_message(&self, params: Value) -> SamplingFuture<'_> {
+        Box::pin(async move {
+            clear_progress();
+            eprintln!("{}", format_sampling_request(&params));
+            eprint!("Reply (single line, empty to decline): ");
+            let reply = tokio::task::spawn_blocking(|| {
+                let mut line = String::new();
+                std::io::stdin()
+                    .read_line(&mut line)
+                    .map(|read| (read > 0).then_some(line))
+            })
+            .await??;
+            let Some(reply) = reply else {
+                bail!("stdin is closed, so the sampling request can't be answered");
+            };
+
+            let reply = reply.trim();
+            if reply.is_empty() {
+                bail!("user declined the sampling request");
+            }
+            Ok(sampling_text_result(reply, "human"))
+        })
+    }
+}
+
+/// Render the messages of a `sampling/createMessage` request for a person to read
+fn format_sampling_request(params: &Value) -> String {
+    let mut lines = vec!["The server is asking for a model response:".to_string()];
+    if let Some(system) = params.get("systemPrompt").and_then(Value::as_str) {
+        lines.push(format!("  [system] {}", system));
+    }
+    let messages = params.get("messages").and_then(Value::as_array);
+    for message in messages.into_iter().flatten() {
+        let role = message
+            .get("role")
+            .and_then(Value::as_str)
+            .unwrap_or("user");
+        let content = &message["content"];
+        match content.get("text").and_then(Value::as_str) {
+            Some(text) => lines.push(format!("  [{}] {}", role, text)),
+            None => lines.push(format!(
+                "  [{}] <{} content>",
+                role,
+                content
+                    .get("type")
+                    .and_then(Value::as_str)
+                    .unwrap_or("unknown")
+            )),
+        }
+    }
+    lines.join("\n")
+}
+
+fn sampling_text_result(text: &str, model: &str) -> Value {
+    json!({
+        "role": "assistant",
+        "content": { "type": "text", "text": text },
+        "model": model,
+        "stopReason": "endTurn"
+    })
+}
+
+/// Send `logging/setLevel` to every server that supports logging, if `--server-log-level` was given
+async fn configure_server_logging(servers: &[Server], opts: &Options) -> Result<()> {
+    let Some(level) = opts.server_log_level else {
+        return Ok(());
+    };
+    for server in servers {
+        if server.init().capabilities.logging.is_none() {
+            warn!(
+                "{} does not support logging, ignoring --server-log-level",
+                server.name
+            );
+            continue;
+        }
+        let client = client_handle(&server.session).await;
+        let params = json!({ "level": level });
+        let response = request(
+            opts,
+            client.send_raw_request("logging/setLevel".to_string(), Some(params)),
+        )
+        .await?;
+        if let Some(error) = response.get("error") {
+            bail!("{} rejected logging/setLevel: {}", server.name, error);
+        }
+    }
+    Ok(())
+}
+
+/// Print the server's `notifications/message` log messages to stderr. The
+/// server already filtered them by `--server-log-level`, so our own log
+/// filter (`--quiet`, `--verbose`, `RUST_LOG`) doesn't apply.
+async fn watch_server_logs(session: &ClientSession, server: String) {
+    session
+        .set_notification_handler("notifications/message", move |params: Option<Value>| {
+            let Some(params) = params else {
+                return;
+            };
+            if is_warning_level(
+                params
+                    .get("level")
+                    .and_then(Value::as_str)
+                    .unwrap_or("info"),
+            ) {
+                SERVER_WARNINGS.fetch_add(1, Ordering::Relaxed);
+            }
+            hide_spinner();
+            clear_progress();
+            eprintln!("{}", format_server_log(&server, &params));
+        })
+        .await;
+}
+
+/// Log notifications at warning level or above received so far, so that
+/// `--strict` can fail a call during which the server complained
+static SERVER_WARNINGS: AtomicUsize = AtomicUsize::new(0);
+
+fn is_warning_level(level: &str) -> bool {
+    matches!(
+        level,
+        "warning" | "error" | "critical" | "alert" | "emergency"
+    )
+}
+
+/// `[server level] logger: data`, with string data shown without quotes
+fn format_server_log(server: &str, params: &Value) -> String {
+    let level = params
+        .get("level")
+        .and_then(Value::as_str)
+        .unwrap_or("info");
+    let data = match params.get("data") {
+        Some(Value::String(text)) => text.clone(),
+        Some(other) => other.to_string(),
+        None => String::new(),
+    };
+    match params.get("logger").and_then(Value::as_str) {
+        Some(logger) => format!("[{} {}] {}: {}", server, level, logger, data),
+        None => format!("[{} {}] {}", server, level, data),
+    }
+}
+
+/// The spinner shown by `with_spinner`, kept here so that streamed text or
+/// progress updates can take its place
+static SPINNER: std::sync::Mutex<Option<ProgressBar>> = std::sync::Mutex::new(None);
+
+/// Await `fut` with a spinner showing `message` on stderr, when spinners are
+/// enabled. Background jobs run silently.
+async fn with_spinner<F: Future>(opts: &Options, message: String, fut: F) -> F::Output {
+    if !opts.spinner || in_background_job() {
+        return fut.await;
+    }
+    let spinner = ProgressBar::new_spinner().with_message(message);
+    spinner.enable_steady_tick(Duration::from_millis(100));
+    *SPINNER
+        .lock()
+        .unwrap_or_else(|poisoned| poisoned.into_inner()) = Some(spinner);
+    let output = fut.await;
+    hide_spinner();
+    output
+}
+
+fn hide_spinner() {
+    let spinner = SPINNER
+        .lock()
+        .unwrap_or_else(|poisoned| poisoned.into_inner())
+        .take();
+    if let Some(spinner) = spinner {
+        spinner.finish_and_clear();
+    }
+}
+
+/// Remove the live progress line, if one is showing
+fn clear_progress() {
+    if PROGRESS_SHOWN.swap(false, Ordering::Relaxed) {
+        eprint!("\r\x1b[2K");
+    }
+}
+
+fn format_progress(params: &Value) -> Option<String> {
+    let progress = params.get("progress")?.as_f64()?;
+    let mut line = match params.get("total").and_then(Value::as_f64) {
+        Some(total) if total > 0.0 => format!(
+            "Progress: {:.0}% ({}/{})",
+            progress / total * 100.0,
+            progress,
+            total
+        ),
+        _ => format!("Progress: {}", progress),
+    };
+    if let Some(message) = params.get("message").and_then(Value::as_str) {
+        line.push_str(" - ");
+        line.push_str(message);
+    }
+    Some(line)
+}
+
+/// Resolve JSON arguments from `--args` or, if given, `--args-file`, whose
+/// contents are transcoded from `encoding`
+fn load_args(
+    args: String,
+    args_file: Option<&Path>,
+    encoding: &'static encoding_rs::Encoding,
+) -> Result<String> {
+    match args_file {
+        None => Ok(args),
+        Some(path) if path == Path::new("-") => {
+            let mut buf = Vec::new();
+            std::io::stdin()
+                .read_to_end(&mut buf)
+                .context("failed to read arguments from stdin")?;
+            decode_args(&buf, encoding).context("failed to read arguments from stdin")
+        }
+        Some(path) => std::fs::read(path)
+            .map_err(anyhow::Error::from)
+            .and_then(|bytes| decode_args(&bytes, encoding))
+            .with_context(|| format!("failed to read arguments from {}", path.display())),
+    }
+}
+
+/// `call-tool --arg-file`: a file whose base64-encoded contents become an argument
+#[derive(Clone, Debug, PartialEq)]
+struct ArgFile {
+    name: String,
+    path: PathBuf,
+}
+
+fn parse_arg_file(arg: &str) -> std::result::Result<ArgFile, String> {
+    match arg.split_once('=') {
+        Some((name, path)) if !name.is_empty() && !path.is_empty() => Ok(ArgFile {
+            name: name.to_string(),
+            path: PathBuf::from(path),
+        }),
+        _ => Err(format!("expected NAME=PATH, got {:?}", arg)),
+    }
+}
+
+/// Add each `--arg-file` to the JSON arguments as a base64 string,
+/// replacing any argument of the same name
+fn with_arg_files(args_json: &str, files: &[ArgFile]) -> Result<String> {
+    if files.is_empty() {
+        return Ok(args_json.to_string());
+    }
+    let mut args = parse_args(args_json)?;
+    for file in files {
+        let bytes = if file.path == Path::new("-") {
+            let mut buf = Vec::new();
+            std::io::stdin()
+                .read_to_end(&mut buf)
+                .with_context(|| format!("failed to read argument '{}' from stdin", file.name))?;
+            buf
+        } else {
+            std::fs::read(&file.path).with_context(|| {
+                format!(
+                    "failed to read argument '{}' from {}",
+                    file.name,
+                    file.path.display()
+                )
+            })?
+        };
+        args.insert(file.name.clone(), Value::String(BASE64.encode(bytes)));
+    }
+    Ok(serde_json::to_string(&args)?)
+}
+
+/// Stdin can only be read once: a second reader would get nothing and send
+/// an empty argument, so at most one of `--args-file -` and each
+/// `--arg-file NAME=-` may ask for it
+fn check_stdin_readers(args_file: Option<&Path>, arg_files: &[ArgFile]) -> Result<()> {
+    let stdin = Path::new("-");
+    let mut readers: Vec<String> = args_file
+        .filter(|path| *path == stdin)
+        .map(|_| "--args-file -".to_string())
+        .into_iter()
+        .collect();
+    readers.extend(
+        arg_files
+            .iter()
+            .filter(|file| file.path == stdin)
+            .map(|file| format!("--arg-file {}=-", file.name)),
+    );
+    if readers.len() > 1 {
+        bail!(
+            "only one input can be read from stdin, but {} all ask for it",
+            readers.join(", ")
+        );
+    }
+    Ok(())
+}
+
+/// Check that the tool takes a string for each `--arg-file` argument before
+/// reading the files. Schema validation of the whole call would catch a
+/// mismatch too, but its message would quote the entire base64 value.
+async fn check_arg_file_types(
+    servers: &[Server],
+    opts: &Options,
+    target: &str,
+    files: &[ArgFile],
+) -> Result<()> {
+    let (server, tool_name) = resolve_tool(servers, opts, target).await?;
+    let Some(tool) = find_tool(server, opts, &tool_name).await else {
+        return Ok(());
+    };
+    let schema = serde_json::to_value(&tool.input_schema)?;
+    for file in files {
+        if let Some(expected) = non_string_type(&schema, &file.name) {
+            bail!(
+                "argument '{}' of tool '{}' is {}, but --arg-file passes a base64 string \
+                 (use --no-validate to send it anyway)",
+                file.name,
+                tool_name,
+                expected
+            );
+        }
+    }
+    Ok(())
+}
+
+/// The declared type of property `name` in an input schema, if the property
+/// has a type and it doesn't allow a string
+fn non_string_type(schema: &Value, name: &str) -> Option<String> {
+    let declared = schema.get("properties")?.get(name)?.get("type")?;
+    let allows_string = match declared {
+        Value::String(kind) => kind == "string",
+        Value::Array(kinds) => kinds.iter().any(|kind| kind == "string"),
+        _ => true,
+    };
+    (!allows_string).then(|| declared.to_string())
+}
+
+/// Look up an `--input-encoding` label such as `latin1` or `shift_jis`
+fn parse_encoding(label: &str) -> std::result::Result<&'static encoding_rs::Encoding, String> {
+    encoding_rs::Encoding::for_label(label.trim().as_bytes())
+        .ok_or_else(|| format!("unknown encoding '{}'", label))
+}
+
+/// Transcode argument file contents to UTF-8. Bytes that aren't valid in
+/// `encoding` are an error rather than being replaced, so a wrong guess
+/// doesn't silently change the arguments.
+fn decode_args(bytes: &[u8], encoding: &'static encoding_rs::Encoding) -> Result<String> {
+    if encoding == encoding_rs::UTF_8 {
+        let text = std::str::from_utf8(bytes).map_err(|e| {
+            anyhow!(
+                "invalid UTF-8 at byte {}; pass --input-encoding if the file uses another encoding",
+                e.valid_up_to()
+            )
+        })?;
+        return Ok(text.strip_prefix('\u{feff}').unwrap_or(text).to_string());
+    }
+    let (text, _, had_errors) = encoding.decode(bytes);
+    if had_errors {
+        bail!("the contents are not valid {}", encoding.name());
+    }
+    Ok(text.into_owned())
+}
+
+/// The tool and JSON arguments for `call-tool`, from the command line or,
+/// with `--stdin-json`, from the request object on stdin
+fn tool_call_input(
+    tool: Option<String>,
+    args: String,
+    args_file: Option<&Path>,
+    stdin_json: bool,
+    encoding: &'static encoding_rs::Encoding,
+) -> Result<(String, String)> {
+    if stdin_json {
+        let mut input = String::new();
+        std::io::stdin()
+            .read_to_string(&mut input)
+            .context("failed to read the tool call from stdin")?;
+        return parse_tool_call(&input);
+    }
+    let tool = tool.context("a tool name is required")?;
+    Ok((tool, load_args(args, args_file, encoding)?))
+}
+
+/// Split a `{"name": ..., "arguments": {...}}` request, the same shape as
+/// `tools/call` params, into the tool name and its arguments as JSON
+fn parse_tool_call(input: &str) -> Result<(String, String)> {
+    let request: Value =
+        serde_json::from_str(input).context("the tool call must be a JSON object")?;
+    tool_call_from_json(&request)
+}
+
+fn tool_call_from_json(request: &Value) -> Result<(String, String)> {
+    let Some(name) = request.get("name").and_then(Value::as_str) else {
+        bail!("the tool call needs a \"name\" string");
+    };
+    let args = match request.get("arguments") {
+        None | Some(Value::Null) => "{}".to_string(),
+        Some(arguments @ Value::Object(_)) => arguments.to_string(),
+        Some(_) => bail!("\"arguments\" must be a JSON object"),
+    };
+    Ok((name.to_string(), args))
+}
+
+/// Await a request, failing if it takes longer than the configured timeout
+async fn request<T, E, F>(opts: &Options, fut: F) -> Result<T>
+where
+    F: Future<Output = std::result::Result<T, E>>,
+    anyhow::Error: From<E>,
+{
+    with_timeout(opts.timeout, fut).await
+}
+
+/// With `--timing`, report how long a command took on stderr
+fn report_timing(opts: &Options, started: Instant) {
+    if opts.timing {
+        eprintln!("(completed in {})", format_duration(started.elapsed()));
+    }
+}
+
+/// `--timing` for a one-shot command: how long each server's transport and
+/// handshake took, then the command itself. Structured output gets a
+/// `{"timing": ...}` object in milliseconds instead of the text line.
+fn phase_timings(servers: &[(&str, ConnectTiming)], request: Duration, structured: bool) -> String {
+    if structured {
+        let connections: Vec<Value> = servers
+            .iter()
+            .map(|(name, timing)| {
+                json!({
+                    "server": name,
+                    "transportMs": timing.transport.as_millis() as u64,
+                    "initializeMs": timing.handshake.as_millis() as u64,
+                })
+            })
+            .collect();
+        let timing = json!({ "connections": connections, "requestMs": request.as_millis() as u64 });
+        return json!({ "timing": timing }).to_string();
+    }
+
+    let phases = |timing: &ConnectTiming| {
+        format!(
+            "transport {}, initialize {}",
+            format_duration(timing.transport),
+            format_duration(timing.handshake)
+        )
+    };
+    let connections: Vec<String> = match servers {
+        [(_, timing)] => vec![phases(timing)],
+        _ => servers
+            .iter()
+            .map(|(name, timing)| format!("{}: {}", name, phases(timing)))
+            .collect(),
+    };
+    let mut line = connections.join("; ");
+    if !line.is_empty() {
+        line.push_str("; ");
+    }
+    format!("({}request {})", line, format_duration(request))
+}
+
+fn format_duration(duration: Duration) -> String {
+    if duration < Duration::from_secs(1) {
+        format!("{}ms", duration.as_millis())
+    } else {
+        format!("{:.2}s", duration.as_secs_f64())
+    }
+}
+
+/// Print a result struct as a single YAML document with `--output yaml` and
+/// as JSON otherwise
+fn print_structured<T: Serialize>(opts: &Options, value: &T) -> Result<()> {
+    outln!("{}", structured_string(opts, value)?);
+    Ok(())
+}
+
+/// What `print_structured` prints for `value`, without the final newline
+fn structured_string<T: Serialize>(opts: &Options, value: &T) -> Result<String> {
+    let Some(path) = &opts.select else {
+        return formatted_string(opts, value);
+    };
+    let value = serde_json::to_value(value)?;
+    let Some(selected) = path.select(&value) else {
+        bail!("--select {} matched nothing in the result", path.source);
+    };
+    match selected {
+        // Like `jq -r`, so the value can be used in a script as-is
+        Value::String(text) if opts.output == OutputFormat::Text => Ok(text.clone()),
+        _ => formatted_string(opts, selected),
+    }
+}
+
+fn print_formatted<T: Serialize>(opts: &Options, value: &T) -> Result<()> {
+    outln!("{}", formatted_string(opts, value)?);
+    Ok(())
+}
+
+fn formatted_string<T: Serialize>(opts: &Options, value: &T) -> Result<String> {
+    match opts.output {
+        OutputFormat::Yaml => Ok(serde_yaml::to_string(value)?.trim_end().to_string()),
+        OutputFormat::Text | OutputFormat::Json => json_string(opts, value),
+    }
+}
+
+/// Serialize `value` for printing, indented or on one line as `--pretty`
+/// and `--compact` decide
+fn json_string<T: Serialize>(opts: &Options, value: &T) -> Result<String> {
+    let json = if opts.pretty {
+        serde_json::to_string_pretty(value)?
+    } else {
+        serde_json::to_string(value)?
+    };
+    Ok(json)
+}
+
+/// A `--select` path such as `structuredContent.items[0].name`
+#[derive(Clone, Debug)]
+struct JsonPath {
+    source: String,
+    segments: Vec<PathSegment>,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+enum PathSegment {
+    Key(String),
+    Index(usize),
+}
+
+impl JsonPath {
+    fn select<'a>(&self, value: &'a Value) -> Option<&'a Value> {
+        self.segments
+            .iter()
+            .try_fold(value, |value, segment| match segment {
+                PathSegment::Key(key) => value.get(key.as_str()),
+                PathSegment::Index(index) => value.get(*index),
+            })
+    }
+}
+
+/// Parse dotted keys with array indices, optionally starting with `$.` as in JSONPath
+fn parse_json_path(arg: &str) -> std::result::Result<JsonPath, String> {
+    let path = arg.strip_prefix('$').unwrap_or(arg);
+    let path = path.strip_prefix('.').unwrap_or(path);
+    let mut segments = Vec::new();
+    if !path.is_empty() {
+        for part in path.split('.') {
+            let (key, mut indices) = part.split_at(part.find('[').unwrap_or(part.len()));
+            if key.is_empty() && indices.is_empty() {
+                return Err(format!("empty key in path {:?}", arg));
+            }
+            if !key.is_empty() {
+                segments.push(PathSegment::Key(key.to_string()));
+            }
+            while !indices.is_empty() {
+                let Some((index, rest)) = indices
+                    .strip_prefix('[')
+                    .and_then(|rest| rest.split_once(']'))
+                else {
+                    return Err(format!("expected [index] in {:?}", part));
+                };
+                let index = index
+                    .parse()
+                    .map_err(|_| format!("array index must be a number, got {:?}", index))?;
+                segments.push(PathSegment::Index(index));
+                indices = rest;
+            }
+        }
+    }
+    Ok(JsonPath {
+        source: arg.to_string(),
+        segments,
+    })
+}
+
+/// Decode base64 content and write it to `dir`, returning the written path
+fn save_binary(dir: &Path, stem: &str, mime_type: Option<&str>, data: &str) -> Result<PathBuf> {
+    let bytes = BASE64.decode(data.trim())?;
+    std::fs::create_dir_all(dir)?;
+    let path = dir.join(format!(
+        "{}.{}",
+        sanitize_file_name(stem),
+        extension_for_mime(mime_type)
+    ));
+    std::fs::write(&path, bytes)?;
+    Ok(path)
+}
+
+/// Replace characters that are awkward in file names with underscores
+fn sanitize_file_name(name: &str) -> String {
+    let sanitized: String = name
+        .chars()
+        .map(|c| {
+            if c.is_ascii_alphanumeric() || matches!(c, '-' | '_' | '.') {
+                c
+            } else {
+                '_'
+            }
+        })
+        .collect();
+    let sanitized = sanitized.trim_matches(|c| c == '.' || c == '_');
+    if sanitized.is_empty() {
+        "content".to_string()
+    } else {
+        sanitized.to_string()
+    }
+}
+
+fn extension_for_mime(mime_type: Option<&str>) -> &'static str {
+    let essence = mime_type
+        .and_then(|m| m.split(';').next())
+        .map(|m| m.trim().to_ascii_lowercase());
+    match essence.as_deref() {
+        Some("image/png") => "png",
+        Some("image/jpeg") | Some("image/jpg") => "jpg",
+        Some("image/gif") => "gif",
+        Some("image/webp") => "webp",
+        Some("image/svg+xml") => "svg",
+        Some("image/bmp") => "bmp",
+        Some("audio/wav") | Some("audio/x-wav") => "wav",
+        Some("audio/mpeg") => "mp3",
+        Some("application/pdf") => "pdf",
+        Some("application/zip") => "zip",
+        Some("application/json") => "json",
+        Some("text/plain") => "txt",
+        _ => "bin",
+    }
+}
+
+/// File name stem for content read from `uri`: its last path segment
+fn resource_file_stem(uri: &str) -> &str {
+    let path = uri.split(['?', '#']).next().unwrap_or(uri);
+    let name = path
+        .rsplit('/')
+        .find(|segment| !segment.is_empty())
+        .unwrap_or(path);
+    match name.rsplit_once('.') {
+        Some((stem, _)) if !stem.is_empty() => stem,
+        _ => name,
+    }
+}
+
+/// Listings older than this are still shown offline, but with a warning
+const CACHE_STALE_AFTER: Duration = Duration::from_secs(24 * 60 * 60);
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum ListingKind {
+    Tools,
+    Resources,
+    ResourceTemplates,
+    Prompts,
+}
+
+impl ListingKind {
+    fn key(self) -> &'static str {
+        match self {
+            ListingKind::Tools => "tools",
+            ListingKind::Resources => "resources",
+            ListingKind::ResourceTemplates => "resource_templates",
+            ListingKind::Prompts => "prompts",
+        }
+    }
+
+    /// Plural noun for counts, e.g. "3 resource templates"
+    fn label(self) -> &'static str {
+        match self {
+            ListingKind::Tools => "tools",
+            ListingKind::Resources => "resources",
+            ListingKind::ResourceTemplates => "resource templates",
+            ListingKind::Prompts => "prompts",
+        }
+    }
+
+    /// Whether the server advertised the capability this listing needs.
+    /// Servers reject list requests for anything else with an opaque error.
+    fn supported_by(self, init: &InitializeResult) -> bool {
+        let capabilities = &init.capabilities;
+        match self {
+            ListingKind::Tools => capabilities.tools.is_some(),
+            ListingKind::Resources | ListingKind::ResourceTemplates => {
+                capabilities.resources.is_some()
+            }
+            ListingKind::Prompts => capabilities.prompts.is_some(),
+        }
+    }
+
+    /// The field holding the items in a list result
+    fn result_field(self) -> &'static str {
+        match self {
+            ListingKind::Tools => "tools",
+            ListingKind::Resources => "resources",
+            ListingKind::ResourceTemplates => "resourceTemplates",
+            ListingKind::Prompts => "prompts",
+        }
+    }
+
+    /// The command that fills this part of the cache
+    fn command(self) -> &'static str {
+        match self {
+            ListingKind::Tools => "list-tools",
+            ListingKind::Resources => "list-resources",
+            ListingKind::ResourceTemplates => "list-resource-templates",
+            ListingKind::Prompts => "list-prompts",
+        }
+    }
+}
+
+/// Report that `server` can't list `kind` instead of asking it anyway. Json
+/// and yaml output get an empty listing.
+fn print_unsupported(server: &Server, opts: &Options, kind: ListingKind) -> Result<()> {
+    info!(
+        "{} did not advertise support for {}",
+        server.name,
+        kind.label()
+    );
+    if opts.structured() {
+        let mut empty = serde_json::Map::new();
+        empty.insert(kind.result_field().to_string(), json!([]));
+        return print_structured(opts, &empty);
+    }
+    outln!("This server does not support {}", kind.label());
+    Ok(())
+}
+
+/// Everything cached for one server, stored as `<cache-dir>/<server>.json`
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct CachedServer {
+    server: String,
+    listings: BTreeMap<String, CachedListing>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct CachedListing {
+    /// Seconds since the Unix epoch
+    captured_at: u64,
+    result: Value,
+}
+
+/// Cache file for `endpoint`; the server arguments are part of its identity
+fn cache_path(dir: &Path, endpoint: &Endpoint) -> PathBuf {
+    let mut identity = endpoint.target.clone();
+    for arg in &endpoint.server_args {
+        identity.push(' ');
+        identity.push_str(arg);
+    }
+    dir.join(format!("{}.json", sanitize_file_name(&identity)))
+}
+
+fn unix_now() -> u64 {
+    std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .map(|elapsed| elapsed.as_secs())
+        .unwrap_or(0)
+}
+
+/// Save a complete listing to `--cache-dir`, if set. Failing to cache never fails the command.
+fn store_cached<T: Serialize>(server: &Server, opts: &Options, kind: ListingKind, result: &T) {
+    let Some(dir) = &opts.cache_dir else {
+        return;
+    };
+    if let Err(e) = write_cache(dir, &server.endpoint, kind, result, unix_now()) {
+        warn!(
+            "Failed to cache {} for {}: {:#}",
+            kind.key(),
+            server.name,
+            e
+        );
+    }
+}
+
+fn write_cache<T: Serialize>(
+    dir: &Path,
+    endpoint: &Endpoint,
+    kind: ListingKind,
+    result: &T,
+    captured_at: u64,
+) -> Result<()> {
+    let path = cache_path(dir, endpoint);
+    let mut cached: CachedServer = match std::fs::read_to_string(&path) {
+        Ok(text) => serde_json::from_str(&text).unwrap_or_default(),
+        Err(_) => CachedServer::default(),
+    };
+    cached.server = endpoint.target.clone();
+    cached.listings.insert(
+        kind.key().to_string(),
+        CachedListing {
+            captured_at,
+            result: serde_json::to_value(result)?,
+        },
+    );
+
+    std::fs::create_dir_all(dir)?;
+    std::fs::write(&path, serde_json::to_string_pretty(&cached)?)?;
+    debug!("Cached {} in {}", kind.key(), path.display());
+    Ok(())
+}
+
+/// Read a listing back from the cache, warning when it is older than a day
+fn read_cache<T: serde::de::DeserializeOwned>(
+    dir: &Path,
+    endpoint: &Endpoint,
+    kind: ListingKind,
+) -> Result<T> {
+    let path = cache_path(dir, endpoint);
+    let missing = || {
+        anyhow!(
+            "no cached {} for {}; run `{}` once without --offline to capture them",
+            kind.key().replace('_', " "),
+            endpoint.target,
+            kind.command()
+        )
+    };
+    let text = std::fs::read_to_string(&path).map_err(|_| missing())?;
+    let cached: CachedServer = serde_json::from_str(&text)
+        .with_context(|| format!("corrupt cache file {}", path.display()))?;
+    let listing = cached.listings.get(kind.key()).ok_or_else(missing)?;
+
+    let age = Duration::from_secs(unix_now().saturating_sub(listing.captured_at));
+    if age > CACHE_STALE_AFTER {
+        warn!(
+            "Cached {} for {} were captured {} days ago and may be out of date",
+            kind.key().replace('_', " "),
+            endpoint.target,
+            age.as_secs() / (24 * 60 * 60)
+        );
+    }
+    Ok(serde_json::from_value(listing.result.clone())?)
+}
+
+/// Answer a listing command from `--cache-dir` without starting the server
+fn run_offline(cli: &Cli, opts: &Options) -> Result<()> {
+    let Some(dir) = &opts.cache_dir else {
+        bail!("--offline needs --cache-dir");
+    };
+    let endpoints = endpoints(cli)?;
+    let [endpoint] = endpoints.as_slice() else {
+        bail!("--offline works with one server at a time");
+    };
+
+    let count = match &cli.command {
+        Commands::ListTools { count: true, .. } => Some(ListingKind::Tools),
+        Commands::ListResources { count: true, .. } => Some(ListingKind::Resources),
+        Commands::ListResourceTemplates { count: true, .. } => Some(ListingKind::ResourceTemplates),
+        Commands::ListPrompts { count: true, .. } => Some(ListingKind::Prompts),
+        _ => None,
+    };
+    if let Some(kind) = count {
+        let len = cached_len(dir, endpoint, kind)?;
+        return print_counts(opts, kind, vec![(server_name(&endpoint.target), Some(len))]);
+    }
+
+    match &cli.command {
+        Commands::ListTools { schemas, .. } => print_tools(
+            opts,
+            read_cache(dir, endpoint, ListingKind::Tools)?,
+            *schemas,
+        ),
+        Commands::SearchTools { query, regex } => {
+            let mut tools = read_cache(dir, endpoint, ListingKind::Tools)?;
+            filter_tools(&mut tools, &ToolQuery::new(query, *regex)?);
+            print_tool_matches(opts, vec![(server_name(&endpoint.target), tools)])
+        }
+        Commands::ListResources { .. } => {
+            print_resources(opts, read_cache(dir, endpoint, ListingKind::Resources)?)
+        }
+        Commands::ListResourceTemplates { .. } => print_resource_templates(
+            opts,
+            read_cache(dir, endpoint, ListingKind::ResourceTemplates)?,
+        ),
+        Commands::ListPrompts { .. } => {
+            print_prompts(opts, read_cache(dir, endpoint, ListingKind::Prompts)?)
+        }
+        _ => bail!("only the list-* commands and search-tools can run with --offline"),
+    }
+}
+
+/// How many items of `kind` each of `servers` has, from complete listings,
+/// or `None` for those that don't support `kind`
+async fn count_all(
+    servers: &[Server],
+    opts: &Options,
+    kind: ListingKind,
+) -> Result<Vec<(String, Option<usize>)>> {
+    let mut counts = Vec::new();
+    for server in servers {
+        if let Some(count) = skip_failed(opts, server, count_listing(server, opts, kind).await)? {
+            counts.push((server.name.clone(), count));
+        }
+    }
+    Ok(counts)
+}
+
+/// The number of items of `kind` the server lists, or `None` if it didn't
+/// advertise support for them
+async fn count_listing(
+    server: &Server,
+    opts: &Options,
+    kind: ListingKind,
+) -> Result<Option<usize>> {
+    if !kind.supported_by(&server.init()) {
+        info!(
+            "{} did not advertise support for {}",
+            server.name,
+            kind.label()
+        );
+        return Ok(None);
+    }
+    Ok(Some(match kind {
+        ListingKind::Tools => {
+            let tools = fetch_tools(&server.session, opts.timeout, None).await?;
+            store_cached(server, opts, kind, &tools);
+            tools.tools.len()
+        }
+        ListingKind::Resources => {
+            let resources = fetch_resources(&server.session, opts.timeout, None).await?;
+            store_cached(server, opts, kind, &resources);
+            resources.resources.len()
+        }
+        ListingKind::ResourceTemplates => {
+            let templates = fetch_resource_templates(&server.session, opts.timeout, None).await?;
+            store_cached(server, opts, kind, &templates);
+            templates.resource_templates.len()
+        }
+        ListingKind::Prompts => {
+            let prompts = fetch_prompts(&server.session, opts.timeout, None).await?;
+            store_cached(server, opts, kind, &prompts);
+            prompts.prompts.len()
+        }
+    }))
+}
+
+/// The number of items in a cached listing
+fn cached_len(dir: &Path, endpoint: &Endpoint, kind: ListingKind) -> Result<usize> {
+    Ok(match kind {
+        ListingKind::Tools => read_cache::<ListToolsResult>(dir, endpoint, kind)?
+            .tools
+            .len(),
+        ListingKind::Resources => read_cache::<ListResourcesResult>(dir, endpoint, kind)?
+            .resources
+            .len(),
+        ListingKind::ResourceTemplates => {
+            read_cache::<ListResourceTemplatesResult>(dir, endpoint, kind)?
+                .resource_templates
+                .len()
+        }
+        ListingKind::Prompts => read_cache::<ListPromptsResult>(dir, endpoint, kind)?
+            .prompts
+            .len(),
+    })
+}
+
+/// Print item counts, per server when there is more than one
+fn print_counts(
+    opts: &Options,
+    kind: ListingKind,
+    counts: Vec<(String, Option<usize>)>,
+) -> Result<()> {
+    if opts.structured() {
+        if let [(_, count)] = counts.as_slice() {
+            return print_structured(opts, &json!({ kind.key(): count }));
+        }
+        let by_server: serde_json::Map<String, Value> = counts
+            .into_iter()
+            .map(|(name, count)| (name, json!({ kind.key(): count })))
+            .collect();
+        return print_structured(opts, &by_server);
+    }
+
+    if let [(_, count)] = counts.as_slice() {
+        match count {
+            Some(count) => outln!("{} {}", count, kind.label()),
+            None => outln!("This server does not support {}", kind.label()),
+        }
+        return Ok(());
+    }
+    for (name, count) in counts {
+        match count {
+            Some(count) => outln!("{}: {} {}", opts.name(&name), count, kind.label()),
+            None => outln!("{}: {}", opts.name(&name), opts.dim("not supported")),
+        }
+    }
+    Ok(())
+}
+
+/// Count everything the first server lists, for the interactive `count` command
+async fn count_everything(server: &Server, opts: &Options) -> Result<()> {
+    let kinds = [
+        ListingKind::Tools,
+        ListingKind::Resources,
+        ListingKind::ResourceTemplates,
+        ListingKind::Prompts,
+    ];
+    let mut counts = serde_json::Map::new();
+    let mut shown = Vec::new();
+    for kind in kinds {
+        let (count, text) = match count_listing(server, opts, kind).await {
+            Ok(Some(count)) => (json!(count), count.to_string()),
+            Ok(None) => (Value::Null, opts.dim("not supported")),
+            // A server can still reject a listing it advertised; show that as unavailable
+            Err(e) => {
+                debug!("Could not count {}: {:#}", kind.label(), e);
+                (Value::Null, opts.dim("unavailable"))
+            }
+        };
+        counts.insert(kind.key().to_string(), count);
+        shown.push((kind, text));
+    }
+
+    if opts.structured() {
+        return print_structured(opts, &counts);
+    }
+    for (kind, text) in shown {
+        outln!("  {}: {}", kind.label(), text);
+    }
+    Ok(())
+}
+
+/// `12 tools, 3 destructive, 2 with no description`: how many items a listing
+/// showed and how many of them have each notable trait, skipping traits none have
+fn listing_summary(count: usize, singular: &str, plural: &str, traits: &[(usize, &str)]) -> String {
+    let mut summary = format!("{} {}", count, if count == 1 { singular } else { plural });
+    for (matching, description) in traits {
+        if *matching > 0 {
+            summary.push_str(&format!(", {} {}", matching, description));
+        }
+    }
+    summary
+}
+
+fn print_listing_summary(opts: &Options, summary: String) {
+    if !opts.quiet {
+        outln!("{}", opts.dim(&summary));
+    }
+}
+
+fn tool_summary<'a>(tools: impl IntoIterator<Item = &'a Tool>) -> String {
+    let (mut count, mut destructive, mut undescribed) = (0, 0, 0);
+    for tool in tools {
+        count += 1;
+        destructive += usize::from(ToolHints::of(tool).destructive);
+        undescribed += usize::from(tool.description.as_deref().is_none_or(str::is_empty));
+    }
+    listing_summary(
+        count,
+        "tool",
+        "tools",
+        &[
+            (destructive, "destructive"),
+            (undescribed, "with no description"),
+        ],
+    )
+}
+
+/// Tell the user how to fetch the page after a manually requested one
+fn print_next_page_note(kind: &str, next_cursor: Option<&str>) {
+    if let Some(next) = next_cursor {
+        outln!("More {} available; continue with --cursor {}", kind, next);
+    }
+}
+
+async fn list_tools(
+    server: &Server,
+    opts: &Options,
+    schemas: bool,
+    cursor: Option<String>,
+) -> Result<()> {
+    if !ListingKind::Tools.supported_by(&server.init()) {
+        return print_unsupported(server, opts, ListingKind::Tools);
+    }
+
+    info!("Listing available tools...");
+    let complete = cursor.is_none();
+    let tools = fetch_tools(&server.session, opts.timeout, cursor).await?;
+    if complete {
+        store_cached(server, opts, ListingKind::Tools, &tools);
+    }
+    print_tools(opts, tools, schemas)
+}
+
+fn print_tools(opts: &Options, tools: ListToolsResult, schemas: bool) -> Result<()> {
+    if opts.structured() {
+        return print_structured(opts, &tools);
+    }
+    let next_cursor = tools.next_cursor.clone();
+
+    if tools.tools.is_empty() {
+        outln!("No tools available");
+    } else {
+        let summary = tool_summary(&tools.tools);
+        outln!("Available tools:");
+        for tool in tools.tools {
+            outln!(
+                "  - {}{}: {}",
+                opts.name(&tool.name),
+                hint_tags(opts, &tool),
+                opts.dim(&tool.description.unwrap_or_default())
+            );
+            if schemas {
+                print_indented_json(&tool.input_schema, "      ")?;
+            }
+        }
+        print_listing_summary(opts, summary);
+    }
+    print_next_page_note("tools", next_cursor.as_deref());
+
+    Ok(())
+}
+
+/// What `search-tools` looks for in tool names and descriptions
+enum ToolQuery {
+    /// Lowercased text to find anywhere
+    Text(String),
+    Pattern(regex::Regex),
+}
+
+impl ToolQuery {
+    fn new(query: &str, regex: bool) -> Result<Self> {
+        if !regex {
+            return Ok(Self::Text(query.to_lowercase()));
+        }
+        let pattern = regex::RegexBuilder::new(query)
+            .case_insensitive(true)
+            .build()
+            .with_context(|| format!("invalid --regex pattern '{}'", query))?;
+        Ok(Self::Pattern(pattern))
+    }
+
+    fn matches(&self, name: &str, description: Option<&str>) -> bool {
+        let description = description.unwrap_or_default();
+        match self {
+            Self::Text(text) => {
+                name.to_lowercase().contains(text) || description.to_lowercase().contains(text)
+            }
+            Self::Pattern(pattern) => pattern.is_match(name) || pattern.is_match(description),
+        }
+    }
+}
+
+/// Keep only the tools matching `query`
+fn filter_tools(tools: &mut ListToolsResult, query: &ToolQuery) {
+    tools
+        .tools
+        .retain(|tool| query.matches(&tool.name, tool.description.as_deref()));
+    tools.next_cursor = None;
+}
+
+/// Search the tools of every server, prefixing matches with the server name
+/// when there is more than one
+async fn search_tools(servers: &[Server], opts: &Options, query: &ToolQuery) -> Result<()> {
+    let mut matches = Vec::new();
+    for server in servers {
+        if !ListingKind::Tools.supported_by(&server.init()) {
+            continue;
+        }
+        let Some(mut tools) = skip_failed(
+            opts,
+            server,
+            fetch_tools(&server.session, opts.timeout, None).await,
+        )?
+        else {
+            continue;
+        };
+        store_cached(server, opts, ListingKind::Tools, &tools);
+        filter_tools(&mut tools, query);
+        matches.push((server.name.clone(), tools));
+    }
+    print_tool_matches(opts, matches)
+}
+
+fn print_tool_matches(opts: &Options, matches: Vec<(String, ListToolsResult)>) -> Result<()> {
+    let qualify = matches.len() > 1;
+    if opts.structured() {
+        if let [(_, tools)] = matches.as_slice() {
+            return print_structured(opts, &tools.tools);
+        }
+        let by_server: serde_json::Map<String, Value> = matches
+            .iter()
+            .map(|(name, tools)| Ok((name.clone(), serde_json::to_value(&tools.tools)?)))
+            .collect::<Result<_>>()?;
+        return print_structured(opts, &by_server);
+    }
+
+    if matches.iter().all(|(_, tools)| tools.tools.is_empty()) {
+        outln!("No matching tools");
+        return Ok(());
+    }
+    outln!("Matching tools:");
+    for (server, tools) in matches {
+        for tool in tools.tools {
+            let tags = hint_tags(opts, &tool);
+            let name = if qualify {
+                format!("{}::{}", server, tool.name)
+            } else {
+                tool.name
+            };
+            outln!(
+                "  - {}{}: {}",
+                opts.name(&name),
+                tags,
+                opts.dim(&tool.description.unwrap_or_default())
+            );
+        }
+    }
+    Ok(())
+}
+
+/// List tools from every server, grouped and prefixed by server name
+async fn list_tools_all(
+    servers: &[Server],
+    opts: &Options,
+    schemas: bool,
+    cursor: Option<String>,
+) -> Result<()> {
+    if let [server] = servers {
+        return list_tools(server, opts, schemas, cursor).await;
+    }
+    if cursor.is_some() {
+        bail!("--cursor can only be used when connected to a single server");
+    }
+
+    info!("Listing available tools from {} servers...", servers.len());
+    let mut grouped = Vec::new();
+    for server in servers {
+        if !ListingKind::Tools.supported_by(&server.init()) {
+            continue;
+        }
+        let tools = fetch_tools(&server.session, opts.timeout, None).await;
+        let Some(tools) = skip_failed(opts, server, tools)? else {
+            continue;
+        };
+        store_cached(server, opts, ListingKind::Tools, &tools);
+        grouped.push((server.name.as_str(), tools));
+    }
+
+    if opts.structured() {
+        let by_server: serde_json::Map<String, Value> = grouped
+            .iter()
+            .map(|(name, tools)| Ok((name.to_string(), serde_json::to_value(tools)?)))
+            .collect::<Result<_>>()?;
+        return print_structured(opts, &by_server);
+    }
+
+    let summary = tool_summary(grouped.iter().flat_map(|(_, tools)| &tools.tools));
+    for (name, tools) in grouped {
+        outln!("[{}]", opts.name(name));
+        if tools.tools.is_empty() {
+            outln!("  No tools available");
+        }
+        for tool in tools.tools {
+            outln!(
+                "  - {}{}: {}",
+                opts.name(&format!("{}::{}", name, tool.name)),
+                hint_tags(opts, &tool),
+                opts.dim(&tool.description.unwrap_or_default())
+            );
+            if schemas {
+                print_indented_json(&tool.input_schema, "      ")?;
+            }
+        }
+    }
+    print_listing_summary(opts, summary);
+
+    Ok(())
+}
+
+async fn export_tools(server: &Server, opts: &Options, openapi: bool) -> Result<()> {
+    let tools = if ListingKind::Tools.supported_by(&server.init()) {
+        info!("Fetching tools to export...");
+        fetch_tools(&server.session, opts.timeout, None)
+            .await?
+            .tools
+    } else {
+        Vec::new()
+    };
+    let tools = tools
+        .iter()
+        .map(serde_json::to_value)
+        .collect::<std::result::Result<Vec<_>, _>>()?;
+    let init = server.init();
+    let info = &init.server_info;
+    print_structured(
+        opts,
+        &tool_surface(&info.name, &info.version, tools, openapi),
+    )
+}
+
+/// The document `export-tools` prints. Tools are sorted by name, and JSON
+/// object keys are always written in sorted order, so the output is stable.
+fn tool_surface(name: &str, version: &str, mut tools: Vec<Value>, openapi: bool) -> Value {
+    tools.sort_by(|a, b| a["name"].as_str().cmp(&b["name"].as_str()));
+    let field = |tool: &Value, key: &str| tool.get(key).cloned().unwrap_or(Value::Null);
+
+    if !openapi {
+        let tools: Vec<Value> = tools
+            .iter()
+            .map(|tool| {
+                json!({
+                    "name": field(tool, "name"),
+                    "title": field(tool, "title"),
+                    "description": field(tool, "description"),
+                    "inputSchema": field(tool, "inputSchema"),
+                    "outputSchema": field(tool, "outputSchema"),
+                    "annotations": field(tool, "annotations"),
+                })
+            })
+            .collect();
+        return json!({ "server": { "name": name, "version": version }, "tools": tools });
+    }
+
+    let mut paths = serde_json::Map::new();
+    for tool in &tools {
+        let tool_name = tool["name"].as_str().unwrap_or_default();
+        // Calls return a CallToolResult; outputSchema describes its structuredContent
+        let mut result_schema = json!({
+            "type": "object",
+            "properties": { "content": { "type": "array" }, "isError": { "type": "boolean" } },
+        });
+        if let Some(output) = tool.get("outputSchema").filter(|schema| !schema.is_null()) {
+            result_schema["properties"]["structuredContent"] = output.clone();
+        }
+        let mut operation = json!({
+            "operationId": tool_name,
+            "summary": field(tool, "title"),
+            "description": field(tool, "description"),
+            "requestBody": {
+                "required": true,
+                "content": { "application/json": { "schema": field(tool, "inputSchema") } },
+            },
+            "responses": {
+                "200": {
+                    "description": "Tool result",
+                    "content": { "application/json": { "schema": result_schema } },
+                },
+            },
+            "x-mcp-annotations": field(tool, "annotations"),
+        });
+        // OpenAPI tools reject nulls where a string or object is expected
+        if let Value::Object(fields) = &mut operation {
+            fields.retain(|_, value| !value.is_null());
+        }
+        paths.insert(
+            format!("/tools/{}", tool_name),
+            json!({ "post": operation }),
+        );
+    }
+    json!({
+        "openapi": "3.1.0",
+        "info": { "title": name, "version": version },
+        "paths": paths,
+    })
+}
+
+/// Print a JSON value (such as a schema) pretty-printed, with every line prefixed by `indent`
+fn print_indented_json<T: Serialize>(value: &T, indent: &str) -> Result<()> {
+    for line in serde_json::to_string_pretty(value)?.lines() {
+        outln!("{}{}", indent, line);
+    }
+    Ok(())
+}
+
+/// Show the description and input schema of a single tool
+async fn describe_tool(servers: &[Server], opts: &Options, target: &str) -> Result<()> {
+    let (server, name) = resolve_tool(servers, opts, target).await?;
+    let tools = fetch_tools(&server.session, opts.timeout, None).await?;
+    let tool = tools
+        .tools
+        .into_iter()
+        .find(|tool| tool.name == name)
+        .ok_or_else(|| anyhow!("tool '{}' not found on {}", name, server.name))?;
+
+    if opts.structured() {
+        return print_structured(opts, &tool);
+    }
+
+    outln!("{}{}", opts.name(&tool.name), hint_tags(opts, &tool));
+    if let Some(description) = &tool.description {
+        outln!("  {}", opts.dim(description));
+    }
+    let hints = ToolHints::of(&tool).labels();
+    if !hints.is_empty() {
+        outln!("  Annotations: {}", hints.join(", "));
+    }
+    outln!("  Input schema:");
+    print_indented_json(&tool.input_schema, "    ")
+}
+
+/// Find the server that should handle `target`, given as `server::tool` or a bare tool name
+async fn resolve_tool<'a>(
+    servers: &'a [Server],
+    opts: &Options,
+    target: &str,
+) -> Result<(&'a Server, String)> {
+    if let Some((name, tool)) = target.split_once("::") {
+        let server = servers
+            .iter()
+            .find(|server| server.name == name)
+            .ok_or_else(|| {
+                let known: Vec<&str> = servers.iter().map(|s| s.name.as_str()).collect();
+                anyhow!(
+                    "unknown server '{}' (connected: {})",
+                    name,
+                    known.join(", ")
+                )
+            })?;
+        return Ok((server, tool.to_string()));
+    }
+
+    if let [server] = servers {
+        return Ok((server, target.to_string()));
+    }
+
+    let mut providers = Vec::new();
+    for server in servers {
+        if !ListingKind::Tools.supported_by(&server.init()) {
+            continue;
+        }
+        let tools = fetch_tools(&server.session, opts.timeout, None).await?;
+        if tools.tools.iter().any(|tool| tool.name == target) {
+            providers.push(server);
+        }
+    }
+
+    match providers.as_slice() {
+        [] => bail!("no connected server provides tool '{}'", target),
+        [server] => Ok((server, target.to_string())),
+        _ => {
+            let names: Vec<&str> = providers.iter().map(|s| s.name.as_str()).collect();
+            bail!(
+                "tool '{}' is provided by several servers ({}); use server::tool to pick one",
+                target,
+                names.join(", ")
+            )
+        }
+    }
+}
+
+async fn call_tool_on(
+    servers: &[Server],
+    opts: &Options,
+    target: &str,
+    args_json: &str,
+) -> Result<Option<String>> {
+    let (server, tool) = resolve_tool(servers, opts, target).await?;
+    call_tool(server, opts, &tool, args_json).await
+}
+
+/// The calls for `call-sequence`: each `--call`, then the calls in `file`
+fn sequence_calls(specs: &[String], file: Option<&Path>) -> Result<Vec<(String, String)>> {
+    let mut calls: Vec<(String, String)> = specs.iter().map(|spec| parse_call_spec(spec)).collect();
+    if let Some(path) = file {
+        let contents = std::fs::read_to_string(path)
+            .with_context(|| format!("failed to read calls from {}", path.display()))?;
+        let Value::Array(requests) = serde_json::from_str::<Value>(&contents)
+            .with_context(|| format!("{} is not valid JSON", path.display()))?
+        else {
+            bail!("{} must hold a JSON array of tool calls", path.display());
+        };
+        for (index, request) in requests.iter().enumerate() {
+            let call = tool_call_from_json(request)
+                .with_context(|| format!("call {} in {}", index + 1, path.display()))?;
+            calls.push(call);
+        }
+    }
+    Ok(calls)
+}
+
+/// Split `tool:{"json": "args"}`. Arguments always start with `{`, which keeps
+/// `server::tool` names intact.
+fn parse_call_spec(spec: &str) -> (String, String) {
+    match spec.split_once(":{") {
+        Some((tool, args)) => (tool.to_string(), format!("{{{}", args)),
+        None => (spec.to_string(), "{}".to_string()),
+    }
+}
+
+/// Make each call in turn, carrying on past failures, and report how many
+/// succeeded. Fails if any call did.
+async fn call_sequence(
+    servers: &[Server],
+    opts: &Options,
+    calls: &[(String, String)],
+) -> Result<()> {
+    let mut failed = 0;
+    for (index, (tool, args)) in calls.iter().enumerate() {
+        if !opts.structured() {
+            outln!("{}", opts.name(&format!("[{}] {}", index + 1, tool)));
+        }
+        if let Err(e) = call_tool_on(servers, opts, tool, args).await {
+            failed += 1;
+            // A tool's own error result has already been printed
+            if !e.chain().any(|cause| cause.is::<ToolFailed>()) {
+                if opts.structured() {
+                    print_structured(opts, &error_envelope(&e))?;
+                } else {
+                    outln!("{}", opts.failure(&format!("Error: {:#}", e)));
+                }
+            }
+        }
+    }
+
+    let summary = format!(
+        "{} of {} calls succeeded",
+        calls.len() - failed,
+        calls.len()
+    );
+    if opts.structured() {
+        eprintln!("{}", summary);
+    } else {
+        outln!("{}", summary);
+    }
+    if failed > 0 {
+        return Err(ToolFailed(format!("{} of {} calls failed", failed, calls.len())).into());
+    }
+    Ok(())
+}
+
+/// Call `target` once for every NDJSON line of `input` (stdin), writing one
+/// JSON line per call that pairs the input with its result or error
+async fn call_many<R>(
+    servers: &[Server],
+    opts: &Options,
+    target: &str,
+    concurrency: usize,
+    input: R,
+) -> Result<()>
+where
+    R: tokio::io::AsyncBufRead + Unpin,
+{
+    let (server, tool) = resolve_tool(servers, opts, target).await?;
+    let semaphore = Arc::new(Semaphore::new(concurrency.max(1)));
+    let mut calls = JoinSet::new();
+    let mut failures = 0;
+
+    let mut lines = input.lines();
+    let mut line_number = 0;
+    while let Some(line) = lines.next_line().await? {
+        line_number += 1;
+        if line.trim().is_empty() {
+            continue;
+        }
+
+        let input: Value = match serde_json::from_str(&line) {
+            Ok(input) => input,
+            Err(e) => {
+                let record =
+                    json!({ "line": line_number, "error": format!("invalid JSON: {}", e) });
+                outln!("{}", serde_json::to_string(&record)?);
+                failures += 1;
+                continue;
+            }
+        };
+        let args = match &input {
+            Value::Object(map) if map.is_empty() => None,
+            Value::Object(map) => Some(map.clone().into_iter().collect::<HashMap<_, _>>()),
+            _ => {
+                let record = json!({
+                    "line": line_number,
+                    "input": input,
+                    "error": "arguments must be a JSON object",
+                });
+                outln!("{}", serde_json::to_string(&record)?);
+                failures += 1;
+                continue;
+            }
+        };
+
+        let permit = Arc::clone(&semaphore).acquire_owned().await?;
+        let client = client_handle(&server.session).await;
+        let tool = tool.clone();
+        let opts = opts.clone();
+        calls.spawn(async move {
+            let _permit = permit;
+            let result = request(&opts, client.call_tool(tool, args)).await;
+            (line_number, input, result)
+        });
+
+        // Report calls as they finish so output streams instead of waiting for EOF
+        while let Some(done) = calls.try_join_next() {
+            let (line, input, result) = done?;
+            if !report_call(line, input, result)? {
+                failures += 1;
+            }
+        }
+    }
+
+    while let Some(done) = calls.join_next().await {
+        let (line, input, result) = done?;
+        if !report_call(line, input, result)? {
+            failures += 1;
+        }
+    }
+
+    if failures > 0 {
+        bail!("{} call(s) to '{}' failed", failures, tool);
+    }
+    Ok(())
+}
+
+/// Print one NDJSON record for a `call-many` call, returning whether it succeeded
+fn report_call(line: usize, input: Value, result: Result<CallToolResult>) -> Result<bool> {
+    let (record, succeeded) = match result {
+        Ok(result) => {
+            let succeeded = !result.is_error.unwrap_or(false);
+            (
+                json!({ "line": line, "input": input, "result": result }),
+                succeeded,
+            )
+        }
+        Err(e) => (
+            json!({ "line": line, "input": input, "error": format!("{:#}", e) }),
+            false,
+        ),
+    };
+    outln!("{}", serde_json::to_string(&record)?);
+    Ok(succeeded)
+}
+
+async fn list_resources(server: &Server, opts: &Options, cursor: Option<String>) -> Result<()> {
+    if !ListingKind::Resources.supported_by(&server.init()) {
+        return print_unsupported(server, opts, ListingKind::Resources);
+    }
+
+    info!("Listing available resources...");
+    let complete = cursor.is_none();
+    let resources = fetch_resources(&server.session, opts.timeout, cursor).await?;
+    if complete {
+        store_cached(server, opts, ListingKind::Resources, &resources);
+    }
+    print_resources(opts, resources)
+}
+
+fn print_resources(opts: &Options, resources: ListResourcesResult) -> Result<()> {
+    if opts.structured() {
+        return print_structured(opts, &resources);
+    }
+    let next_cursor = resources.next_cursor.clone();
+
+    if resources.resources.is_empty() {
+        outln!("No resources available");
+    } else {
+        let undescribed = resources
+            .resources
+            .iter()
+            .filter(|resource| resource.description.as_deref().is_none_or(str::is_empty))
+            .count();
+        let untyped = resources
+            .resources
+            .iter()
+            .filter(|resource| resource.mime_type.is_none())
+            .count();
+        let summary = listing_summary(
+            resources.resources.len(),
+            "resource",
+            "resources",
+            &[
+                (undescribed, "with no description"),
+                (untyped, "with no MIME type"),
+            ],
+        );
+        outln!("Available resources:");
+        for resource in resources.resources {
+            outln!(
+                "  - {}: {}",
+                opts.name(&resource.uri),
+                opts.dim(&resource.description.unwrap_or_default())
+            );
+        }
+        print_listing_summary(opts, summary);
+    }
+    print_next_page_note("resources", next_cursor.as_deref());
+
+    Ok(())
+}
+
+async fn list_resource_templates(
+    server: &Server,
+    opts: &Options,
+    cursor: Option<String>,
+) -> Result<()> {
+    if !ListingKind::ResourceTemplates.supported_by(&server.init()) {
+        return print_unsupported(server, opts, ListingKind::ResourceTemplates);
+    }
+
+    info!("Listing resource templates...");
+    let complete = cursor.is_none();
+    let templates = fetch_resource_templates(&server.session, opts.timeout, cursor).await?;
+    if complete {
+        store_cached(server, opts, ListingKind::ResourceTemplates, &templates);
+    }
+    print_resource_templates(opts, templates)
+}
+
+fn print_resource_templates(opts: &Options, templates: ListResourceTemplatesResult) -> Result<()> {
+    if opts.structured() {
+        return print_structured(opts, &templates);
+    }
+    let next_cursor = templates.next_cursor.clone();
+
+    if templates.resource_templates.is_empty() {
+        outln!("No resource templates available");
+    } else {
+        outln!("Available resource templates:");
+        for template in templates.resource_templates {
+            outln!(
+                "  - {}: {}",
+                opts.name(&template.uri_template),
+                opts.dim(&template.description.unwrap_or_default())
+            );
+        }
+    }
+    print_next_page_note("resource templates", next_cursor.as_deref());
+
+    Ok(())
+}
+
+async fn list_prompts(server: &Server, opts: &Options, cursor: Option<String>) -> Result<()> {
+    if !ListingKind::Prompts.supported_by(&server.init()) {
+        return print_unsupported(server, opts, ListingKind::Prompts);
+    }
+
+    info!("Listing available prompts...");
+    let complete = cursor.is_none();
+    let prompts = fetch_prompts(&server.session, opts.timeout, cursor).await?;
+    if complete {
+        store_cached(server, opts, ListingKind::Prompts, &prompts);
+    }
+    print_prompts(opts, prompts)
+}
+
+fn print_prompts(opts: &Options, prompts: ListPromptsResult) -> Result<()> {
+    if opts.structured() {
+        return print_structured(opts, &prompts);
+    }
+    let next_cursor = prompts.next_cursor.clone();
+
+    if prompts.prompts.is_empty() {
+        outln!("No prompts available");
+    } else {
+        let undescribed = prompts
+            .prompts
+            .iter()
+            .filter(|prompt| prompt.description.as_deref().is_none_or(str::is_empty))
+            .count();
+        let summary = listing_summary(
+            prompts.prompts.len(),
+            "prompt",
+            "prompts",
+            &[(undescribed, "with no description")],
+        );
+        outln!("Available prompts:");
+        for prompt in prompts.prompts {
+            outln!(
+                "  - {}: {}",
+                opts.name(&prompt.name),
+                opts.dim(&prompt.description.unwrap_or_default())
+            );
+        }
+        print_listing_summary(opts, summary);
+    }
+    print_next_page_note("prompts", next_cursor.as_deref());
+
+    Ok(())
+}
+
+/// Call a tool and print its result, returning the first text block so
+/// scripts can pass it on as `$PREV`
+async fn call_tool(
+    server: &Server,
+    opts: &Options,
+    tool_name: &str,
+    args_json: &str,
+) -> Result<Option<String>> {
+    let session = &server.session;
+    let args_json = expand_args(opts, args_json)?;
+    if opts.dry_run {
+        print_request(
+            opts,
+            "tools/call",
+            named_params(tool_name, parse_args(&args_json)?),
+        )?;
+        return Ok(None);
+    }
+
+    info!("Calling tool: {} with args: {}", tool_name, args_json);
+
+    let args = parse_args(&args_json)?;
+    check_tool_call(server, opts, tool_name, &args).await?;
+
+    // Streamed chunks would corrupt json and yaml output, and would bypass
+    // output held back for `wait` or `--repeat`, so both just get the final result
+    let captured = JOB_OUTPUT.try_with(|_| ()).is_ok();
+    let stream = !opts.structured() && !captured;
+    let warnings = SERVER_WARNINGS.load(Ordering::Relaxed);
+    let attempt = || {
+        if stream {
+            *STREAMED
+                .lock()
+                .unwrap_or_else(|poisoned| poisoned.into_inner()) =
+                Some(Streamed::new(opts.max_response_bytes));
+        }
+        let call = {{crate_name}}::call_tool(session, opts.timeout, tool_name, args.clone());
+        async move {
+            let result = with_spinner(
+                opts,
+                format!("Calling {}...", tool_name),
+                track_call(session, call),
+            )
+            .await;
+            clear_progress();
+            result
+        }
+    };
+    // The next attempt streams its text afresh; say that what is already on
+    // screen came from one that failed
+    let on_retry = |_: u32| {
+        if let Some(streamed) = take_streamed().filter(|streamed| !streamed.text.is_empty()) {
+            streamed.finish();
+            outln!(
+                "{}",
+                opts.dim("(attempt failed; retrying, output above is incomplete)")
+            );
+        }
+    };
+    let result = retry_tool_call(tool_name, opts.tool_retries, attempt, on_retry).await?;
+
+    let streamed = take_streamed().unwrap_or_default();
+    streamed.finish();
+
+    let failed = result.is_error.unwrap_or(false);
+    let empty = result.content.is_empty() && result.structured_content.is_none();
+    let text = tool_text(&result);
+
+    print_call_result(opts, tool_name, result, &streamed.text).await?;
+
+    if failed {
+        return Err(ToolFailed(format!("tool '{}' returned an error", tool_name)).into());
+    }
+    if opts.strict && empty {
+        return Err(ToolFailed(format!("tool '{}' returned no content", tool_name)).into());
+    }
+    if opts.strict && SERVER_WARNINGS.load(Ordering::Relaxed) > warnings {
+        return Err(ToolFailed(format!(
+            "server logged warnings while calling tool '{}'",
+            tool_name
+        ))
+        .into());
+    }
+
+    Ok(text)
+}
+
+/// `call-tool --until`: a path into the tool result and the value to wait for
+#[derive(Clone, Debug)]
+struct Until {
+    path: JsonPath,
+    expected: Value,
+}
+
+fn parse_until(arg: &str) -> std::result::Result<Until, String> {
+    let Some((path, value)) = arg.split_once('=') else {
+        return Err(format!("expected PATH=VALUE, got {:?}", arg));
+    };
+    // `status=done` shouldn't need shell-quoted JSON strings
+    let expected = serde_json::from_str(value).unwrap_or_else(|_| Value::String(value.to_string()));
+    Ok(Until {
+        path: parse_json_path(path)?,
+        expected,
+    })
+}
+
+/// Call a tool every `interval` until `until` matches its result, printing
+/// the status of each attempt on stderr and the final result as usual
+async fn poll_tool(
+    servers: &[Server],
+    opts: &Options,
+    target: &str,
+    args_json: &str,
+    until: &Until,
+    interval: Duration,
+    max_attempts: u32,
+) -> Result<()> {
+    let (server, tool_name) = resolve_tool(servers, opts, target).await?;
+    let args = parse_args(&expand_args(opts, args_json)?)?;
+    check_tool_call(server, opts, &tool_name, &args).await?;
+
+    for attempt in 1..=max_attempts {
+        let call =
+            {{crate_name}}::call_tool(&server.session, opts.timeout, &tool_name, args.clone());
+        let result = track_call(&server.session, call).await?;
+
+        let failed = result.is_error.unwrap_or(false);
+        let value = serde_json::to_value(&result)?;
+        let actual = until.path.select(&value);
+        if failed || actual == Some(&until.expected) {
+            print_call_result(opts, &tool_name, result, "").await?;
+            if failed {
+                return Err(ToolFailed(format!("tool '{}' returned an error", tool_name)).into());
+            }
+            return Ok(());
+        }
+
+        let actual = actual.map_or_else(|| "(missing)".to_string(), Value::to_string);
+        eprintln!(
+            "Attempt {}/{}: {} is {}, waiting for {}",
+            attempt, max_attempts, until.path.source, actual, until.expected
+        );
+        if attempt < max_attempts {
+            tokio::time::sleep(interval).await;
+        }
+    }
+
+    bail!(
+        "{} did not become {} after {} attempts",
+        until.path.source,
+        until.expected,
+        max_attempts
+    )
+}
+
+/// Apply `--expand-env` to an arguments string
+fn expand_args<'a>(opts: &Options, args_json: &'a str) -> Result<Cow<'a, str>> {
+    if opts.expand_env {
+        Ok(Cow::Owned(expand_env(args_json, |name| {
+            std::env::var(name).ok()
+        })?))
+    } else {
+        Ok(Cow::Borrowed(args_json))
+    }
+}
+
+/// Substitute `${VAR}` and `${VAR:-default}` in `input`. Values are JSON-escaped
+/// so they can sit inside a quoted string; an unset variable without a default
+/// is an error.
+fn expand_env(input: &str, lookup: impl Fn(&str) -> Option<String>) -> Result<String> {
+    let mut output = String::with_capacity(input.len());
+    let mut rest = input;
+    while let Some(start) = rest.find("${") {
+        output.push_str(&rest[..start]);
+        let after = &rest[start + 2..];
+        let end = after
+            .find('}')
+            .ok_or_else(|| anyhow!("unterminated ${{ in arguments: {}", &rest[start..]))?;
+        let expr = &after[..end];
+        let (name, default) = match expr.split_once(":-") {
+            Some((name, default)) => (name, Some(default)),
+            None => (expr, None),
+        };
+        let value = match (lookup(name), default) {
+            (Some(value), _) => value,
+            (None, Some(default)) => default.to_string(),
+            (None, None) => bail!(
+                "environment variable {} is not set (use ${{{}:-default}} for a fallback)",
+                name,
+                name
+            ),
+        };
+        output.push_str(&json_escape(&value));
+        rest = &after[end + 1..];
+    }
+    output.push_str(rest);
+    Ok(output)
+}
+
+/// Params for `tools/call` and `prompts/get`; `arguments` is left out when empty
+fn named_params(name: &str, args: HashMap<String, Value>) -> Value {
+    let mut params = json!({ "name": name });
+    if !args.is_empty() {
+        params["arguments"] = json!(args);
+    }
+    params
+}
+
+/// Print the JSON-RPC request that would be sent, for `--dry-run`
+fn print_request(opts: &Options, method: &str, params: Value) -> Result<()> {
+    let request = json!({
+        "jsonrpc": "2.0",
+        "id": 1,
+        "method": method,
+        "params": params,
+    });
+    outln!("{}", json_string(opts, &request)?);
+    Ok(())
+}
+
+/// Handle `--dry-run` for commands that need no server at all, returning
+/// `None` for commands that still have to connect
+fn dry_run(command: &Commands, opts: &Options) -> Option<Result<()>> {
+    let result = match command {
+        Commands::CallTool {
+            tool,
+            args,
+            args_file,
+            stdin_json,
+            arg_files,
+            ..
+        } => check_stdin_readers(args_file.as_deref(), arg_files)
+            .and_then(|()| {
+                tool_call_input(
+                    tool.clone(),
+                    args.clone(),
+                    args_file.as_deref(),
+                    *stdin_json,
+                    opts.input_encoding,
+                )
+            })
+            .and_then(|(tool, args)| {
+                let args = with_arg_files(&args, arg_files)?;
+                // Without servers to pick from, `server::tool` just names the tool
+                let tool = tool
+                    .split_once("::")
+                    .map_or(tool.as_str(), |(_, tool)| tool);
+                print_request(
+                    opts,
+                    "tools/call",
+                    named_params(tool, parse_args(&expand_args(opts, &args)?)?),
+                )
+            }),
+        Commands::ReadResource {
+            uris, uris_file, ..
+        } => resource_uris(uris.clone(), uris_file.as_deref()).and_then(|uris| {
+            uris.iter()
+                .try_for_each(|uri| print_request(opts, "resources/read", json!({ "uri": uri })))
+        }),
+        Commands::GetPrompt {
+            name,
+            args,
+            args_file,
+            ..
+        } => load_args(args.clone(), args_file.as_deref(), opts.input_encoding).and_then(|args| {
+            print_request(
+                opts,
+                "prompts/get",
+                named_params(name, parse_args(&expand_args(opts, &args)?)?),
+            )
+        }),
+        _ => return None,
+    };
+    Some(result)
+}
+
+/// Look up a tool's definition before calling it, listing the server's
+/// tools only the first time. If they can't be fetched the call goes ahead
+/// unchecked and the server decides.
+async fn find_tool(server: &Server, opts: &Options, tool_name: &str) -> Option<Tool> {
+    let cached = server
+        .tools
+        .lock()
+        .unwrap_or_else(|poisoned| poisoned.into_inner())
+        .as_ref()
+        .map(|tools| tools.iter().find(|tool| tool.name == tool_name).cloned());
+    let tool = match cached {
+        Some(tool) => tool,
+        None => {
+            let tools = match fetch_tools(&server.session, opts.timeout, None).await {
+                Ok(tools) => tools.tools,
+                Err(e) => {
+                    warn!("Skipping tool checks, could not list tools: {:#}", e);
+                    return None;
+                }
+            };
+            let tool = tools.iter().find(|tool| tool.name == tool_name).cloned();
+            *server
+                .tools
+                .lock()
+                .unwrap_or_else(|poisoned| poisoned.into_inner()) = Some(tools);
+            tool
+        }
+    };
+    if tool.is_none() {
+        debug!("Tool {} not in listing, skipping tool checks", tool_name);
+    }
+    tool
+}
+
+/// Check a call against the tool's definition before making it: its
+/// arguments unless `--no-validate`, and a confirmation for destructive
+/// tools unless `--assume-yes`
+async fn check_tool_call(
+    server: &Server,
+    opts: &Options,
+    tool_name: &str,
+    args: &HashMap<String, Value>,
+) -> Result<()> {
+    if !opts.validate && opts.assume_yes {
+        return Ok(());
+    }
+    let Some(tool) = find_tool(server, opts, tool_name).await else {
+        return Ok(());
+    };
+    if opts.validate {
+        check_tool_args(&tool, args)?;
+    }
+    if !opts.assume_yes && ToolHints::of(&tool).destructive {
+        confirm_destructive(tool_name).await?;
+    }
+    Ok(())
+}
+
+/// Validate `args` against the tool's input schema before sending the call
+fn check_tool_args(tool: &Tool, args: &HashMap<String, Value>) -> Result<()> {
+    validate_tool_args(tool, args).with_context(|| {
+        format!(
+            "invalid arguments for tool '{}' (use --no-validate to send them anyway)",
+            tool.name
+        )
+    })
+}
+
+/// The behaviour hints in a tool's `annotations`. Only hints the server states
+/// explicitly count; a tool without annotations gets no tags.
+#[derive(Debug, Default, PartialEq)]
+struct ToolHints {
+    read_only: bool,
+    destructive: bool,
+    idempotent: bool,
+    open_world: bool,
+}
+
+impl ToolHints {
+    fn of(tool: &Tool) -> Self {
+        let annotations = serde_json::to_value(tool)
+            .ok()
+            .and_then(|tool| tool.get("annotations").cloned())
+            .unwrap_or_default();
+        Self::from_annotations(&annotations)
+    }
+
+    fn from_annotations(annotations: &Value) -> Self {
+        let hint = |name: &str| annotations.get(name).and_then(Value::as_bool) == Some(true);
+        let read_only = hint("readOnlyHint");
+        Self {
+            read_only,
+            // A read-only tool can't be destructive, whatever else it claims
+            destructive: !read_only && hint("destructiveHint"),
+            idempotent: hint("idempotentHint"),
+            open_world: hint("openWorldHint"),
+        }
+    }
+
+    fn labels(&self) -> Vec<&'static str> {
+        let mut labels = Vec::new();
+        if self.read_only {
+            labels.push("read-only");
+        }
+        if self.destructive {
+            labels.push("destructive");
+        }
+        if self.idempotent {
+            labels.push("idempotent");
+        }
+        if self.open_world {
+            labels.push("open-world");
+        }
+        labels
+    }
+}
+
+/// Markers shown after a tool's name in listings
+fn hint_tags(opts: &Options, tool: &Tool) -> String {
+    let hints = ToolHints::of(tool);
+    if hints.destructive {
+        format!(" {}", opts.failure("[destructive]"))
+    } else if hints.read_only {
+        format!(" {}", opts.dim("[read-only]"))
+    } else {
+        String::new()
+    }
+}
+
+/// Ask before calling a tool annotated as destructive; `--assume-yes` skips this
+async fn confirm_destructive(tool_name: &str) -> Result<()> {
+    // A background job reading stdin would fight the prompt for the user's input
+    if in_background_job() {
+        bail!(
+            "tool '{}' is marked destructive; call it in the foreground to confirm, or pass --assume-yes",
+            tool_name
+        );
+    }
+    if !std::io::stdin().is_terminal() {
+        bail!(
+            "tool '{}' is marked destructive; pass --assume-yes to call it without confirmation",
+            tool_name
+        );
+    }
+    clear_progress();
+    eprint!(
+        "Tool '{}' is marked destructive. Call it anyway? [y/N] ",
+        tool_name
+    );
+    let answer = tokio::task::spawn_blocking(|| {
+        let mut line = String::new();
+        std::io::stdin().read_line(&mut line).map(|_| line)
+    })
+    .await??;
+    if !matches!(answer.trim().to_lowercase().as_str(), "y" | "yes") {
+        bail!("not calling destructive tool '{}'", tool_name);
+    }
+    Ok(())
+}
+
+/// An argument a prompt declares, read from its listing entry
+#[derive(Debug, PartialEq)]
+struct PromptArg {
+    name: String,
+    description: Option<String>,
+    required: bool,
+}
+
+/// The arguments of `prompt` to ask for: every one not in `given` when no
+/// arguments were given at all, otherwise just the missing required ones
+fn missing_prompt_args(prompt: &Value, given: &HashMap<String, Value>) -> Vec<PromptArg> {
+    let declared = prompt.get("arguments").and_then(Value::as_array);
+    declared
+        .into_iter()
+        .flatten()
+        .filter_map(|argument| {
+            Some(PromptArg {
+                name: argument.get("name")?.as_str()?.to_string(),
+                description: argument
+                    .get("description")
+                    .and_then(Value::as_str)
+                    .map(str::to_string),
+                required: argument
+                    .get("required")
+                    .and_then(Value::as_bool)
+                    .unwrap_or(false),
+            })
+        })
+        .filter(|argument| {
+            !given.contains_key(&argument.name) && (given.is_empty() || argument.required)
+        })
+        .collect()
+}
+
+/// Fill in the arguments of prompt `name` that `args_json` leaves out by
+/// asking on the terminal, showing each one's description. Optional
+/// arguments can be skipped with an empty answer; required ones are asked again.
+async fn ask_prompt_args(
+    session: &ClientSession,
+    opts: &Options,
+    name: &str,
+    args_json: &str,
+) -> Result<String> {
+    let mut args = parse_args(&expand_args(opts, args_json)?)?;
+    let prompts = fetch_prompts(session, opts.timeout, None).await?;
+    let Some(prompt) = prompts.prompts.iter().find(|prompt| prompt.name == name) else {
+        // Let the server report the unknown prompt
+        return Ok(args_json.to_string());
+    };
+    let missing = missing_prompt_args(&serde_json::to_value(prompt)?, &args);
+    if missing.is_empty() {
+        return Ok(args_json.to_string());
+    }
+
+    clear_progress();
+    for argument in missing {
+        if let Some(description) = &argument.description {
+            eprintln!("{}", opts.dim(description));
+        }
+        let label = if argument.required {
+            format!("{}: ", argument.name)
+        } else {
+            format!("{} (optional): ", argument.name)
+        };
+        loop {
+            eprint!("{}", label);
+            let answer = tokio::task::spawn_blocking(|| {
+                let mut line = String::new();
+                std::io::stdin()
+                    .read_line(&mut line)
+                    .map(|read| (read > 0).then_some(line))
+            })
+            .await??;
+            let Some(answer) = answer else {
+                bail!(
+                    "stdin is closed, so argument '{}' can't be asked for",
+                    argument.name
+                );
+            };
+            let answer = answer.trim_end_matches(['\r', '\n']);
+            if !answer.is_empty() {
+                args.insert(argument.name.clone(), Value::String(answer.to_string()));
+            } else if argument.required {
+                eprintln!("{} is required", argument.name);
+                continue;
+            }
+            break;
+        }
+    }
+    Ok(serde_json::to_string(&args)?)
+}
+
+/// Cut `text` off after at most `limit` bytes, on a character boundary,
+/// noting how much was left out
+fn truncate_text(text: &str, limit: usize) -> Cow<'_, str> {
+    if text.len() <= limit {
+        return Cow::Borrowed(text);
+    }
+    let mut end = limit;
+    while !text.is_char_boundary(end) {
+        end -= 1;
+    }
+    Cow::Owned(format!(
+        "{}... (truncated, {} more bytes)",
+        &text[..end],
+        text.len() - end
+    ))
+}
+
+/// Print a tool result in the chosen format, or what `--post-process` makes
+/// of it, cut off at `--max-response-bytes`
+async fn print_call_result(
+    opts: &Options,
+    tool_name: &str,
+    result: CallToolResult,
+    streamed: &str,
+) -> Result<()> {
+    match &opts.post_process {
+        Some(command) => {
+            let output = post_process(command, post_process_input(opts, &result)?).await?;
+            outln!("{}", opts.clip(output.trim_end_matches(['\r', '\n'])));
+            Ok(())
+        }
+        None if opts.structured() => print_structured(opts, &result),
+        None => print_tool_result(opts, tool_name, result, streamed),
+    }
+}
+
+/// What the `--post-process` command reads: with text output, the result's
+/// text blocks (or its structured content when it has none); otherwise the
+/// json or yaml document that would have been printed
+fn post_process_input(opts: &Options, result: &CallToolResult) -> Result<String> {
+    if opts.structured() {
+        return Ok(format!("{}\n", structured_string(opts, result)?));
+    }
+    let texts: Vec<&str> = result
+        .content
+        .iter()
+        .filter_map(|content| match content {
+            mcp_protocol_sdk::protocol::types::Content::Text { text } => Some(text.as_str()),
+            _ => None,
+        })
+        .collect();
+    match &result.structured_content {
+        Some(structured) if texts.is_empty() => Ok(format!("{}\n", json_string(opts, structured)?)),
+        _ => Ok(texts.join("\n")),
+    }
+}
+
+/// Run `command` through the shell with `input` on its stdin, returning its
+/// stdout. A non-zero exit is an error.
+async fn post_process(command: &str, input: String) -> Result<String> {
+    let mut shell = if cfg!(windows) {
+        let mut shell = tokio::process::Command::new("cmd");
+        shell.arg("/C");
+        shell
+    } else {
+        let mut shell = tokio::process::Command::new("sh");
+        shell.arg("-c");
+        shell
+    };
+    let mut child = shell
+        .arg(command)
+        .stdin(std::process::Stdio::piped())
+        .stdout(std::process::Stdio::piped())
+        .kill_on_drop(true)
+        .spawn()
+        .with_context(|| format!("failed to run --post-process command `{}`", command))?;
+
+    // Write from another task so a command that prints before it has read
+    // everything can't fill its stdout pipe and stall both sides
+    let mut stdin = child
+        .stdin
+        .take()
+        .context("--post-process command has no stdin")?;
+    let writer = tokio::spawn(async move {
+        // A command that stops reading early, like `head`, closes the pipe
+        let _ = tokio::io::AsyncWriteExt::write_all(&mut stdin, input.as_bytes()).await;
+    });
+    let output = child.wait_with_output().await?;
+    let _ = writer.await;
+    if !output.status.success() {
+        bail!(
+            "--post-process command `{}` exited with {}",
+            command,
+            output.status
+        );
+    }
+    String::from_utf8(output.stdout)
+        .with_context(|| format!("--post-process command `{}` printed invalid UTF-8", command))
+}
+
+/// Print a tool result for people. A text block matching `streamed`, the text
+/// already shown as it arrived, isn't repeated.
+fn print_tool_result(
+    opts: &Options,
+    tool_name: &str,
+    result: CallToolResult,
+    streamed: &str,
+) -> Result<()> {
+    if result.is_error.unwrap_or(false) {
+        outln!("{}", opts.failure("Tool returned an error:"));
+    } else {
+        outln!("Tool result:");
+    }
+    for (index, content) in result.content.into_iter().enumerate() {
+        match content {
+            mcp_protocol_sdk::protocol::types::Content::Text { text }
+                if !streamed.is_empty() && text.trim() == streamed.trim() =>
+            {
+                outln!("  Text: (streamed above)");
+            }
+            mcp_protocol_sdk::protocol::types::Content::Text { text } if opts.markdown => {
+                outln!("  Text:");
+                outln!(
+                    "{}",
+                    termimad::MadSkin::default().term_text(&opts.clip(&text))
+                );
+            }
+            mcp_protocol_sdk::protocol::types::Content::Text { text } => {
+                outln!("  Text: {}", opts.clip(&text));
+            }
+            mcp_protocol_sdk::protocol::types::Content::Image { data, mime_type } => {
+                if let Some(dir) = &opts.out_dir {
+                    let stem = format!("{}-{}", tool_name, index + 1);
+                    let path = save_binary(dir, &stem, Some(&mime_type), &data)?;
+                    outln!("  Image: saved to {} (type: {})", path.display(), mime_type);
+                } else {
+                    outln!("  Image: {} bytes, type: {}", data.len(), mime_type);
+                }
+            }
+            mcp_protocol_sdk::protocol::types::Content::Resource { .. } => {
+                outln!("  Resource content");
+            }
+            // Content types added to the protocol after this client was written
+            #[allow(unreachable_patterns)]
+            other => outln!("  {}", unsupported_content(&other)),
+        }
+    }
+    // Tools using structured output may send this alongside the content blocks, or instead of them
+    if let Some(structured) = &result.structured_content {
+        outln!("  Structured content:");
+        print_indented_json(structured, "    ")?;
+    }
+
+    Ok(())
+}
+
+/// Notice for a content block this client doesn't know how to show, with
+/// its raw JSON so nothing is lost
+fn unsupported_content<T: Serialize>(content: &T) -> String {
+    let json =
+        serde_json::to_string(content).unwrap_or_else(|e| format!("<unserializable: {}>", e));
+    format!("Received unsupported content type: {}", json)
+}
+
+/// Read a resource and print it, returning its first text content for `$PREV`
+async fn read_resource(
+    session: &ClientSession,
+    opts: &Options,
+    uri: &str,
+    lines: Option<LineRange>,
+) -> Result<Option<String>> {
+    if opts.dry_run {
+        print_request(opts, "resources/read", json!({ "uri": uri }))?;
+        return Ok(None);
+    }
+
+    info!("Reading resource: {}", uri);
+
+    let result = with_spinner(
+        opts,
+        format!("Reading {}...", uri),
+        {{crate_name}}::read_resource(session, opts.timeout, uri),
+    )
+    .await?;
+    show_resource(opts, result, lines)
+}
+
+/// Print a resource that has been read, keeping only `lines` of its text,
+/// and return its first text content
+fn show_resource(
+    opts: &Options,
+    mut result: ReadResourceResult,
+    lines: Option<LineRange>,
+) -> Result<Option<String>> {
+    // MCP reads have no range parameter, so the whole resource is fetched and cut here
+    if let Some(lines) = lines {
+        for content in &mut result.contents {
+            if let Some(text) = &content.text {
+                content.text = Some(lines.select(text));
+            }
+        }
+    }
+    let text = result
+        .contents
+        .iter()
+        .find_map(|content| content.text.clone());
+    print_resource(opts, result)?;
+    Ok(text)
+}
+
+/// Read each of `uris` and write every content block to its own file in
+/// `dir`, text as-is and blobs decoded, then print a manifest of the files
+async fn split_resources(
+    session: &ClientSession,
+    opts: &Options,
+    uris: &[String],
+    lines: Option<LineRange>,
+    dir: &Path,
+) -> Result<()> {
+    std::fs::create_dir_all(dir).with_context(|| format!("failed to create {}", dir.display()))?;
+
+    let mut written = BTreeSet::new();
+    let mut manifest = Vec::new();
+    let results = with_spinner(
+        opts,
+        format!("Reading {} resources...", uris.len()),
+        {{crate_name}}::read_resources(session, opts.timeout, uris),
+    )
+    .await;
+    let mut failed = 0;
+    for (uri, result) in uris.iter().zip(results) {
+        // One unreadable resource shouldn't lose the others
+        let result = match result {
+            Ok(result) => result,
+            Err(e) => {
+                failed += 1;
+                manifest.push(json!({ "uri": uri, "error": format!("{:#}", e) }));
+                continue;
+            }
+        };
+
+        // A blob that won't decode is listed in its place; the rest of the
+        // resource is still written
+        let mut undecodable = false;
+        for content in result.contents {
+            let (bytes, kind) = match (&content.text, &content.blob) {
+                (Some(text), _) => {
+                    let text = lines.map_or_else(|| text.clone(), |lines| lines.select(text));
+                    (text.into_bytes(), "text")
+                }
+                (None, Some(blob)) => match BASE64.decode(blob.trim()) {
+                    Ok(bytes) => (bytes, "binary"),
+                    Err(e) => {
+                        undecodable = true;
+                        let error = format!("invalid base64 content: {}", e);
+                        manifest.push(json!({ "uri": content.uri, "error": error }));
+                        continue;
+                    }
+                },
+                (None, None) => continue,
+            };
+            let name = uri_file_name(&content.uri, content.mime_type.as_deref(), kind == "text");
+            let path = unused_path(dir, &name, &mut written);
+            std::fs::write(&path, &bytes)
+                .with_context(|| format!("failed to write {}", path.display()))?;
+            manifest.push(json!({
+                "uri": content.uri,
+                "path": path,
+                "kind": kind,
+                "bytes": bytes.len(),
+            }));
+        }
+        if undecodable {
+            failed += 1;
+        }
+    }
+
+    if opts.structured() {
+        print_structured(opts, &manifest)?;
+    } else {
+        print_split_manifest(opts, &manifest, dir);
+    }
+    if failed > 0 {
+        return Err(ToolFailed(format!(
+            "{} of {} resources could not be read",
+            failed,
+            uris.len()
+        ))
+        .into());
+    }
+    Ok(())
+}
+
+fn print_split_manifest(opts: &Options, manifest: &[Value], dir: &Path) {
+    let files = manifest
+        .iter()
+        .filter(|entry| entry.get("path").is_some())
+        .count();
+    outln!("Wrote {} files to {}:", files, dir.display());
+    for entry in manifest {
+        if let Some(error) = entry.get("error").and_then(Value::as_str) {
+            outln!(
+                "  - {}: {}",
+                opts.name(entry["uri"].as_str().unwrap_or_default()),
+                opts.failure(error)
+            );
+            continue;
+        }
+        outln!(
+            "  - {} -> {} ({}, {} bytes)",
+            opts.name(entry["uri"].as_str().unwrap_or_default()),
+            entry["path"].as_str().unwrap_or_default(),
+            entry["kind"].as_str().unwrap_or_default(),
+            entry["bytes"]
+        );
+    }
+}
+
+/// The URIs for `read-resource`: those on the command line, then those in `file`
+fn resource_uris(mut uris: Vec<String>, file: Option<&Path>) -> Result<Vec<String>> {
+    let Some(path) = file else {
+        return Ok(uris);
+    };
+    let list = if path == Path::new("-") {
+        let mut list = String::new();
+        std::io::stdin()
+            .read_to_string(&mut list)
+            .context("failed to read URIs from stdin")?;
+        list
+    } else {
+        std::fs::read_to_string(path)
+            .with_context(|| format!("failed to read URIs from {}", path.display()))?
+    };
+    uris.extend(parse_uri_list(&list));
+    if uris.is_empty() {
+        bail!("{} lists no URIs", path.display());
+    }
+    Ok(uris)
+}
+
+/// One URI per line, skipping blank lines and `#` comments
+fn parse_uri_list(list: &str) -> Vec<String> {
+    list.lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty() && !line.starts_with('#'))
+        .map(str::to_string)
+        .collect()
+}
+
+/// Read each resource in turn over the one session. A resource that can't be
+/// read is reported and the rest are still shown; the command fails at the end.
+async fn read_resources(
+    session: &ClientSession,
+    opts: &Options,
+    uris: &[String],
+    lines: Option<LineRange>,
+) -> Result<()> {
+    if opts.dry_run || uris.len() == 1 {
+        for uri in uris {
+            read_resource(session, opts, uri, lines).await?;
+        }
+        return Ok(());
+    }
+    let results = with_spinner(
+        opts,
+        format!("Reading {} resources...", uris.len()),
+        {{crate_name}}::read_resources(session, opts.timeout, uris),
+    )
+    .await;
+    let mut failed = 0;
+    for (uri, result) in uris.iter().zip(results) {
+        if let Err(e) = result.and_then(|result| show_resource(opts, result, lines)) {
+            failed += 1;
+            let e = e.context(format!("failed to read {}", uri));
+            if opts.structured() {
+                print_structured(opts, &error_envelope(&e))?;
+            } else {
+                outln!("{}", opts.failure(&format!("Error: {:#}", e)));
+            }
+        }
+    }
+    if failed > 0 {
+        return Err(ToolFailed(format!(
+            "{} of {} resources could not be read",
+            failed,
+            uris.len()
+        ))
+        .into());
+    }
+    Ok(())
+}
+
+/// File name for `read-resource --split-dir`: the URI without its scheme,
+/// sanitized, with an extension from the MIME type if the URI has none
+fn uri_file_name(uri: &str, mime_type: Option<&str>, text: bool) -> String {
+    let location = uri.split_once("://").map_or(uri, |(_, rest)| rest);
+    let location = location
+        .split(['?', '#'])
+        .next()
+        .unwrap_or(location)
+        .trim_end_matches('/');
+    let name = sanitize_file_name(location);
+    let last_segment = location.rsplit('/').next().unwrap_or(location);
+    if last_segment
+        .rsplit_once('.')
+        .is_some_and(|(stem, _)| !stem.is_empty())
+    {
+        return name;
+    }
+    let extension = match extension_for_mime(mime_type) {
+        "bin" if text => "txt",
+        extension => extension,
+    };
+    format!("{}.{}", name, extension)
+}
+
+/// `dir/name`, or `dir/name-2` and so on when an earlier content block
+/// already took that name
+fn unused_path(dir: &Path, name: &str, written: &mut BTreeSet<String>) -> PathBuf {
+    let (stem, extension) = match name.rsplit_once('.') {
+        Some((stem, extension)) => (stem, format!(".{}", extension)),
+        None => (name, String::new()),
+    };
+    let mut candidate = name.to_string();
+    let mut n = 1;
+    while !written.insert(candidate.clone()) {
+        n += 1;
+        candidate = format!("{}-{}{}", stem, n, extension);
+    }
+    dir.join(candidate)
+}
+
+/// `read-resource --head` or `--tail`
+#[derive(Clone, Copy, Debug)]
+enum LineRange {
+    Head(usize),
+    Tail(usize),
+}
+
+impl LineRange {
+    fn select(self, text: &str) -> String {
+        let lines: Vec<&str> = text.lines().collect();
+        let selected = match self {
+            LineRange::Head(n) => &lines[..n.min(lines.len())],
+            LineRange::Tail(n) => &lines[lines.len().saturating_sub(n)..],
+        };
+        selected.join("\n")
+    }
+}
+
+fn print_resource(opts: &Options, result: ReadResourceResult) -> Result<()> {
+    if opts.structured() {
+        return print_structured(opts, &result);
+    }
+
+    outln!("Resource content:");
+    let multiple = result.contents.len() > 1;
+    for (index, content) in result.contents.into_iter().enumerate() {
+        outln!("  URI: {}", content.uri);
+        if let Some(mime_type) = &content.mime_type {
+            outln!("  MIME type: {}", mime_type);
+        }
+        if let Some(text) = content.text {
+            let text = opts.clip(&text);
+            let highlighted = match &content.mime_type {
+                Some(mime_type) if opts.highlight => highlight(&text, mime_type),
+                _ => None,
+            };
+            match highlighted {
+                Some(highlighted) => {
+                    outln!("  Text content:");
+                    outln!("{}", highlighted);
+                }
+                None => outln!("  Text content: {}", text),
+            }
+        }
+        if let Some(blob) = content.blob {
+            if let Some(dir) = &opts.out_dir {
+                let mut stem = resource_file_stem(&content.uri).to_string();
+                if multiple {
+                    stem = format!("{}-{}", stem, index + 1);
+                }
+                let path = save_binary(dir, &stem, content.mime_type.as_deref(), &blob)?;
+                outln!("  Binary content: saved to {}", path.display());
+            } else {
+                outln!("  Binary content: {} bytes", blob.len());
+            }
+        }
+    }
+
+    Ok(())
+}
+
+/// Capability groups every server is described in terms of, supported or not
+const KNOWN_CAPABILITIES: &[&str] = &["tools", "resources", "prompts", "logging", "completions"];
+
+fn print_capabilities(servers: &[Server], opts: &Options) -> Result<()> {
+    if opts.structured() {
+        if let [server] = servers {
+            return print_structured(opts, &server.init().capabilities);
+        }
+        let by_server: serde_json::Map<String, Value> = servers
+            .iter()
+            .map(|server| {
+                Ok((
+                    server.name.clone(),
+                    serde_json::to_value(&server.init().capabilities)?,
+                ))
+            })
+            .collect::<Result<_>>()?;
+        return print_structured(opts, &by_server);
+    }
+
+    for server in servers {
+        if servers.len() > 1 {
+            outln!("{}:", opts.name(&server.name));
+        }
+        print_capability_lines(&server.init())?;
+    }
+    Ok(())
+}
+
+fn print_capability_lines(init: &InitializeResult) -> Result<()> {
+    let capabilities = serde_json::to_value(&init.capabilities)?;
+    outln!("Server capabilities:");
+    for name in KNOWN_CAPABILITIES {
+        outln!(
+            "  {}: {}",
+            name,
+            describe_capability(capabilities.get(*name))
+        );
+    }
+    // Anything else the server sent, including experimental flags, as-is
+    if let Value::Object(all) = &capabilities {
+        for (name, value) in all {
+            if !KNOWN_CAPABILITIES.contains(&name.as_str()) && !value.is_null() {
+                outln!("  {}: {}", name, value);
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Report the result of the initialize handshake; connecting already did the work
+fn print_init(servers: &[Server], opts: &Options) -> Result<()> {
+    if opts.structured() {
+        if let [server] = servers {
+            return print_structured(opts, &server.init());
+        }
+        let by_server: serde_json::Map<String, Value> = servers
+            .iter()
+            .map(|server| Ok((server.name.clone(), serde_json::to_value(&server.init())?)))
+            .collect::<Result<_>>()?;
+        return print_structured(opts, &by_server);
+    }
+
+    for server in servers {
+        if servers.len() > 1 {
+            outln!("{}:", opts.name(&server.name));
+        }
+        let init = server.init();
+        outln!("Protocol version: {}", init.protocol_version);
+        outln!(
+            "Server: {} v{}",
+            opts.name(&init.server_info.name),
+            init.server_info.version
+        );
+        print_capability_lines(&init)?;
+    }
+    Ok(())
+}
+
+/// One-line summary of a capability object, e.g. `supported (listChanged, subscribe)`
+fn describe_capability(value: Option<&Value>) -> String {
+    match value {
+        None | Some(Value::Null) => "not supported".to_string(),
+        Some(Value::Object(flags)) => {
+            let enabled: Vec<&str> = flags
+                .iter()
+                .filter(|(_, flag)| flag.as_bool() == Some(true))
+                .map(|(name, _)| name.as_str())
+                .collect();
+            if enabled.is_empty() {
+                "supported".to_string()
+            } else {
+                format!("supported ({})", enabled.join(", "))
+            }
+        }
+        Some(_) => "supported".to_string(),
+    }
+}
+
+/// Whether the server advertised `resources.subscribe` during initialization
+fn supports_subscriptions(init: &InitializeResult) -> bool {
+    init.capabilities
+        .resources
+        .as_ref()
+        .and_then(|resources| resources.subscribe)
+        .unwrap_or(false)
+}
+
+/// The syntax name syntect knows a MIME type's language by, if any
+fn syntax_token_for_mime(mime_type: &str) -> Option<&str> {
+    // Ignore parameters such as `; charset=utf-8`
+    let essence = mime_type.split(';').next().unwrap_or(mime_type).trim();
+    let token = match essence {
+        "application/json" | "application/ld+json" => "json",
+        "application/javascript" | "text/javascript" => "js",
+        "application/typescript" => "ts",
+        "application/xml" | "text/xml" => "xml",
+        "application/toml" => "toml",
+        "application/yaml" | "application/x-yaml" | "text/yaml" => "yaml",
+        "application/sql" => "sql",
+        "text/html" => "html",
+        "text/css" => "css",
+        "text/markdown" => "md",
+        "text/x-shellscript" | "application/x-sh" => "sh",
+        "text/x-rust" => "rs",
+        "text/x-python" => "py",
+        // Other text/x-<language> types usually name the language directly
+        other => other.strip_prefix("text/x-")?,
+    };
+    Some(token)
+}
+
+/// Highlight `text` for a 24-bit color terminal, or `None` if the MIME type
+/// doesn't map to a known syntax
+fn highlight(text: &str, mime_type: &str) -> Option<String> {
+    use syntect::{easy::HighlightLines, highlighting::ThemeSet, parsing::SyntaxSet, util};
+
+    // Loading the bundled definitions is slow, so do it once
+    static SYNTAXES: OnceLock<(SyntaxSet, ThemeSet)> = OnceLock::new();
+    let (syntaxes, themes) = SYNTAXES.get_or_init(|| {
+        (
+            SyntaxSet::load_defaults_newlines(),
+            ThemeSet::load_defaults(),
+        )
+    });
+
+    let syntax = syntaxes.find_syntax_by_token(syntax_token_for_mime(mime_type)?)?;
+    let mut highlighter = HighlightLines::new(syntax, &themes.themes["base16-ocean.dark"]);
+    let mut output = String::new();
+    for line in util::LinesWithEndings::from(text) {
+        let ranges = highlighter.highlight_line(line, syntaxes).ok()?;
+        output.push_str(&util::as_24_bit_terminal_escaped(&ranges, false));
+    }
+    output.push_str("\x1b[0m");
+    Some(output)
+}
+
+/// Print `uri`, then print it again each time it changes until Ctrl-C.
+/// Uses resource subscriptions when the server supports them and falls back
+/// to polling every `interval` otherwise.
+async fn watch_resource(
+    server: &Server,
+    opts: &Options,
+    uri: &str,
+    interval: Duration,
+) -> Result<()> {
+    let subscribe = supports_subscriptions(&server.init());
+
+    let client = client_handle(&server.session).await;
+    let mut last = request(opts, client.read_resource(uri.to_string(), None)).await?;
+    print_resource(opts, last.clone())?;
+
+    if subscribe {
+        let (tx, mut updates) = mpsc::unbounded_channel();
+        let watched = uri.to_string();
+        server
+            .session
+            .set_notification_handler(
+                "notifications/resources/updated",
+                move |params: Option<Value>| {
+                    let updated = params
+                        .as_ref()
+                        .and_then(|params| params.get("uri"))
+                        .and_then(Value::as_str);
+                    if updated == Some(watched.as_str()) {
+                        let _ = tx.send(());
+                    }
+                },
+            )
+            .await;
+        request(opts, client.subscribe_resource(uri.to_string())).await?;
+        info!("Watching {} for updates, press Ctrl-C to stop", uri);
+
+        loop {
+            tokio::select! {
+                _ = tokio::signal::ctrl_c() => break,
+                Some(()) = updates.recv() => {
+                    match request(opts, client.read_resource(uri.to_string(), None)).await {
+                        Ok(result) => print_resource(opts, result)?,
+                        Err(e) => warn!("Failed to re-read {}: {}", uri, e),
+                    }
+                }
+            }
+        }
+
+        if let Err(e) = request(opts, client.unsubscribe_resource(uri.to_string())).await {
+            warn!("Failed to unsubscribe from {}: {}", uri, e);
+        }
+    } else {
+        info!(
+            "Server does not support subscriptions, polling {} every {} seconds (Ctrl-C to stop)",
+            uri,
+            interval.as_secs()
+        );
+        let mut ticker = tokio::time::interval(interval);
+        ticker.tick().await;
+
+        loop {
+            tokio::select! {
+                _ = tokio::signal::ctrl_c() => break,
+                _ = ticker.tick() => {
+                    match request(opts, client.read_resource(uri.to_string(), None)).await {
+                        Ok(result) if result != last => {
+                            last = result.clone();
+                            print_resource(opts, result)?;
+                        }
+                        Ok(_) => debug!("{} unchanged", uri),
+                        Err(e) => warn!("Failed to poll {}: {}", uri, e),
+                    }
+                }
+            }
+        }
+    }
+
+    Ok(())
+}
+
+async fn complete(
+    session: &ClientSession,
+    opts: &Options,
+    reference: CompletionReference,
+    argument: &str,
+    value: &str,
+) -> Result<()> {
+    let client = client_handle(session).await;
+
+    info!("Requesting completions for {} = {:?}", argument, value);
+    let argument = CompletionArgument {
+        name: argument.to_string(),
+        value: value.to_string(),
+    };
+    let result = request(opts, client.complete(reference, argument)).await?;
+
+    if opts.structured() {
+        return print_structured(opts, &result);
+    }
+
+    let completion = result.completion;
+    if completion.values.is_empty() {
+        outln!("No completions available");
+        return Ok(());
+    }
+    outln!("Completions:");
+    for value in &completion.values {
+        outln!("  - {}", value);
+    }
+    if completion.has_more.unwrap_or(false) {
+        match completion.total {
+            Some(total) => outln!("  ... {} total", total),
+            None => outln!("  ... more available"),
+        }
+    }
+
+    Ok(())
+}
+
+/// Send `method` with `params` as-is and print the full JSON-RPC response,
+/// including any error object the server returns
+async fn raw_request(
+    session: &ClientSession,
+    opts: &Options,
+    method: &str,
+    params: Option<&str>,
+) -> Result<()> {
+    let client = client_handle(session).await;
+
+    let params: Option<Value> = match params {
+        Some(params) if !params.trim().is_empty() => {
+            Some(serde_json::from_str(params).context("params must be valid JSON")?)
+        }
+        _ => None,
+    };
+
+    info!("Sending raw request: {}", method);
+    let response = request(opts, client.send_raw_request(method.to_string(), params)).await?;
+    print_structured(opts, &response)
+}
+
+/// Re-send each request in the recording at `path` and compare the responses
+/// with the recorded ones. Fails if any differ.
+async fn replay(session: &ClientSession, opts: &Options, path: &Path) -> Result<()> {
+    let recording = std::fs::read_to_string(path)
+        .with_context(|| format!("failed to read recording {}", path.display()))?;
+
+    let mut reports = Vec::new();
+    for (index, line) in recording.lines().enumerate() {
+        if line.trim().is_empty() {
+            continue;
+        }
+        let entry: Value = serde_json::from_str(line)
+            .with_context(|| format!("{}:{}: not a recorded request", path.display(), index + 1))?;
+        let Some(method) = entry.get("method").and_then(Value::as_str) else {
+            bail!(
+                "{}:{}: recorded request has no method",
+                path.display(),
+                index + 1
+            );
+        };
+        let params = entry.get("params").cloned().unwrap_or(Value::Null);
+        // The replaying session has already made its own handshake
+        if method == "initialize" {
+            debug!("Skipping the recorded initialize on line {}", index + 1);
+            continue;
+        }
+
+        info!("Replaying {} from line {}", method, index + 1);
+        let replayed = replay_request(session, opts, method, &params).await;
+        let mut differences = Vec::new();
+        match (entry.get("result"), &replayed) {
+            (Some(expected), Ok(actual)) => {
+                json_differences("$", expected, actual, &mut differences)
+            }
+            (Some(_), Err(e)) => differences.push(format!("expected a result, got error: {:#}", e)),
+            (None, Ok(_)) => differences.push("expected an error, got a result".to_string()),
+            // Error messages carry details such as timings, so any error matches
+            (None, Err(_)) => {}
+        }
+        reports.push((index + 1, method.to_string(), differences));
+    }
+
+    let mismatched = reports
+        .iter()
+        .filter(|(_, _, differences)| !differences.is_empty())
+        .count();
+    if opts.structured() {
+        let reports: Vec<Value> = reports
+            .iter()
+            .map(|(line, method, differences)| {
+                json!({
+                    "line": line,
+                    "method": method,
+                    "matched": differences.is_empty(),
+                    "differences": differences,
+                })
+            })
+            .collect();
+        print_structured(opts, &reports)?;
+    } else {
+        for (line, method, differences) in &reports {
+            if differences.is_empty() {
+                outln!("  ok line {}: {}", line, method);
+                continue;
+            }
+            outln!("  {} line {}: {}", opts.failure("MISMATCH"), line, method);
+            for difference in differences {
+                outln!("      {}", difference);
+            }
+        }
+        outln!(
+            "{} of {} responses matched",
+            reports.len() - mismatched,
+            reports.len()
+        );
+    }
+
+    if mismatched > 0 {
+        return Err(ToolFailed(format!(
+            "{} replayed responses differ from the recording",
+            mismatched
+        ))
+        .into());
+    }
+    Ok(())
+}
+
+/// Send a recorded request as it was sent when recorded. List requests
+/// are recorded a page at a time, so they fetch just the page at their cursor.
+async fn replay_request(
+    session: &ClientSession,
+    opts: &Options,
+    method: &str,
+    params: &Value,
+) -> Result<Value> {
+    let client = client_handle(session).await;
+    let params = (!params.is_null()).then(|| params.clone());
+    request(opts, client.send_raw_request(method.to_string(), params)).await
+}
+
+/// `diff-servers`: compare the first server, the reference, with the second
+async fn diff_servers(
+    servers: &[Server],
+    opts: &Options,
+    calls: &[(String, String)],
+) -> Result<()> {
+    let [first, second] = servers else {
+        bail!("diff-servers compares exactly two servers; pass --server twice");
+    };
+
+    let mut listings = Vec::new();
+    for server in [first, second] {
+        let tools = if ListingKind::Tools.supported_by(&server.init()) {
+            info!("Listing tools of {}...", server.name);
+            fetch_tools(&server.session, opts.timeout, None)
+                .await?
+                .tools
+        } else {
+            Vec::new()
+        };
+        listings.push(
+            tools
+                .iter()
+                .map(serde_json::to_value)
+                .collect::<std::result::Result<Vec<_>, _>>()?,
+        );
+    }
+    let second_tools = listings.pop().unwrap_or_default();
+    let first_tools = listings.pop().unwrap_or_default();
+    let tools = compare_tools(&first_tools, &second_tools);
+
+    let mut results = Vec::new();
+    for (tool, args_json) in calls {
+        let args = parse_args(&expand_args(opts, args_json)?)?;
+        info!("Calling {} on both servers", tool);
+        let mut outcomes = Vec::new();
+        for server in [first, second] {
+            let result =
+                {{crate_name}}::call_tool(&server.session, opts.timeout, tool, args.clone()).await;
+            outcomes.push(result.and_then(|result| Ok(serde_json::to_value(result)?)));
+        }
+        let mut differences = Vec::new();
+        match (&outcomes[0], &outcomes[1]) {
+            (Ok(expected), Ok(actual)) => json_differences("$", expected, actual, &mut differences),
+            (Ok(_), Err(e)) => differences.push(format!("expected a result, got error: {:#}", e)),
+            (Err(_), Ok(_)) => differences.push("expected an error, got a result".to_string()),
+            // As with replay, error messages vary in detail, so any error matches
+            (Err(_), Err(_)) => {}
+        }
+        results.push(json!({
+            "tool": tool,
+            "arguments": args,
+            "matched": differences.is_empty(),
+            "differences": differences,
+        }));
+    }
+
+    let mismatched_calls = results
+        .iter()
+        .filter(|call| call["matched"] == false)
+        .count();
+    let tool_differences = ["onlyInFirst", "onlyInSecond", "changed"]
+        .iter()
+        .map(|key| tools[key].as_array().map_or(0, Vec::len))
+        .sum::<usize>();
+    let report = json!({
+        "servers": [first.name, second.name],
+        "tools": tools,
+        "calls": results,
+    });
+
+    if opts.structured() {
+        print_structured(opts, &report)?;
+    } else {
+        print_server_diff(opts, &report);
+    }
+
+    if tool_differences + mismatched_calls > 0 {
+        return Err(ToolFailed(format!(
+            "{} differs from {}: {} tool differences, {} calls with different results",
+            second.name, first.name, tool_differences, mismatched_calls
+        ))
+        .into());
+    }
+    Ok(())
+}
+
+/// Tools only one server has, and for tools both have, where the second's
+/// definition differs from the first's
+fn compare_tools(first: &[Value], second: &[Value]) -> Value {
+    let by_name = |tools: &[Value]| -> BTreeMap<String, Value> {
+        tools
+            .iter()
+            .map(|tool| {
+                (
+                    tool["name"].as_str().unwrap_or_default().to_string(),
+                    tool.clone(),
+                )
+            })
+            .collect()
+    };
+    let (first, second) = (by_name(first), by_name(second));
+
+    let only_in =
+        |tools: &BTreeMap<String, Value>, other: &BTreeMap<String, Value>| -> Vec<String> {
+            tools
+                .keys()
+                .filter(|name| !other.contains_key(*name))
+                .cloned()
+                .collect()
+        };
+    let changed: Vec<Value> = first
+        .iter()
+        .filter_map(|(name, expected)| {
+            let actual = second.get(name)?;
+            let mut differences = Vec::new();
+            json_differences("$", expected, actual, &mut differences);
+            (!differences.is_empty()).then(|| json!({ "name": name, "differences": differences }))
+        })
+        .collect();
+    json!({
+        "onlyInFirst": only_in(&first, &second),
+        "onlyInSecond": only_in(&second, &first),
+        "changed": changed,
+    })
+}
+
+/// The `diff-servers` report for people
+fn print_server_diff(opts: &Options, report: &Value) {
+    let names = |value: &Value| -> Vec<String> {
+        value
+            .as_array()
+            .into_iter()
+            .flatten()
+            .map(|item| {
+                item.as_str()
+                    .map_or_else(|| item.to_string(), str::to_string)
+            })
+            .collect()
+    };
+    let servers = names(&report["servers"]);
+    let tools = &report["tools"];
+
+    outln!("Tools:");
+    for (key, server) in [("onlyInFirst", &servers[0]), ("onlyInSecond", &servers[1])] {
+        for name in names(&tools[key]) {
+            outln!("  {} {}: only on {}", opts.failure("MISSING"), name, server);
+        }
+    }
+    for tool in tools["changed"].as_array().into_iter().flatten() {
+        outln!(
+            "  {} {}",
+            opts.failure("CHANGED"),
+            tool["name"].as_str().unwrap_or_default()
+        );
+        for difference in names(&tool["differences"]) {
+            outln!("      {}", difference);
+        }
+    }
+    if ["onlyInFirst", "onlyInSecond", "changed"]
+        .iter()
+        .all(|key| names(&tools[*key]).is_empty())
+    {
+        outln!("  ok: both servers have the same tools");
+    }
+
+    let calls = report["calls"].as_array().cloned().unwrap_or_default();
+    if calls.is_empty() {
+        return;
+    }
+    outln!("Calls:");
+    for call in &calls {
+        let tool = call["tool"].as_str().unwrap_or_default();
+        if call["matched"] == true {
+            outln!("  ok {}", tool);
+            continue;
+        }
+        outln!("  {} {}", opts.failure("MISMATCH"), tool);
+        for difference in names(&call["differences"]) {
+            outln!("      {}", difference);
+        }
+    }
+    let matched = calls.iter().filter(|call| call["matched"] == true).count();
+    outln!("{} of {} calls matched", matched, calls.len());
+}
+
+/// Describe where `actual` differs from `expected`, one line per differing
+/// value, with paths like `$.content[0].text`
+fn json_differences(path: &str, expected: &Value, actual: &Value, differences: &mut Vec<String>) {
+    match (expected, actual) {
+        (Value::Object(expected), Value::Object(actual)) => {
+            let keys: BTreeSet<&String> = expected.keys().chain(actual.keys()).collect();
+            for key in keys {
+                let path = format!("{}.{}", path, key);
+                match (expected.get(key), actual.get(key)) {
+                    (Some(expected), Some(actual)) => {
+                        json_differences(&path, expected, actual, differences)
+                    }
+                    (Some(_), None) => differences.push(format!("{}: missing", path)),
+                    (None, Some(actual)) => {
+                        differences.push(format!("{}: unexpected {}", path, actual))
+                    }
+                    (None, None) => {}
+                }
+            }
+        }
+        (Value::Array(expected_items), Value::Array(actual_items))
+            if expected_items.len() == actual_items.len() =>
+        {
+            for (i, (expected, actual)) in expected_items.iter().zip(actual_items).enumerate() {
+                json_differences(&format!("{}[{}]", path, i), expected, actual, differences);
+            }
+        }
+        (Value::Array(expected_items), Value::Array(actual_items)) => differences.push(format!(
+            "{}: expected {} items, got {}",
+            path,
+            expected_items.len(),
+            actual_items.len()
+        )),
+        _ if expected != actual => {
+            differences.push(format!("{}: expected {}, got {}", path, expected, actual))
+        }
+        _ => {}
+    }
+}
+
+async fn get_prompt(
+    session: &ClientSession,
+    opts: &Options,
+    prompt_name: &str,
+    args_json: &str,
+    as_messages: bool,
+) -> Result<()> {
+    let args_json = expand_args(opts, args_json)?;
+    if opts.dry_run {
+        return print_request(
+            opts,
+            "prompts/get",
+            named_params(prompt_name, parse_args(&args_json)?),
+        );
+    }
+
+    info!("Getting prompt: {} with args: {}", prompt_name, args_json);
+
+    let args = parse_args(&args_json)?;
+    let result = with_spinner(
+        opts,
+        format!("Getting {}...", prompt_name),
+        {{crate_name}}::get_prompt(session, opts.timeout, prompt_name, args),
+    )
+    .await?;
+
+    if as_messages {
+        return print_structured(opts, &chat_messages(&result.messages));
+    }
+    if opts.structured() {
+        return print_structured(opts, &result);
+    }
+
+    outln!("Prompt result:");
+    if let Some(description) = result.description {
+        outln!("  Description: {}", description);
+    }
+
+    for message in result.messages {
+        outln!(
+            "  {} role: {}",
+            message.role,
+            match message.content {
+                mcp_protocol_sdk::protocol::types::PromptContent::Text { text } =>
+                    opts.clip(&text).into_owned(),
+                mcp_protocol_sdk::protocol::types::PromptContent::Image { .. } =>
+                    "[Image content]".to_string(),
+                mcp_protocol_sdk::protocol::types::PromptContent::Resource { .. } =>
+                    "[Resource content]".to_string(),
+                #[allow(unreachable_patterns)]
+                other => unsupported_content(&other),
+            }
+        );
+    }
+
+    Ok(())
+}
+
+async fn ping(session: &ClientSession, opts: &Options) -> Result<()> {
+    let client = client_handle(session).await;
+
+    info!("Pinging server...");
+    let start = Instant::now();
+    let result = request(opts, client.ping()).await;
+    let latency = start.elapsed();
+    result?;
+
+    if opts.structured() {
+        return print_structured(
+            opts,
+            &json!({ "latency_ms": latency.as_secs_f64() * 1000.0 }),
+        );
+    }
+
+    outln!("Pong: {:.1}ms", latency.as_secs_f64() * 1000.0);
+    Ok(())
+}
+
+/// Ping the server in the background every `interval` until aborted
+fn spawn_keepalive(
+    client: Arc<Mutex<McpClient>>,
+    interval: Duration,
+    timeout: Duration,
+) -> JoinHandle<()> {
+    tokio::spawn(async move {
+        let mut ticker = tokio::time::interval(interval);
+        // The first tick completes immediately; skip it so we start after one interval
+        ticker.tick().await;
+        loop {
+            ticker.tick().await;
+            let client = client.lock().await.clone();
+            match tokio::time::timeout(timeout, client.ping()).await {
+                Ok(Ok(_)) => debug!("Keepalive ping succeeded"),
+                Ok(Err(e)) => warn!("Keepalive ping failed, connection may be lost: {}", e),
+                Err(_) => warn!(
+                    "Keepalive ping timed out after {} seconds, connection may be lost",
+                    timeout.as_secs()
+                ),
+            }
+        }
+    })
+}
+
+async fn interactive_mode(
+    servers: &mut Vec<Server>,
+    opts: &Options,
+    connector: &Connector,
+    keepalive: Option<Duration>,
+    concurrency: usize,
+    idle_timeout: Option<Duration>,
+) -> Result<()> {
+    println!("Entering interactive mode. Type 'help' for commands, 'exit' to quit.");
+
+    // Background jobs need their own handle on the servers; `connect` and
+    // `disconnect` take the list back once no job is using it
+    let mut shared = Arc::new(std::mem::take(servers));
+    let mut jobs = Jobs::new(concurrency);
+
+    let cache = Arc::new(std::sync::Mutex::new(CompletionCache::default()));
+    let mut editor: Editor<ReplHelper, DefaultHistory> = Editor::new()?;
+    editor.set_helper(Some(ReplHelper {
+        cache: Arc::clone(&cache),
+        client: None,
+        timeout: opts.timeout,
+    }));
+    // Update notifications arrive while the prompt is showing, so print them
+    // through rustyline to keep the line being edited intact
+    let notify: Arc<dyn Fn(String) + Send + Sync> = match editor.create_external_printer() {
+        Ok(printer) => {
+            let printer = std::sync::Mutex::new(printer);
+            Arc::new(move |message| {
+                if let Ok(mut printer) = printer.lock() {
+                    let _ = printer.print(message);
+                }
+            })
+        }
+        Err(_) => Arc::new(|message| eprintln!("{}", message)),
+    };
+    let mut keepalive_tasks =
+        attach_sessions(&shared, opts, keepalive, &cache, &notify, &mut editor).await;
+
+    // Piped commands (`echo tools | ... interactive`) run once and end at EOF;
+    // they shouldn't end up in the user's history
+    let piped = !std::io::stdin().is_terminal();
+    let history = if piped { None } else { history_path() };
+    if let Some(path) = &history {
+        // A missing history file just means this is the first session
+        let _ = editor.load_history(path);
+    }
+
+    loop {
+        let stale = cache.lock().map(|cache| cache.stale).unwrap_or(false);
+        if stale {
+            refresh_completions(&shared, opts, &cache).await;
+        }
+
+        // An idle timeout exits while the read is still waiting on the
+        // terminal, so by then the history has to be on disk already and the
+        // terminal's settings saved to undo rustyline's raw mode
+        let mut terminal_mode = None;
+        if idle_timeout.is_some() {
+            if let Some(path) = &history {
+                if let Err(e) = save_history(&mut editor, path) {
+                    warn!("Failed to save history to {}: {}", path.display(), e);
+                }
+            }
+            terminal_mode = TerminalMode::save();
+        }
+        let Some((returned, line)) =
+            read_line(editor, idle_timeout, |editor| editor.readline("> ")).await?
+        else {
+            if let Some(mode) = &terminal_mode {
+                mode.restore();
+            }
+            let idle = idle_timeout.map_or(0, |limit| limit.as_secs());
+            eprintln!(
+                "\nNo command for {} seconds; disconnecting and exiting",
+                idle
+            );
+            for handle in keepalive_tasks {
+                handle.abort();
+            }
+            jobs.abort_all().await;
+            disconnect_all(&shared).await;
+            flush_output()?;
+            // The pending read can't be interrupted, and the runtime would
+            // wait for it on the way out
+            std::process::exit(0);
+        };
+        editor = returned;
+        let line = match line {
+            Ok(line) => line,
+            // Ctrl-C abandons the current line but keeps the session open
+            Err(ReadlineError::Interrupted) => continue,
+            Err(ReadlineError::Eof) => {
+                // Leave the shell prompt on its own line after Ctrl-D
+                if !piped {
+                    println!();
+                }
+                break;
+            }
+            Err(e) => return Err(e.into()),
+        };
+        let input = line.trim();
+
+        if input.is_empty() {
+            continue;
+        }
+        let _ = editor.add_history_entry(input);
+
+        if input == "exit" || input == "quit" {
+            break;
+        }
+
+        // Switching servers replaces the connection list itself, so it's handled here
+        let words: Vec<&str> = input.split_whitespace().collect();
+        if matches!(words[0], "connect" | "disconnect") {
+            let Some(servers) = Arc::get_mut(&mut shared) else {
+                error!(
+                    "Background jobs are still running; `wait` for them before switching servers"
+                );
+                continue;
+            };
+            match switch_server(servers, opts, connector, words[0], &words[1..]).await {
+                Ok(()) => {
+                    for handle in keepalive_tasks.drain(..) {
+                        handle.abort();
+                    }
+                    keepalive_tasks =
+                        attach_sessions(&shared, opts, keepalive, &cache, &notify, &mut editor)
+                            .await;
+                }
+                Err(e) => error!("{:#}", e),
+            }
+            continue;
+        }
+        match words[0] {
+            "jobs" => {
+                jobs.list();
+                continue;
+            }
+            "wait" => {
+                // Ctrl-C stops waiting; the jobs keep running
+                tokio::select! {
+                    result = jobs.wait(words.get(1).copied()) => {
+                        if let Err(e) = result {
+                            error!("{:#}", e);
+                        }
+                    }
+                    _ = tokio::signal::ctrl_c() => println!("Stopped waiting"),
+                }
+                continue;
+            }
+            _ => {}
+        }
+        if let Some(command) = input.strip_suffix('&') {
+            let command = command.trim();
+            if command.is_empty() {
+                error!("Nothing to run in the background");
+            } else {
+                jobs.spawn(command, &shared, opts, &notify);
+            }
+            continue;
+        }
+
+        // Ctrl-C while a command runs abandons it and cancels the tool call
+        // in flight on the server; the session stays open for the next command
+        let outcome = tokio::select! {
+            result = execute_line(&shared, opts, input) => Some(result),
+            _ = tokio::signal::ctrl_c() => None,
+        };
+        let Some(result) = outcome else {
+            cancel_in_flight().await;
+            println!("cancelled");
+            continue;
+        };
+
+        if let Err(e) = result {
+            error!("{:#}", e);
+            if let Some(hint) = opts.error_hint(&e) {
+                eprintln!("{}", hint);
+            }
+            if is_transport_error(&e) {
+                match recover_connections(&shared, opts).await {
+                    Ok(()) => println!("Connection restored; re-run the command to retry it"),
+                    Err(e) => error!("{:#}", e),
+                }
+            }
+        }
+    }
+
+    for handle in keepalive_tasks {
+        handle.abort();
+    }
+    jobs.abort_all().await;
+    // With every job gone this is the only handle, so the servers can be disconnected
+    match Arc::try_unwrap(shared) {
+        Ok(shared) => *servers = shared,
+        Err(_) => warn!("Background jobs still hold the connections; skipping disconnect"),
+    }
+
+    if let Some(path) = &history {
+        if let Err(e) = save_history(&mut editor, path) {
+            warn!("Failed to save history to {}: {}", path.display(), e);
+        }
+    }
+
+    println!("Exiting interactive mode");
+    Ok(())
+}
+
+/// Read the next line at the prompt with `read`. With an idle timeout the
+/// read runs on a blocking thread, lent the editor, so a timer can race it;
+/// `None` means the timer won and the editor is still with the pending read.
+async fn read_line<E, F>(
+    mut editor: E,
+    idle_timeout: Option<Duration>,
+    read: F,
+) -> Result<Option<(E, rustyline::Result<String>)>>
+where
+    E: Send + 'static,
+    F: FnOnce(&mut E) -> rustyline::Result<String> + Send + 'static,
+{
+    let Some(limit) = idle_timeout else {
+        let line = read(&mut editor);
+        return Ok(Some((editor, line)));
+    };
+    let read = tokio::task::spawn_blocking(move || {
+        let line = read(&mut editor);
+        (editor, line)
+    });
+    match tokio::time::timeout(limit, read).await {
+        Ok(read) => Ok(Some(read?)),
+        Err(_) => Ok(None),
+    }
+}
+
+/// Settings of the terminal on stdin, to put back if the program exits while
+/// rustyline has it in raw mode
+#[cfg(unix)]
+struct TerminalMode(libc::termios);
+
+#[cfg(unix)]
+impl TerminalMode {
+    /// `None` if stdin isn't a terminal
+    fn save() -> Option<Self> {
+        let mut termios = std::mem::MaybeUninit::uninit();
+        // SAFETY: tcgetattr fills in the termios it is given, or fails without touching it
+        if unsafe { libc::tcgetattr(libc::STDIN_FILENO, termios.as_mut_ptr()) } != 0 {
+            return None;
+        }
+        // SAFETY: tcgetattr succeeded, so the termios is initialized
+        Some(Self(unsafe { termios.assume_init() }))
+    }
+
+    fn restore(&self) {
+        // SAFETY: the termios came from tcgetattr on the same descriptor
+        unsafe {
+            libc::tcsetattr(libc::STDIN_FILENO, libc::TCSANOW, &self.0);
+        }
+    }
+}
+
+/// Windows consoles aren't saved; the console mode is left for the shell to reset
+#[cfg(not(unix))]
+struct TerminalMode;
+
+#[cfg(not(unix))]
+impl TerminalMode {
+    fn save() -> Option<Self> {
+        None
+    }
+
+    fn restore(&self) {}
+}
+
+tokio::task_local! {
+    /// Output of an interactive background job, held until `wait` collects it.
+    /// `--repeat` also uses it to drop the output of all but the last run.
+    static JOB_OUTPUT: std::cell::RefCell<String>;
+
+    /// Set for an interactive background job, which can't prompt the user or
+    /// show a spinner. `--repeat` runs are foreground commands and don't set it.
+    static BACKGROUND_JOB: ();
+}
+
+fn in_background_job() -> bool {
+    BACKGROUND_JOB.try_with(|_| ()).is_ok()
+}
+
+struct Job {
+    id: usize,
+    command: String,
+    handle: JoinHandle<(String, Result<()>)>,
+}
+
+/// Commands started with a trailing `&` at the interactive prompt
+struct Jobs {
+    running: Vec<Job>,
+    next_id: usize,
+    /// Bounds how many jobs send requests at once; the rest queue
+    permits: Arc<Semaphore>,
+}
+
+impl Jobs {
+    fn new(concurrency: usize) -> Self {
+        Self {
+            running: Vec::new(),
+            next_id: 1,
+            permits: Arc::new(Semaphore::new(concurrency.max(1))),
+        }
+    }
+
+    fn spawn(
+        &mut self,
+        command: &str,
+        servers: &Arc<Vec<Server>>,
+        opts: &Options,
+        notify: &Arc<dyn Fn(String) + Send + Sync>,
+    ) {
+        let id = self.next_id;
+        self.next_id += 1;
+
+        let servers = Arc::clone(servers);
+        let opts = opts.clone();
+        let notify = Arc::clone(notify);
+        let permits = Arc::clone(&self.permits);
+        let line = command.to_string();
+        // Requests go through client handles rather than holding the session
+        // lock, so a slow job doesn't hold up foreground commands
+        let job = JOB_OUTPUT.scope(Default::default(), async move {
+            let result = match permits.acquire_owned().await {
+                Ok(_permit) => execute_line(&servers, &opts, &line).await.map(|_| ()),
+                Err(e) => Err(e.into()),
+            };
+            let output = JOB_OUTPUT.with(|output| output.take());
+            let status = if result.is_ok() { "done" } else { "failed" };
+            notify(format!(
+                "[{}] {}: {} (use `wait {}` for the result)",
+                id, status, line, id
+            ));
+            (output, result)
+        });
+        let handle = tokio::spawn(BACKGROUND_JOB.scope((), job));
+
+        outln!("[{}] {}", id, command);
+        self.running.push(Job {
+            id,
+            command: command.to_string(),
+            handle,
+        });
+    }
+
+    fn list(&self) {
+        if self.running.is_empty() {
+            outln!("No background jobs");
+        }
+        for job in &self.running {
+            let state = if job.handle.is_finished() {
+                "done"
+            } else {
+                "running"
+            };
+            outln!("  [{}] {:<7} {}", job.id, state, job.command);
+        }
+    }
+
+    /// Collect one job's output, or every job's when `id` is `None`. A job
+    /// stays in the list until its result has been shown, so an interrupted
+    /// wait loses nothing.
+    async fn wait(&mut self, id: Option<&str>) -> Result<()> {
+        let ids: Vec<usize> = match id {
+            None => self.running.iter().map(|job| job.id).collect(),
+            Some(id) => {
+                let id: usize = id
+                    .trim_start_matches('%')
+                    .parse()
+                    .context("Usage: wait [job id]")?;
+                if !self.running.iter().any(|job| job.id == id) {
+                    bail!("No job [{}]", id);
+                }
+                vec![id]
+            }
+        };
+        if ids.is_empty() {
+            outln!("No background jobs");
+        }
+
+        let mut failures = 0;
+        for id in ids {
+            let Some(index) = self.running.iter().position(|job| job.id == id) else {
+                continue;
+            };
+            let (output, result) = (&mut self.running[index].handle).await?;
+            let job = self.running.remove(index);
+            outln!("[{}] {}", job.id, job.command);
+            for line in output.lines() {
+                outln!("{}", line);
+            }
+            if let Err(e) = result {
+                error!("[{}] {:#}", job.id, e);
+                failures += 1;
+            }
+        }
+        if failures > 0 {
+            bail!("{} job(s) failed", failures);
+        }
+        Ok(())
+    }
+
+    async fn abort_all(&mut self) {
+        for job in self.running.drain(..) {
+            job.handle.abort();
+            let _ = job.handle.await;
+        }
+    }
+}
+
+/// Hook the interactive prompt up to the current servers: completions, update
+/// notices and keepalive pings. Returns the keepalive tasks.
+async fn attach_sessions(
+    servers: &[Server],
+    opts: &Options,
+    keepalive: Option<Duration>,
+    cache: &SharedCompletionCache,
+    notify: &Arc<dyn Fn(String) + Send + Sync>,
+    editor: &mut Editor<ReplHelper, DefaultHistory>,
+) -> Vec<JoinHandle<()>> {
+    for server in servers {
+        watch_list_changes(server, cache).await;
+        let label = (servers.len() > 1).then(|| server.name.clone());
+        watch_resource_updates(&server.session, label, Arc::clone(notify)).await;
+    }
+    refresh_completions(servers, opts, cache).await;
+
+    if let Some(helper) = editor.helper_mut() {
+        helper.client = servers.first().map(|server| server.session.client());
+    }
+
+    match keepalive {
+        Some(interval) => servers
+            .iter()
+            .map(|server| spawn_keepalive(server.session.client(), interval, opts.timeout))
+            .collect(),
+        None => Vec::new(),
+    }
+}
+
+/// Handle the interactive `connect <command> [args]` and `disconnect`
+/// commands. The current sessions are always shut down first, and the new
+/// one is set up like those made at startup.
+async fn switch_server(
+    servers: &mut Vec<Server>,
+    opts: &Options,
+    connector: &Connector,
+    command: &str,
+    args: &[&str],
+) -> Result<()> {
+    let Some((target, server_args)) = args.split_first() else {
+        if command == "connect" {
+            bail!("Usage: connect <command> [args]");
+        }
+        disconnect_all(servers).await;
+        servers.clear();
+        outln!("Disconnected");
+        return Ok(());
+    };
+    if command == "disconnect" {
+        bail!("Usage: disconnect");
+    }
+
+    disconnect_all(servers).await;
+    servers.clear();
+
+    let (transport, endpoint_target) = parse_target(target)?;
+    let endpoint = Endpoint {
+        transport,
+        target: endpoint_target,
+        server_args: server_args.iter().map(|arg| arg.to_string()).collect(),
+        server_env: connector.server_env.clone(),
+        working_dir: connector.working_dir.clone(),
+        headers: connector.headers.clone(),
+        init_timeout: connector.init_timeout,
+        ready_probe: connector.ready_probe.clone(),
+        server_stderr: connector.server_stderr.clone(),
+    };
+    let server = connector
+        .connect(endpoint, server_name(target))
+        .await
+        .context("Not connected; use `connect` to try again")?;
+    let init = server.init();
+    outln!(
+        "Connected to {} v{}",
+        init.server_info.name,
+        init.server_info.version
+    );
+    // Connected either way; a server that rejects the level still works
+    if let Err(e) = configure_server_logging(std::slice::from_ref(&server), opts).await {
+        warn!("{:#}", e);
+    }
+    servers.push(server);
+    Ok(())
+}
+
+/// Run one interactive-style command line against the connected servers,
+/// returning the text result of `call` and `read` for scripts
+async fn execute_line(servers: &[Server], opts: &Options, input: &str) -> Result<Option<String>> {
+    let started = Instant::now();
+    let result = dispatch_line(servers, opts, input).await;
+    report_timing(opts, started);
+    result
+}
+
+async fn dispatch_line(servers: &[Server], opts: &Options, input: &str) -> Result<Option<String>> {
+    let parts: Vec<&str> = input.splitn(3, ' ').collect();
+    let command = parts[0];
+
+    // These work without a connection
+    match command {
+        "help" => {
+            print_repl_help();
+            return Ok(None);
+        }
+        "subscriptions" => {
+            list_subscriptions(servers);
+            return Ok(None);
+        }
+        _ => {}
+    }
+    let Some(first) = servers.first() else {
+        bail!("Not connected to a server; use `connect <command> [args]` first");
+    };
+    let session = &first.session;
+
+    // Commands with a text result that scripts can pass on as `$PREV`
+    match command {
+        "call" => {
+            let Some(tool) = parts.get(1) else {
+                bail!("Usage: call <[server::]tool> [args]");
+            };
+            let args = repl_args(parts.get(2).copied().unwrap_or_default())?;
+            return call_tool_on(servers, opts, tool, &args)
+                .await
+                .context("Error calling tool");
+        }
+        "read" => {
+            let Some(uri) = parts.get(1) else {
+                bail!("Usage: read <uri>");
+            };
+            return read_resource(session, opts, uri, None)
+                .await
+                .context("Error reading resource");
+        }
+        _ => {}
+    }
+
+    let result = match command {
+        "tools" => list_tools_all(servers, opts, false, None)
+            .await
+            .context("Error listing tools"),
+        "count" => count_everything(&servers[0], opts)
+            .await
+            .context("Error counting listings"),
+        "search" => {
+            let Some(query) = input.split_once(' ').map(|(_, query)| query.trim()) else {
+                bail!("Usage: search <text>");
+            };
+            search_tools(servers, opts, &ToolQuery::new(query, false)?)
+                .await
+                .context("Error searching tools")
+        }
+        "resources" => list_resources(&servers[0], opts, None)
+            .await
+            .context("Error listing resources"),
+        "templates" => list_resource_templates(&servers[0], opts, None)
+            .await
+            .context("Error listing resource templates"),
+        "prompts" => list_prompts(&servers[0], opts, None)
+            .await
+            .context("Error listing prompts"),
+        "describe" => {
+            let Some(tool) = parts.get(1) else {
+                bail!("Usage: describe <[server::]tool>");
+            };
+            describe_tool(servers, opts, tool)
+                .await
+                .context("Error describing tool")
+        }
+        "prompt" => {
+            let Some(name) = parts.get(1) else {
+                bail!("Usage: prompt <name> [args]");
+            };
+            let args = parts.get(2).unwrap_or(&"{}");
+            // Rather than failing on missing arguments, ask for them
+            let args = if std::io::stdin().is_terminal() && !opts.dry_run {
+                ask_prompt_args(session, opts, name, args).await?
+            } else {
+                args.to_string()
+            };
+            get_prompt(session, opts, name, &args, false)
+                .await
+                .context("Error getting prompt")
+        }
+        "ping" => ping(session, opts).await.context("Error pinging server"),
+        "subscribe" => {
+            let Some(target) = parts.get(1) else {
+                bail!("Usage: subscribe <[server::]uri>");
+            };
+            async {
+                let (server, uri) = resolve_resource(servers, opts, target).await?;
+                subscribe(server, opts, &uri).await
+            }
+            .await
+            .context("Error subscribing to resource")
+        }
+        "unsubscribe" => {
+            let Some(target) = parts.get(1) else {
+                bail!("Usage: unsubscribe <[server::]uri>");
+            };
+            async {
+                let (server, uri) = resolve_subscription(servers, target)?;
+                unsubscribe(server, opts, &uri).await
+            }
+            .await
+            .context("Error unsubscribing from resource")
+        }
+        "capabilities" => print_capabilities(servers, opts),
+        "raw" => {
+            let Some(method) = parts.get(1) else {
+                bail!("Usage: raw <method> [params]");
+            };
+            raw_request(session, opts, method, parts.get(2).copied())
+                .await
+                .context("Error sending request")
+        }
+        _ => bail!(
+            "Unknown command: {}. Type 'help' for available commands.",
+            command
+        ),
+    };
+    result.map(|_| None)
+}
+
+/// Arguments typed after `call <tool>`: a JSON object, or `key=value` pairs
+/// turned into one. Values that parse as JSON (numbers, booleans, null,
+/// quoted strings, arrays) keep their type; anything else is a string.
+fn repl_args(input: &str) -> Result<String> {
+    let input = input.trim();
+    if input.is_empty() || input.starts_with('{') {
+        return Ok(input.to_string());
+    }
+
+    let mut args = serde_json::Map::new();
+    for pair in split_words(input)? {
+        let Some((key, value)) = pair.split_once('=').filter(|(key, _)| !key.is_empty()) else {
+            bail!("expected key=value or a JSON object, got '{}'", pair);
+        };
+        let value =
+            serde_json::from_str(value).unwrap_or_else(|_| Value::String(value.to_string()));
+        args.insert(key.to_string(), value);
+    }
+    Ok(Value::Object(args).to_string())
+}
+
+/// Split on whitespace, keeping double-quoted sections (with `\` escapes) in one word
+fn split_words(input: &str) -> Result<Vec<String>> {
+    let mut words = Vec::new();
+    let mut word = String::new();
+    let mut quoted = false;
+    let mut chars = input.chars();
+    while let Some(c) = chars.next() {
+        match c {
+            '"' => {
+                quoted = !quoted;
+                word.push(c);
+            }
+            '\\' if quoted => {
+                word.push(c);
+                word.extend(chars.next());
+            }
+            c if c.is_whitespace() && !quoted => {
+                if !word.is_empty() {
+                    words.push(std::mem::take(&mut word));
+                }
+            }
+            c => word.push(c),
+        }
+    }
+    if quoted {
+        bail!("unterminated quote in '{}'", input);
+    }
+    if !word.is_empty() {
+        words.push(word);
+    }
+    Ok(words)
+}
+
+/// Announce `notifications/resources/updated` from `session` through `notify`
+async fn watch_resource_updates(
+    session: &ClientSession,
+    label: Option<String>,
+    notify: Arc<dyn Fn(String) + Send + Sync>,
+) {
+    session
+        .set_notification_handler(
+            "notifications/resources/updated",
+            move |params: Option<Value>| {
+                let uri = params
+                    .as_ref()
+                    .and_then(|params| params.get("uri"))
+                    .and_then(Value::as_str)
+                    .unwrap_or("<unknown>");
+                match &label {
+                    Some(server) => notify(format!("[{}] Resource updated: {}", server, uri)),
+                    None => notify(format!("Resource updated: {}", uri)),
+                }
+            },
+        )
+        .await;
+}
+
+/// The server a `[server::]uri` argument refers to, and the bare URI. An
+/// unqualified URI goes to the only server, or else to the one server whose
+/// resource listing includes it. URIs may contain `::` themselves, so a
+/// prefix only counts when it names a connected server.
+async fn resolve_resource<'a>(
+    servers: &'a [Server],
+    opts: &Options,
+    target: &str,
+) -> Result<(&'a Server, String)> {
+    if let Some((name, uri)) = target.split_once("::") {
+        if let Some(server) = servers.iter().find(|server| server.name == name) {
+            return Ok((server, uri.to_string()));
+        }
+    }
+
+    if let [server] = servers {
+        return Ok((server, target.to_string()));
+    }
+
+    let mut providers = Vec::new();
+    for server in servers {
+        if !ListingKind::Resources.supported_by(&server.init()) {
+            continue;
+        }
+        let resources = fetch_resources(&server.session, opts.timeout, None).await?;
+        if resources
+            .resources
+            .iter()
+            .any(|resource| resource.uri == target)
+        {
+            providers.push(server);
+        }
+    }
+
+    match providers.as_slice() {
+        [] => bail!(
+            "no connected server lists resource '{}'; use server::uri to pick one",
+            target
+        ),
+        [server] => Ok((server, target.to_string())),
+        _ => {
+            let names: Vec<&str> = providers.iter().map(|s| s.name.as_str()).collect();
+            bail!(
+                "resource '{}' is listed by several servers ({}); use server::uri to pick one",
+                target,
+                names.join(", ")
+            )
+        }
+    }
+}
+
+/// The server holding the subscription a `[server::]uri` argument refers to,
+/// and the bare URI
+fn resolve_subscription<'a>(servers: &'a [Server], target: &str) -> Result<(&'a Server, String)> {
+    let subscribed = |server: &Server, uri: &str| {
+        server
+            .subscriptions
+            .lock()
+            .map(|subscriptions| subscriptions.contains(uri))
+            .unwrap_or(false)
+    };
+    if let Some((name, uri)) = target.split_once("::") {
+        if let Some(server) = servers.iter().find(|server| server.name == name) {
+            return Ok((server, uri.to_string()));
+        }
+    }
+
+    let holders: Vec<&Server> = servers
+        .iter()
+        .filter(|server| subscribed(server, target))
+        .collect();
+    match holders.as_slice() {
+        [] => bail!("Not subscribed to {}", target),
+        [server] => Ok((server, target.to_string())),
+        _ => bail!(
+            "Subscribed to {} on several servers; use server::uri to pick one",
+            target
+        ),
+    }
+}
+
+async fn subscribe(server: &Server, opts: &Options, uri: &str) -> Result<()> {
+    if !supports_subscriptions(&server.init()) {
+        bail!(
+            "{} does not support resource subscriptions; use `read` to fetch it again",
+            server.name
+        );
+    }
+
+    let client = client_handle(&server.session).await;
+    request(opts, client.subscribe_resource(uri.to_string())).await?;
+    if let Ok(mut subscriptions) = server.subscriptions.lock() {
+        subscriptions.insert(uri.to_string());
+    }
+    outln!("Subscribed to {}", uri);
+    Ok(())
+}
+
+async fn unsubscribe(server: &Server, opts: &Options, uri: &str) -> Result<()> {
+    let subscribed = server
+        .subscriptions
+        .lock()
+        .map(|subscriptions| subscriptions.contains(uri))
+        .unwrap_or(false);
+    if !subscribed {
+        bail!("Not subscribed to {}", uri);
+    }
+
+    let client = client_handle(&server.session).await;
+    request(opts, client.unsubscribe_resource(uri.to_string())).await?;
+    if let Ok(mut subscriptions) = server.subscriptions.lock() {
+        subscriptions.remove(uri);
+    }
+    outln!("Unsubscribed from {}", uri);
+    Ok(())
+}
+
+fn list_subscriptions(servers: &[Server]) {
+    let mut any = false;
+    for server in servers {
+        let Ok(subscriptions) = server.subscriptions.lock() else {
+            continue;
+        };
+        for uri in subscriptions.iter() {
+            any = true;
+            if servers.len() > 1 {
+                outln!("  {}::{}", server.name, uri);
+            } else {
+                outln!("  {}", uri);
+            }
+        }
+    }
+    if !any {
+        outln!("No active subscriptions");
+    }
+}
+
+fn print_repl_help() {
+    outln!("Available commands:");
+    outln!("  tools - List available tools");
+    outln!("  search <text> - List tools whose name or description contains the text");
+    outln!("  count - Show how many tools, resources, templates and prompts there are");
+    outln!("  resources - List available resources");
+    outln!("  templates - List resource URI templates");
+    outln!("  prompts - List available prompts");
+    outln!("  describe <[server::]tool> - Show a tool's description and input schema");
+    outln!("  call <[server::]tool> [args] - Call a tool; args are JSON or key=value pairs");
+    outln!("  read <uri> - Read a resource");
+    outln!("  prompt <name> [args] - Get a prompt, asking for missing arguments");
+    outln!("  ping - Check that the server is responsive");
+    outln!("  capabilities - Show what the server supports");
+    outln!("  subscribe <[server::]uri> - Print a notice whenever a resource changes");
+    outln!("  unsubscribe <[server::]uri> - Stop receiving updates for a resource");
+    outln!("  subscriptions - List active resource subscriptions");
+    outln!("  raw <method> [params] - Send a JSON-RPC request and print the raw response");
+    outln!("  <command> & - Run a command in the background");
+    outln!("  jobs - List background jobs");
+    outln!("  wait [id] - Wait for a background job (or all of them) and show its output");
+    outln!("  connect <command> [args] - Disconnect and start a session with another server");
+    outln!("  disconnect - Disconnect from the current server");
+    outln!("  help - Show this help");
+    outln!("  exit - Exit interactive mode");
+}
+
+/// Execute the interactive-style commands in `path`, one per line.
+/// Blank lines and lines starting with `#` are skipped. The text result of
+/// the last `call` or `read` is available to the next command as `$PREV`.
+async fn run_script(
+    servers: &[Server],
+    opts: &Options,
+    path: &Path,
+    continue_on_error: bool,
+) -> Result<()> {
+    let script = std::fs::read_to_string(path)
+        .with_context(|| format!("failed to read script {}", path.display()))?;
+
+    let mut failures = 0;
+    let mut prev = None;
+    for (index, line) in script.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        if line == "exit" || line == "quit" {
+            break;
+        }
+
+        let location = format!("{}:{}", path.display(), index + 1);
+        info!("{}: {}", location, line);
+        if let Err(e) = run_pipeline(servers, opts, line, &mut prev).await {
+            if !continue_on_error {
+                return Err(e.context(format!("{}: `{}` failed", location, line)));
+            }
+            error!("{}: {:#}", location, e);
+            failures += 1;
+        }
+    }
+
+    if failures > 0 {
+        bail!("{} command(s) in {} failed", failures, path.display());
+    }
+    Ok(())
+}
+
+/// Run a script line made of stages separated by ` | `, substituting the
+/// previous text result for `$PREV`. A piped stage without `$PREV` must be
+/// a bare `call <tool>`, which receives the text as its single argument.
+async fn run_pipeline(
+    servers: &[Server],
+    opts: &Options,
+    line: &str,
+    prev: &mut Option<String>,
+) -> Result<()> {
+    for (index, stage) in line.split(" | ").enumerate() {
+        let stage = stage.trim();
+        let command = if stage.contains("$PREV") {
+            let text = prev.as_deref().ok_or_else(|| {
+                anyhow!(
+                    "`{}` uses $PREV but the previous command produced no text",
+                    stage
+                )
+            })?;
+            stage.replace("$PREV", &json_escape(text))
+        } else if index > 0 {
+            piped_call(servers, opts, stage, prev.as_deref()).await?
+        } else {
+            stage.to_string()
+        };
+        *prev = execute_line(servers, opts, &command).await?;
+    }
+    Ok(())
+}
+
+/// Rewrite a piped `call <tool>` stage to pass `text` as the tool's argument
+async fn piped_call(
+    servers: &[Server],
+    opts: &Options,
+    stage: &str,
+    text: Option<&str>,
+) -> Result<String> {
+    let text = text.ok_or_else(|| {
+        anyhow!(
+            "nothing to pipe into `{}`: the previous command produced no text",
+            stage
+        )
+    })?;
+    let target = match stage.split_once(' ') {
+        Some(("call", target)) if !target.trim().contains(' ') => target.trim(),
+        _ => bail!(
+            "`{}` can't take piped input; pass it explicitly with $PREV",
+            stage
+        ),
+    };
+
+    let (server, name) = resolve_tool(servers, opts, target).await?;
+    let tools = fetch_tools(&server.session, opts.timeout, None).await?;
+    let tool = tools
+        .tools
+        .into_iter()
+        .find(|tool| tool.name == name)
+        .ok_or_else(|| anyhow!("tool '{}' not found on {}", name, server.name))?;
+
+    let schema = serde_json::to_value(&tool.input_schema)?;
+    let argument = piped_argument(&schema).ok_or_else(|| {
+        anyhow!(
+            "tool '{}' has no single argument to pipe into; pass it explicitly with $PREV",
+            name
+        )
+    })?;
+    let mut args = serde_json::Map::new();
+    args.insert(argument, Value::String(text.to_string()));
+    Ok(format!("call {} {}", target, Value::Object(args)))
+}
+
+/// The argument piped text should fill: the schema's only required
+/// property, or its only property when nothing is required
+fn piped_argument(schema: &Value) -> Option<String> {
+    let required: Vec<&str> = schema
+        .get("required")
+        .and_then(Value::as_array)
+        .map(|required| required.iter().filter_map(Value::as_str).collect())
+        .unwrap_or_default();
+    if let [name] = required.as_slice() {
+        return Some(name.to_string());
+    }
+
+    let properties = schema.get("properties")?.as_object()?;
+    match (required.is_empty(), properties.len()) {
+        (true, 1) => properties.keys().next().cloned(),
+        _ => None,
+    }
+}
+
+/// Escape `text` for splicing into a JSON string literal
+fn json_escape(text: &str) -> String {
+    let quoted = Value::String(text.to_string()).to_string();
+    quoted[1..quoted.len() - 1].to_string()
+}
+
+/// Commands understood by the interactive prompt, offered for tab completion
+const REPL_COMMANDS: &[&str] = &[
+    "tools",
+    "search",
+    "count",
+    "resources",
+    "templates",
+    "prompts",
+    "describe",
+    "call",
+    "read",
+    "prompt",
+    "ping",
+    "capabilities",
+    "subscribe",
+    "unsubscribe",
+    "subscriptions",
+    "raw",
+    "jobs",
+    "wait",
+    "connect",
+    "disconnect",
+    "help",
+    "exit",
+];
+
+/// Tool, resource and prompt names offered for tab completion
+#[derive(Default)]
+struct CompletionCache {
+    tools: Vec<String>,
+    resources: Vec<String>,
+    prompts: Vec<String>,
+    /// Set when a server reports that one of its lists changed
+    stale: bool,
+}
+
+type SharedCompletionCache = Arc<std::sync::Mutex<CompletionCache>>;
+
+/// Re-fetch the names used for completion. Tools come from every server
+/// (prefixed with `server::` when there are several); resources and prompts
+/// come from the first server, which handles `read` and `prompt`.
+async fn refresh_completions(servers: &[Server], opts: &Options, cache: &SharedCompletionCache) {
+    let mut fresh = CompletionCache::default();
+    let prefixed = servers.len() > 1;
+
+    for (index, server) in servers.iter().enumerate() {
+        match fetch_tools(&server.session, opts.timeout, None).await {
+            Ok(tools) => fresh.tools.extend(tools.tools.into_iter().map(|tool| {
+                if prefixed {
+                    format!("{}::{}", server.name, tool.name)
+                } else {
+                    tool.name
+                }
+            })),
+            Err(e) => debug!("Not completing tools from {}: {}", server.name, e),
+        }
+
+        if index > 0 {
+            continue;
+        }
+        match fetch_resources(&server.session, opts.timeout, None).await {
+            Ok(resources) => fresh
+                .resources
+                .extend(resources.resources.into_iter().map(|resource| resource.uri)),
+            Err(e) => debug!("Not completing resources: {}", e),
+        }
+        match fetch_prompts(&server.session, opts.timeout, None).await {
+            Ok(prompts) => fresh
+                .prompts
+                .extend(prompts.prompts.into_iter().map(|prompt| prompt.name)),
+            Err(e) => debug!("Not completing prompts: {}", e),
+        }
+    }
+
+    if let Ok(mut cache) = cache.lock() {
+        *cache = fresh;
+    }
+}
+
+/// Mark the completion cache stale whenever the server's lists change, and
+/// drop the tool listing used to check calls when its tools do
+async fn watch_list_changes(server: &Server, cache: &SharedCompletionCache) {
+    for method in [
+        "notifications/tools/list_changed",
+        "notifications/resources/list_changed",
+        "notifications/prompts/list_changed",
+    ] {
+        let cache = Arc::clone(cache);
+        let tools =
+            (method == "notifications/tools/list_changed").then(|| Arc::clone(&server.tools));
+        server
+            .session
+            .set_notification_handler(method, move |_params: Option<Value>| {
+                if let Ok(mut cache) = cache.lock() {
+                    cache.stale = true;
+                }
+                if let Some(tools) = &tools {
+                    tools
+                        .lock()
+                        .unwrap_or_else(|poisoned| poisoned.into_inner())
+                        .take();
+                }
+            })
+            .await;
+    }
+}
+
+/// Line editor helper that completes command names, their first argument,
+/// and prompt argument values (via the server's completion endpoint)
+struct ReplHelper {
+    cache: SharedCompletionCache,
+    /// Client of the server that handles `prompt`, used for argument completion
+    client: Option<Arc<Mutex<McpClient>>>,
+    timeout: Duration,
+}
+
+impl ReplHelper {
+    fn candidates(&self, line: &str) -> (usize, Vec<String>) {
+        let Some((command, rest)) = line.split_once(' ') else {
+            let commands = REPL_COMMANDS
+                .iter()
+                .filter(|c| c.starts_with(line))
+                .map(|c| c.to_string())
+                .collect();
+            return (0, commands);
+        };
+        if let Some((prompt, args)) = rest.split_once(' ') {
+            if command == "prompt" {
+                if let Some((argument, partial)) = pending_json_string(args) {
+                    let values = self.complete_prompt_argument(prompt, argument, partial);
+                    return (line.len() - partial.len(), values);
+                }
+            }
+            return (line.len(), Vec::new());
+        }
+
+        let Ok(cache) = self.cache.lock() else {
+            return (line.len(), Vec::new());
+        };
+        let names = match command {
+            "call" | "describe" => &cache.tools,
+            "read" | "subscribe" | "unsubscribe" => &cache.resources,
+            "prompt" => &cache.prompts,
+            _ => return (line.len(), Vec::new()),
+        };
+        let matches = names
+            .iter()
+            .filter(|name| name.starts_with(rest))
+            .cloned()
+            .collect();
+        (command.len() + 1, matches)
+    }
+}
+
+impl ReplHelper {
+    /// Ask the server for values of `argument` on `prompt` starting with `partial`
+    fn complete_prompt_argument(&self, prompt: &str, argument: &str, partial: &str) -> Vec<String> {
+        let Some(client) = &self.client else {
+            return Vec::new();
+        };
+        let reference = CompletionReference::Prompt {
+            name: prompt.to_string(),
+        };
+        let argument = CompletionArgument {
+            name: argument.to_string(),
+            value: partial.to_string(),
+        };
+
+        // The line editor is synchronous, so block this worker while the request runs
+        tokio::task::block_in_place(|| {
+            tokio::runtime::Handle::current().block_on(async {
+                let client = client.lock().await.clone();
+                match tokio::time::timeout(self.timeout, client.complete(reference, argument)).await
+                {
+                    Ok(Ok(result)) => result.completion.values,
+                    Ok(Err(e)) => {
+                        debug!("Completion request failed: {}", e);
+                        Vec::new()
+                    }
+                    Err(_) => Vec::new(),
+                }
+            })
+        })
+    }
+}
+
+/// If `args` ends inside a JSON string value (`{"lang": "py`), return the
+/// argument name and the partial value typed so far
+fn pending_json_string(args: &str) -> Option<(&str, &str)> {
+    let value_start = args.rfind('"')?;
+    let partial = &args[value_start + 1..];
+    let before = args[..value_start].trim_end().strip_suffix(':')?.trim_end();
+    let before = before.strip_suffix('"')?;
+    let name_start = before.rfind('"')?;
+    Some((&before[name_start + 1..], partial))
+}
+
+impl Completer for ReplHelper {
+    type Candidate = Pair;
+
+    fn complete(
+        &self,
+        line: &str,
+        pos: usize,
+        _ctx: &rustyline::Context<'_>,
+    ) -> rustyline::Result<(usize, Vec<Pair>)> {
+        let (start, names) = self.candidates(&line[..pos]);
+        let pairs = names
+            .into_iter()
+            .map(|name| Pair {
+                display: name.clone(),
+                replacement: name,
+            })
+            .collect();
+        Ok((start, pairs))
+    }
+}
+
+impl Hinter for ReplHelper {
+    type Hint = String;
+}
+
+impl Highlighter for ReplHelper {}
+
+impl Validator for ReplHelper {}
+
+impl Helper for ReplHelper {}
+
+/// Location of the interactive history file under the user's config dir
+fn history_path() -> Option<PathBuf> {
+    dirs::config_dir().map(|dir| dir.join("{{project-name}}").join("history.txt"))
+}
+
+fn save_history(editor: &mut Editor<ReplHelper, DefaultHistory>, path: &Path) -> Result<()> {
+    if let Some(parent) = path.parent() {
+        std::fs::create_dir_all(parent)?;
+    }
+    editor.save_history(path)?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_cli_parsing() {
+        let cli = Cli::try_parse_from(&["test", "list-tools"]).unwrap();
+        assert!(!cli.verbose);
+        assert!(!cli.quiet);
+        assert_eq!(cli.server, vec!["./server"]);
+        assert_eq!(cli.transport, Transport::Stdio);
+        assert_eq!(cli.output, OutputFormat::Text);
+        assert_eq!(cli.timeout, 30);
+        assert!(cli.out_dir.is_none());
+        assert!(!cli.strict);
+        assert_eq!(cli.connect_attempts, 3);
+        assert!(Cli::try_parse_from(&["test", "--connect-attempts", "0", "list-tools"]).is_err());
+        assert!(matches!(
+            cli.command,
+            Commands::ListTools {
+                schemas: false,
+                cursor: None,
+                count: false
+            }
+        ));
+    }
+
+    #[test]
+    fn test_log_format() {
+        let cli = Cli::try_parse_from(&["test", "list-tools"]).unwrap();
+        assert_eq!(cli.log_format, LogFormat::Text);
+        let cli = Cli::try_parse_from(&["test", "--log-format", "json", "list-tools"]).unwrap();
+        assert_eq!(cli.log_format, LogFormat::Json);
+    }
+
+    #[test]
+    fn test_logs_go_to_stderr() {
+        // The global subscriber can only be installed once per process, so
+        // this runs itself again in a child and checks the child's streams
+        if let Ok(format) = std::env::var("LOGS_GO_TO_STDERR_CHILD") {
+            let cli =
+                Cli::try_parse_from(&["test", "--log-format", &format, "list-tools"]).unwrap();
+            init_logging(&cli).unwrap();
+            // A target the filter always lets through at info
+            info!(target: "mcp_protocol_sdk", "logged for the test");
+            return;
+        }
+        for format in ["text", "json"] {
+            let output = std::process::Command::new(std::env::current_exe().unwrap())
+                .args([
+                    "tests::test_logs_go_to_stderr",
+                    "--exact",
+                    "--nocapture",
+                    "--test-threads=1",
+                ])
+                .env("LOGS_GO_TO_STDERR_CHILD", format)
+                .output()
+                .unwrap();
+            assert!(output.status.success());
+            assert!(String::from_utf8_lossy(&output.stderr).contains("logged for the test"));
+            assert!(!String::from_utf8_lossy(&output.stdout).contains("logged for the test"));
+        }
+    }
+
+    #[test]
+    fn test_quiet_conflicts_with_verbose() {
+        let cli = Cli::try_parse_from(&["test", "-q", "list-tools"]).unwrap();
+        assert!(cli.quiet);
+        assert!(Cli::try_parse_from(&["test", "-q", "-v", "list-tools"]).is_err());
+    }
+
+    #[test]
+    fn test_completions_parsing() {
+        let cli = Cli::try_parse_from(&["test", "completions", "zsh"]).unwrap();
+        assert!(matches!(
+            cli.command,
+            Commands::Completions {
+                shell: clap_complete::Shell::Zsh
+            }
+        ));
+        assert!(Cli::try_parse_from(&["test", "completions", "tcsh"]).is_err());
+    }
+
+    #[test]
+    fn test_server_args_parsing() {
+        let cli = Cli::try_parse_from(&[
+            "test",
+            "--server-arg",
+            "--config",
+            "--server-arg",
+            "my config.toml",
+            "list-tools",
+        ])
+        .unwrap();
+        assert_eq!(cli.server_args, vec!["--config", "my config.toml"]);
+    }
+
+    #[test]
+    fn test_interactive_keepalive_parsing() {
+        let cli = Cli::try_parse_from(&["test", "interactive", "--keepalive", "15"]).unwrap();
+        assert!(matches!(
+            cli.command,
+            Commands::Interactive {
+                keepalive: Some(15),
+                concurrency: 4,
+                idle_timeout: None,
+            }
+        ));
+        assert!(Cli::try_parse_from(&["test", "interactive", "--keepalive", "0"]).is_err());
+
+        let cli = Cli::try_parse_from(&["test", "interactive", "--idle-timeout", "600"]).unwrap();
+        assert!(matches!(
+            cli.command,
+            Commands::Interactive {
+                idle_timeout: Some(600),
+                ..
+            }
+        ));
+        assert!(Cli::try_parse_from(&["test", "interactive", "--idle-timeout", "0"]).is_err());
+    }
+
+    #[tokio::test]
+    async fn test_read_line_idle_timeout() {
+        let started = Instant::now();
+        let read = read_line((), Some(Duration::from_millis(100)), |_| {
+            std::thread::sleep(Duration::from_secs(2));
+            Ok("too late".to_string())
+        })
+        .await
+        .unwrap();
+        assert!(read.is_none());
+        assert!(started.elapsed() < Duration::from_secs(1));
+
+        let read = read_line(
+            (),
+            Some(Duration::from_secs(5)),
+            |_| Ok("tools".to_string()),
+        )
+        .await
+        .unwrap();
+        assert_eq!(read.unwrap().1.unwrap(), "tools");
+        let read = read_line((), None, |_| Ok("tools".to_string()))
+            .await
+            .unwrap();
+        assert_eq!(read.unwrap().1.unwrap(), "tools");
+
+        // Not a terminal under the test runner, so there is nothing to restore
+        if !std::io::stdin().is_terminal() {
+            assert!(TerminalMode::save().is_none());
+        }
+    }
+
+    #[test]
+    fn test_multiple_servers() {
+        let cli =
+            Cli::try_parse_from(&["test", "-s", "./files", "-s", "./db-server", "list-tools"])
+                .unwrap();
+        assert_eq!(cli.server, vec!["./files", "./db-server"]);
+        assert_eq!(server_name("./bin/db-server"), "db-server");
+        assert_eq!(server_name("http://localhost:3000/mcp"), "localhost_3000");
+    }
+
+    #[tokio::test]
+    async fn test_connect_tcp_errors() {
+        let error = connect_tcp("localhost").await.unwrap_err().to_string();
+        assert!(error.contains("host:port"), "{}", error);
+        let error = connect_tcp("localhost:http").await.unwrap_err().to_string();
+        assert!(error.contains("invalid port"), "{}", error);
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn test_init_timeout() {
+        // `sleep` never answers the initialize request
+        let endpoint = Endpoint {
+            transport: Transport::Stdio,
+            target: "sleep".to_string(),
+            server_args: vec!["10".to_string()],
+            server_env: Vec::new(),
+            working_dir: None,
+            headers: Vec::new(),
+            init_timeout: Some(Duration::from_millis(200)),
+            ready_probe: None,
+            server_stderr: ServerStderr::Inherit,
+        };
+        let session = ClientSession::new(McpClient::with_capabilities(
+            "test".to_string(),
+            "0.1.0".to_string(),
+            Default::default(),
+        ));
+        let started = Instant::now();
+        let error = connect(&session, &endpoint).await.unwrap_err().to_string();
+        assert!(error.contains("failed to initialize in time"), "{}", error);
+        assert!(started.elapsed() < Duration::from_secs(5));
+    }
+
+    #[test]
+    fn test_websocket_url() {
+        let cli = Cli::try_parse_from(&[
+            "test",
+            "--transport",
+            "websocket",
+            "--url",
+            "wss://mcp.example.com/ws",
+            "list-tools",
+        ])
+        .unwrap();
+        assert_eq!(cli.transport, Transport::Websocket);
+        assert!(check_websocket_url("wss://mcp.example.com/ws").is_ok());
+        assert!(check_websocket_url("http://localhost:3000/mcp").is_err());
+        assert!(check_websocket_url("").is_err());
+    }
+
+    #[test]
+    fn test_color_choice() {
+        let cli = Cli::try_parse_from(&["test", "list-tools"]).unwrap();
+        assert_eq!(cli.color_choice(), ColorChoice::Auto);
+        let unset = |_: &str| None;
+        assert!(ColorChoice::Auto.resolve(true, unset));
+        assert!(!ColorChoice::Auto.resolve(false, unset));
+        assert!(ColorChoice::Always.resolve(false, unset));
+        assert!(!ColorChoice::Never.resolve(true, unset));
+
+        let cli = Cli::try_parse_from(&["test", "--no-color", "list-tools"]).unwrap();
+        assert_eq!(cli.color_choice(), ColorChoice::Never);
+        assert!(
+            Cli::try_parse_from(&["test", "--no-color", "--color", "always", "list-tools"])
+                .is_err()
+        );
+    }
+
+    #[test]
+    fn test_color_env_vars() {
+        let env = |vars: &'static [(&'static str, &'static str)]| {
+            move |name: &str| {
+                vars.iter()
+                    .find(|(k, _)| *k == name)
+                    .map(|(_, v)| v.to_string())
+            }
+        };
+        assert!(!ColorChoice::Auto.resolve(true, env(&[("NO_COLOR", "1")])));
+        assert!(ColorChoice::Auto.resolve(true, env(&[("NO_COLOR", "")])));
+        assert!(ColorChoice::Auto.resolve(false, env(&[("CLICOLOR_FORCE", "1")])));
+        assert!(!ColorChoice::Auto.resolve(false, env(&[("CLICOLOR_FORCE", "0")])));
+        assert!(!ColorChoice::Auto.resolve(true, env(&[("CLICOLOR", "0")])));
+        assert!(
+            !ColorChoice::Auto.resolve(true, env(&[("NO_COLOR", "1"), ("CLICOLOR_FORCE", "1")]))
+        );
+        // An explicit --color always beats the environment
+        assert!(ColorChoice::Always.resolve(false, env(&[("NO_COLOR", "1")])));
+    }
+
+    #[test]
+    fn test_markdown_rendering_opt_out() {
+        let cli = Cli::try_parse_from(&[
+            "test",
+            "--format-content",
+            "markdown",
+            "--no-render",
+            "list-tools",
+        ])
+        .unwrap();
+        assert_eq!(cli.format_content, ContentFormat::Markdown);
+        assert!(!Options::from_cli(&cli).markdown);
+    }
+
+    #[test]
+    fn test_output_file_is_never_colored() {
+        let cli = Cli::try_parse_from(&["test", "--color", "always", "list-tools"]).unwrap();
+        assert!(Options::from_cli(&cli).color);
+        let cli = Cli::try_parse_from(&[
+            "test",
+            "--color",
+            "always",
+            "--output-file",
+            "out.json",
+            "list-tools",
+        ])
+        .unwrap();
+        assert!(!Options::from_cli(&cli).color);
+    }
+
+    #[test]
+    fn test_rendering_follows_color() {
+        let cli = Cli::try_parse_from(&[
+            "test",
+            "--color",
+            "always",
+            "--format-content",
+            "markdown",
+            "list-tools",
+        ])
+        .unwrap();
+        let opts = Options::from_cli(&cli);
+        assert!(opts.color && opts.markdown && opts.highlight);
+
+        for flags in [
+            &["--no-color"][..],
+            &["--color", "always", "--output-file", "out.txt"][..],
+        ] {
+            let cli = Cli::try_parse_from(["test"].iter().chain(flags).chain(&[
+                "--format-content",
+                "markdown",
+                "list-tools",
+            ]))
+            .unwrap();
+            let opts = Options::from_cli(&cli);
+            assert!(
+                !opts.color && !opts.markdown && !opts.highlight,
+                "{:?}",
+                flags
+            );
+        }
+    }
+
+    #[test]
+    fn test_config_fills_unset_flags() {
+        let config: Config = toml::from_str(
+            r#"
+            server = "./configured"
+            timeout = 5
+            output = "json"
+            "#,
+        )
+        .unwrap();
+        let matches = Cli::command()
+            .try_get_matches_from(["test", "--timeout", "60", "list-tools"])
+            .unwrap();
+        let mut cli = Cli::from_arg_matches(&matches).unwrap();
+        cli.apply_config(config, &matches);
+
+        assert_eq!(cli.server, vec!["./configured"]);
+        assert_eq!(cli.output, OutputFormat::Json);
+        assert_eq!(cli.timeout, 60);
+        assert_eq!(cli.transport, Transport::Stdio);
+    }
+
+    #[test]
+    fn test_server_env_parsing() {
+        let cli = Cli::try_parse_from(&[
+            "test",
+            "--env",
+            "API_KEY=abc=123",
+            "--env",
+            "EMPTY=",
+            "list-tools",
+        ])
+        .unwrap();
+        assert_eq!(
+            cli.server_env,
+            vec![
+                ("API_KEY".to_string(), "abc=123".to_string()),
+                ("EMPTY".to_string(), String::new())
+            ]
+        );
+
+        assert!(Cli::try_parse_from(&["test", "--env", "NO_EQUALS", "list-tools"]).is_err());
+        assert!(Cli::try_parse_from(&["test", "--env", "=value", "list-tools"]).is_err());
+    }
+
+    #[test]
+    fn test_server_command_working_dir() {
+        let endpoint = Endpoint {
+            transport: Transport::Stdio,
+            target: "./server".to_string(),
+            server_args: Vec::new(),
+            server_env: Vec::new(),
+            working_dir: Some(PathBuf::from("/srv/data")),
+            headers: Vec::new(),
+            init_timeout: None,
+            ready_probe: None,
+            server_stderr: ServerStderr::Null,
+        };
+        let command = server_command(&endpoint).unwrap();
+        let command = command.as_std();
+        assert_eq!(command.get_current_dir(), Some(Path::new("/srv/data")));
+        // The server binary is still found relative to the client
+        assert!(Path::new(command.get_program()).is_absolute());
+    }
+
+    #[test]
+    fn test_server_stderr() {
+        let cli = Cli::try_parse_from(&["test", "list-tools"]).unwrap();
+        assert_eq!(cli.server_stderr, ServerStderr::Inherit);
+        let cli = Cli::try_parse_from(&["test", "--server-stderr", "null", "list-tools"]).unwrap();
+        assert_eq!(cli.server_stderr, ServerStderr::Null);
+        let cli =
+            Cli::try_parse_from(&["test", "--server-stderr", "server.log", "list-tools"]).unwrap();
+        assert_eq!(
+            cli.server_stderr,
+            ServerStderr::File(PathBuf::from("server.log"))
+        );
+    }
+
+    #[test]
+    fn test_request_headers() {
+        let cli = Cli::try_parse_from(&[
+            "test",
+            "--header",
+            "X-Api-Key: abc:123",
+            "--bearer",
+            "secret",
+            "list-tools",
+        ])
+        .unwrap();
+        let headers = request_headers(&cli);
+        assert_eq!(headers[0].name, "X-Api-Key");
+        assert_eq!(headers[0].value, "abc:123");
+        assert_eq!(headers[1].value, "Bearer secret");
+        // Credentials stay out of debug logs
+        assert_eq!(
+            format!("{:?}", headers),
+            "[X-Api-Key: <redacted>, Authorization: <redacted>]"
+        );
+
+        assert!(Cli::try_parse_from(&["test", "--header", "no-colon", "list-tools"]).is_err());
+    }
+
+    /// `data` as a zlib stream of one stored (uncompressed) deflate block,
+    /// which is all a `Content-Encoding: deflate` body needs to be
+    #[cfg(feature = "http")]
+    fn zlib_stored(data: &[u8]) -> Vec<u8> {
+        let len = data.len() as u16;
+        let mut out = vec![0x78, 0x01, 0x01];
+        out.extend(len.to_le_bytes());
+        out.extend((!len).to_le_bytes());
+        out.extend(data);
+        let (mut a, mut b) = (1u32, 0u32);
+        for byte in data {
+            a = (a + u32::from(*byte)) % 65521;
+            b = (b + a) % 65521;
+        }
+        out.extend(((b << 16) | a).to_be_bytes());
+        out
+    }
+
+    #[cfg(feature = "http")]
+    #[tokio::test]
+    async fn test_http_deflate_response() {
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+        // Answers every JSON-RPC request with a deflate-compressed body; the
+        // handshake only succeeds if the transport decompresses it
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}/mcp", listener.local_addr().unwrap());
+        tokio::spawn(async move {
+            while let Ok((stream, _)) = listener.accept().await {
+                tokio::spawn(async move {
+                    let mut stream = BufReader::new(stream);
+                    loop {
+                        let mut length = 0;
+                        let mut line = String::new();
+                        while stream.read_line(&mut line).await.unwrap_or(0) > 0 && line != "\r\n" {
+                            if let Some((name, value)) = line.split_once(':') {
+                                if name.eq_ignore_ascii_case("content-length") {
+                                    length = value.trim().parse().unwrap_or(0);
+                                }
+                            }
+                            line.clear();
+                        }
+                        if line.is_empty() {
+                            return;
+                        }
+                        let mut body = vec![0; length];
+                        if stream.read_exact(&mut body).await.is_err() {
+                            return;
+                        }
+                        let request: Value = serde_json::from_slice(&body).unwrap_or_default();
+                        let response = match request.get("id") {
+                            Some(id) => {
+                                let result = json!({
+                                    "protocolVersion": "2024-11-05",
+                                    "capabilities": {},
+                                    "serverInfo": { "name": "deflated", "version": "1.0" },
+                                });
+                                let body = zlib_stored(
+                                    json!({ "jsonrpc": "2.0", "id": id, "result": result })
+                                        .to_string()
+                                        .as_bytes(),
+                                );
+                                let mut response = format!(
+                                    "HTTP/1.1 200 OK\r\nContent-Type: application/json\r\nContent-Encoding: deflate\r\nContent-Length: {}\r\n\r\n",
+                                    body.len()
+                                )
+                                .into_bytes();
+                                response.extend(body);
+                                response
+                            }
+                            None => b"HTTP/1.1 202 Accepted\r\nContent-Length: 0\r\n\r\n".to_vec(),
+                        };
+                        if stream.get_mut().write_all(&response).await.is_err() {
+                            return;
+                        }
+                    }
+                });
+            }
+        });
+
+        let endpoint = Endpoint {
+            transport: Transport::Http,
+            target: url,
+            server_args: Vec::new(),
+            server_env: Vec::new(),
+            working_dir: None,
+            // A hand-set Accept-Encoding doesn't stop the decompression
+            headers: vec![parse_header("Accept-Encoding: deflate").unwrap()],
+            init_timeout: Some(Duration::from_secs(5)),
+            ready_probe: None,
+            server_stderr: ServerStderr::Inherit,
+        };
+        let session = ClientSession::new(McpClient::with_capabilities(
+            "test".to_string(),
+            "0.1.0".to_string(),
+            Default::default(),
+        ));
+        let (init, _) = connect(&session, &endpoint).await.unwrap();
+        assert_eq!(init.server_info.name, "deflated");
+    }
+
+    #[test]
+    fn test_http_transport_parsing() {
+        let cli = Cli::try_parse_from(&[
+            "test",
+            "--transport",
+            "http",
+            "--url",
+            "http://localhost:3000/mcp",
+            "list-tools",
+        ])
+        .unwrap();
+        assert_eq!(cli.transport, Transport::Http);
+        assert_eq!(cli.url.as_deref(), Some("http://localhost:3000/mcp"));
+    }
+
+    #[test]
+    fn test_client_info() {
+        let connector = Connector::from_cli(&Cli::try_parse_from(&["test", "list-tools"]).unwrap());
+        assert_eq!(connector.client_name, "{{project-name}}");
+        assert_eq!(connector.client_version, env!("CARGO_PKG_VERSION"));
+
+        let cli = Cli::try_parse_from(&[
+            "test",
+            "--client-name",
+            "ci-bot",
+            "--client-version",
+            "2.3.0",
+            "list-tools",
+        ])
+        .unwrap();
+        let connector = Connector::from_cli(&cli);
+        assert_eq!(connector.client_name, "ci-bot");
+        assert_eq!(connector.client_version, "2.3.0");
+    }
+
+    #[test]
+    fn test_json_path() {
+        let path = parse_json_path("content[0].text").unwrap();
+        assert_eq!(
+            path.segments,
+            [
+                PathSegment::Key("content".to_string()),
+                PathSegment::Index(0),
+                PathSegment::Key("text".to_string())
+            ]
+        );
+        assert_eq!(parse_json_path("$.items[1][2]").unwrap().segments.len(), 3);
+        assert!(parse_json_path("$").unwrap().segments.is_empty());
+        assert!(parse_json_path("a..b").is_err());
+        assert!(parse_json_path("items[x]").is_err());
+        assert!(parse_json_path("items[0").is_err());
+
+        let result = json!({ "content": [{ "type": "text", "text": "hi" }], "structuredContent": { "n": 3 } });
+        assert_eq!(path.select(&result), Some(&json!("hi")));
+        assert_eq!(
+            parse_json_path("structuredContent.n")
+                .unwrap()
+                .select(&result),
+            Some(&json!(3))
+        );
+        assert_eq!(
+            parse_json_path("content[5].text").unwrap().select(&result),
+            None
+        );
+        assert_eq!(
+            parse_json_path("content.text").unwrap().select(&result),
+            None
+        );
+    }
+
+    #[test]
+    fn test_unsupported_content() {
+        let notice = unsupported_content(&json!({ "type": "audio", "data": "AAAA" }));
+        assert_eq!(
+            notice,
+            r#"Received unsupported content type: {"data":"AAAA","type":"audio"}"#
+        );
+    }
+
+    #[test]
+    fn test_stdin_json() {
+        let cli = Cli::try_parse_from(&["test", "call-tool", "--stdin-json"]).unwrap();
+        assert!(cli.stdin_json());
+        assert_eq!(Options::from_cli(&cli).output, OutputFormat::Json);
+        assert!(Cli::try_parse_from(&["test", "call-tool", "echo", "--stdin-json"]).is_err());
+        assert!(Cli::try_parse_from(&["test", "call-tool"]).is_err());
+
+        let (name, args) =
+            parse_tool_call(r#"{"name": "echo", "arguments": {"text": "hi"}}"#).unwrap();
+        assert_eq!(name, "echo");
+        assert_eq!(args, r#"{"text":"hi"}"#);
+        assert_eq!(parse_tool_call(r#"{"name": "ping"}"#).unwrap().1, "{}");
+        assert!(parse_tool_call(r#"{"arguments": {}}"#).is_err());
+        assert!(parse_tool_call(r#"{"name": "echo", "arguments": [1]}"#).is_err());
+        assert!(parse_tool_call("echo").is_err());
+    }
+
+    #[test]
+    fn test_client_roots() {
+        assert_eq!(
+            file_uri(Path::new("/home/me/my project")),
+            "file:///home/me/my%20project"
+        );
+        assert_eq!(file_uri(Path::new("C:\\work")), "file:///C:/work");
+
+        let dir = std::env::temp_dir();
+        let roots = client_roots(&[dir.clone()]).unwrap();
+        assert!(roots[0]["uri"].as_str().unwrap().starts_with("file:///"));
+        assert!(client_roots(&[dir.join("no-such-root-dir")]).is_err());
+        assert!(client_roots(&[]).unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_pretty_and_compact_json() {
+        let cli = Cli::try_parse_from(&["test", "--compact", "list-tools"]).unwrap();
+        let mut opts = Options::from_cli(&cli);
+        assert!(!opts.pretty);
+        assert_eq!(
+            json_string(&opts, &json!({ "a": [1, 2] })).unwrap(),
+            r#"{"a":[1,2]}"#
+        );
+
+        let cli = Cli::try_parse_from(&["test", "--pretty", "list-tools"]).unwrap();
+        assert!(Options::from_cli(&cli).pretty);
+        opts.pretty = true;
+        assert_eq!(
+            json_string(&opts, &json!({ "a": 1 })).unwrap(),
+            "{\n  \"a\": 1\n}"
+        );
+
+        assert!(Cli::try_parse_from(&["test", "--pretty", "--compact", "list-tools"]).is_err());
+    }
+
+    #[test]
+    fn test_parse_until() {
+        let until = parse_until("structuredContent.status=done").unwrap();
+        assert_eq!(until.path.source, "structuredContent.status");
+        assert_eq!(until.expected, json!("done"));
+        assert_eq!(
+            parse_until("structuredContent.progress=100")
+                .unwrap()
+                .expected,
+            json!(100)
+        );
+        assert_eq!(
+            parse_until("structuredContent.ready=true")
+                .unwrap()
+                .expected,
+            json!(true)
+        );
+        assert!(parse_until("structuredContent.status").is_err());
+
+        let cli = Cli::try_parse_from(&[
+            "test",
+            "call-tool",
+            "job_status",
+            "--until",
+            "isError=false",
+        ])
+        .unwrap();
+        assert!(matches!(
+            cli.command,
+            Commands::CallTool {
+                until: Some(_),
+                interval: 2,
+                max_attempts: 30,
+                ..
+            }
+        ));
+        assert!(
+            Cli::try_parse_from(&["test", "call-tool", "job_status", "--interval", "5"]).is_err()
+        );
+    }
+
+    #[tokio::test]
+    async fn test_with_spinner_disabled() {
+        let cli = Cli::try_parse_from(&["test", "call-tool", "echo"]).unwrap();
+        let opts = Options::from_cli(&cli);
+        assert_eq!(
+            with_spinner(&opts, "Calling echo...".to_string(), async { 42 }).await,
+            42
+        );
+        assert!(SPINNER.lock().unwrap().is_none());
+    }
+
+    #[test]
+    fn test_sequence_calls() {
+        assert_eq!(
+            parse_call_spec("ping"),
+            ("ping".to_string(), "{}".to_string())
+        );
+        assert_eq!(
+            parse_call_spec(r#"echo:{"text": "a:{b}"}"#),
+            ("echo".to_string(), r#"{"text": "a:{b}"}"#.to_string())
+        );
+        assert_eq!(
+            parse_call_spec(r#"db::query:{"sql": "SELECT 1"}"#),
+            (
+                "db::query".to_string(),
+                r#"{"sql": "SELECT 1"}"#.to_string()
+            )
+        );
+
+        let path = std::env::temp_dir().join(format!("calls-{}.json", std::process::id()));
+        std::fs::write(
+            &path,
+            r#"[{"name": "add", "arguments": {"a": 1}}, {"name": "ping"}]"#,
+        )
+        .unwrap();
+        let calls = sequence_calls(&["first".to_string()], Some(&path)).unwrap();
+        assert_eq!(
+            calls,
+            [
+                ("first".to_string(), "{}".to_string()),
+                ("add".to_string(), r#"{"a":1}"#.to_string()),
+                ("ping".to_string(), "{}".to_string()),
+            ]
+        );
+        std::fs::write(&path, r#"{"name": "ping"}"#).unwrap();
+        assert!(sequence_calls(&[], Some(&path)).is_err());
+        std::fs::remove_file(&path).unwrap();
+
+        assert!(Cli::try_parse_from(&["test", "call-sequence"]).is_err());
+    }
+
+    #[tokio::test]
+    async fn test_wait_for_line() {
+        let stderr: &[u8] = b"loading index\nlistening, READY\nfirst request\n";
+        let mut lines = BufReader::new(stderr).lines();
+        let mut shown = Vec::new();
+        wait_for_line(&mut lines, "READY", &mut shown)
+            .await
+            .unwrap();
+        assert_eq!(shown, b"loading index\nlistening, READY\n");
+        assert_eq!(
+            lines.next_line().await.unwrap().as_deref(),
+            Some("first request")
+        );
+
+        let stderr: &[u8] = b"panicked at startup\n";
+        let error = wait_for_line(
+            &mut BufReader::new(stderr).lines(),
+            "READY",
+            &mut std::io::sink(),
+        )
+        .await
+        .unwrap_err();
+        assert!(
+            error.to_string().contains("exited before printing"),
+            "{}",
+            error
+        );
+    }
+
+    #[test]
+    fn test_uri_file_name() {
+        assert_eq!(
+            uri_file_name("file:///docs/guide.md", None, true),
+            "docs_guide.md"
+        );
+        assert_eq!(
+            uri_file_name("config://app/settings", Some("application/json"), true),
+            "app_settings.json"
+        );
+        assert_eq!(
+            uri_file_name("db://users/42?fields=name", None, true),
+            "users_42.txt"
+        );
+        assert_eq!(
+            uri_file_name("screenshot://latest", Some("image/png"), false),
+            "latest.png"
+        );
+        assert_eq!(uri_file_name("blob://data/", None, false), "data.bin");
+
+        let mut written = BTreeSet::new();
+        let dir = Path::new("snapshot");
+        assert_eq!(unused_path(dir, "a.txt", &mut written), dir.join("a.txt"));
+        assert_eq!(unused_path(dir, "a.txt", &mut written), dir.join("a-2.txt"));
+        assert_eq!(unused_path(dir, "a.txt", &mut written), dir.join("a-3.txt"));
+    }
+
+    #[test]
+    fn test_redact_fields() {
+        let mut exchange = json!({
+            "method": "tools/call",
+            "params": { "name": "login", "arguments": { "user": "me", "apiToken": "abc", "nested": [{ "Password": "x" }] } },
+        });
+        redact_fields(
+            &mut exchange,
+            &["token".to_string(), "password".to_string()],
+        );
+        assert_eq!(exchange["params"]["arguments"]["apiToken"], "[REDACTED]");
+        assert_eq!(
+            exchange["params"]["arguments"]["nested"][0]["Password"],
+            "[REDACTED]"
+        );
+        assert_eq!(exchange["params"]["arguments"]["user"], "me");
+        assert_eq!(exchange["params"]["name"], "login");
+
+        let cli = Cli::try_parse_from(&[
+            "test",
+            "--trace-protocol",
+            "--redact",
+            "token,secret",
+            "list-tools",
+        ])
+        .unwrap();
+        assert_eq!(cli.redact, ["token", "secret"]);
+        assert!(Cli::try_parse_from(&["test", "--redact", "token", "list-tools"]).is_err());
+    }
+
+    #[test]
+    fn test_parse_target() {
+        assert_eq!(
+            parse_target("./server").unwrap(),
+            (Transport::Stdio, "./server".to_string())
+        );
+        assert_eq!(
+            parse_target("stdio:./server").unwrap(),
+            (Transport::Stdio, "./server".to_string())
+        );
+        assert_eq!(
+            parse_target("https://mcp.example.com/mcp").unwrap(),
+            (Transport::Http, "https://mcp.example.com/mcp".to_string())
+        );
+        assert_eq!(
+            parse_target("ws://localhost:3000/ws").unwrap(),
+            (Transport::Websocket, "ws://localhost:3000/ws".to_string())
+        );
+        assert_eq!(
+            parse_target("tcp://localhost:9000").unwrap(),
+            (Transport::Tcp, "localhost:9000".to_string())
+        );
+        assert!(parse_target("tcp://").is_err());
+        assert!(parse_target("stdio:").is_err());
+        assert!(parse_target("ftp://example.com").is_err());
+    }
+
+    #[test]
+    fn test_scheme_selects_transport() {
+        let cli = Cli::try_parse_from(&[
+            "test",
+            "--server",
+            "./server",
+            "--server",
+            "http://localhost:3000/mcp",
+            "--server",
+            "tcp://localhost:9000",
+            "list-tools",
+        ])
+        .unwrap();
+        let transports: Vec<_> = endpoints(&cli)
+            .unwrap()
+            .iter()
+            .map(|e| e.transport)
+            .collect();
+        assert_eq!(
+            transports,
+            [Transport::Stdio, Transport::Http, Transport::Tcp]
+        );
+    }
+
+    #[test]
+    fn test_recorded_exchange() {
+        let request = json!({ "jsonrpc": "2.0", "id": 3, "method": "tools/call", "params": { "name": "echo" } });
+        let response = json!({ "jsonrpc": "2.0", "id": 3, "result": { "content": [] } });
+        assert_eq!(
+            recorded_exchange(&request, &response),
+            json!({ "method": "tools/call", "params": { "name": "echo" }, "result": { "content": [] } })
+        );
+        let request = json!({ "jsonrpc": "2.0", "id": 4, "method": "resources/read", "params": { "uri": "file:///x" } });
+        let response = json!({ "jsonrpc": "2.0", "id": 4, "error": { "code": -32002, "message": "not found" } });
+        assert_eq!(recorded_exchange(&request, &response)["error"], "not found");
+    }
+
+    #[test]
+    fn test_json_differences() {
+        let mut differences = Vec::new();
+        let expected = json!({ "content": [{ "text": "hi" }], "isError": false });
+        json_differences("$", &expected, &expected.clone(), &mut differences);
+        assert!(differences.is_empty());
+
+        let actual = json!({ "content": [{ "text": "bye" }], "extra": 1 });
+        json_differences("$", &expected, &actual, &mut differences);
+        assert_eq!(
+            differences,
+            [
+                "$.content[0].text: expected \"hi\", got \"bye\"",
+                "$.extra: unexpected 1",
+                "$.isError: missing",
+            ]
+        );
+
+        differences.clear();
+        json_differences("$", &json!([1, 2]), &json!([1]), &mut differences);
+        assert_eq!(differences, ["$: expected 2 items, got 1"]);
+    }
+
+    #[test]
+    fn test_output_format_parsing() {
+        let cli = Cli::try_parse_from(&["test", "--output", "json", "list-tools"]).unwrap();
+        assert_eq!(cli.output, OutputFormat::Json);
+
+        let cli = Cli::try_parse_from(&["test", "--output", "yaml", "list-tools"]).unwrap();
+        assert_eq!(cli.output, OutputFormat::Yaml);
+    }
+
+    #[test]
+    fn test_args_file_conflicts_with_args() {
+        let cli = Cli::try_parse_from(&["test", "call-tool", "echo", "--args-file", "args.json"])
+            .unwrap();
+        assert!(matches!(
+            cli.command,
+            Commands::CallTool {
+                args_file: Some(_),
+                ..
+            }
+        ));
+
+        let result = Cli::try_parse_from(&[
+            "test",
+            "call-tool",
+            "echo",
+            "--args",
+            "{}",
+            "--args-file",
+            "args.json",
+        ]);
+        assert!(result.is_err());
+    }
+
+    #[test]
+    fn test_resource_uris() {
+        assert_eq!(
+            parse_uri_list("file:///a.txt\n\n  # snapshots\n  db://tables/users  \n"),
+            ["file:///a.txt", "db://tables/users"]
+        );
+
+        let path = std::env::temp_dir().join(format!("uris-test-{}.txt", std::process::id()));
+        std::fs::write(&path, "file:///b.txt\n").unwrap();
+        let uris = resource_uris(vec!["file:///a.txt".to_string()], Some(&path));
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(uris.unwrap(), ["file:///a.txt", "file:///b.txt"]);
+
+        let cli =
+            Cli::try_parse_from(&["test", "read-resource", "--uris-file", "uris.txt"]).unwrap();
+        assert!(matches!(
+            cli.command,
+            Commands::ReadResource {
+                uris_file: Some(_),
+                ..
+            }
+        ));
+        assert!(Cli::try_parse_from(&["test", "read-resource"]).is_err());
+    }
+
+    #[test]
+    fn test_explain_error_code() {
+        assert!(explain_error_code(-32601)
+            .unwrap()
+            .starts_with("method not found"));
+        assert!(explain_error_code(-32602).unwrap().contains("input schema"));
+        assert!(explain_error_code(-32050).is_none());
+
+        let cli = Cli::try_parse_from(&["test", "--pretty-errors", "list-tools"]).unwrap();
+        let opts = Options::from_cli(&cli);
+        assert!(opts.pretty_errors);
+        // Only JSON-RPC errors carry a code to explain
+        assert!(opts
+            .error_hint(&anyhow::anyhow!("connection refused"))
+            .is_none());
+    }
+
+    #[test]
+    fn test_keep_going() {
+        let cli = Cli::try_parse_from(&["test", "--keep-going", "list-tools"]).unwrap();
+        assert!(Options::from_cli(&cli).keep_going);
+        assert!(
+            Cli::try_parse_from(&["test", "--keep-going", "--fail-fast", "list-tools"]).is_err()
+        );
+
+        let failed = vec![("db".to_string(), "connection refused".to_string())];
+        assert_eq!(
+            server_summary(&["files", "search"], &failed),
+            "Servers: 2 succeeded (files, search), 1 failed (db: connection refused)"
+        );
+        assert_eq!(
+            server_summary(&["files"], &[]),
+            "Servers: 1 succeeded (files)"
+        );
+    }
+
+    #[test]
+    fn test_compare_tools() {
+        let echo =
+            json!({ "name": "echo", "inputSchema": { "type": "object", "required": ["message"] } });
+        let first = vec![echo.clone(), json!({ "name": "legacy", "inputSchema": {} })];
+        let mut second = vec![echo, json!({ "name": "search", "inputSchema": {} })];
+
+        let tools = compare_tools(&first, &second);
+        assert_eq!(tools["onlyInFirst"], json!(["legacy"]));
+        assert_eq!(tools["onlyInSecond"], json!(["search"]));
+        assert_eq!(tools["changed"], json!([]));
+
+        second[0]["inputSchema"]["required"] = json!(["text"]);
+        let tools = compare_tools(&first, &second);
+        assert_eq!(tools["changed"][0]["name"], "echo");
+        assert_eq!(
+            tools["changed"][0]["differences"],
+            json!([r#"$.inputSchema.required[0]: expected "message", got "text""#])
+        );
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn test_post_process() {
+        assert_eq!(
+            post_process("tr a-z A-Z", "hello\n".to_string())
+                .await
+                .unwrap(),
+            "HELLO\n"
+        );
+        let error = post_process("cat >/dev/null; exit 3", "hello".to_string())
+            .await
+            .unwrap_err();
+        assert!(error.to_string().contains("exited with"), "{}", error);
+
+        let result: CallToolResult = serde_json::from_value(json!({
+            "content": [{ "type": "text", "text": "hello world" }]
+        }))
+        .unwrap();
+        let cli = Cli::try_parse_from(&[
+            "test",
+            "--output",
+            "json",
+            "--post-process",
+            "cat",
+            "call-tool",
+            "echo",
+        ])
+        .unwrap();
+        let opts = Options::from_cli(&cli);
+        let input = post_process_input(&opts, &result).unwrap();
+        assert!(input.ends_with("}\n"), "{}", input);
+        assert_eq!(
+            serde_json::from_str::<Value>(&input).unwrap()["content"][0]["text"],
+            "hello world"
+        );
+
+        // The command's output is held to --max-response-bytes like any other
+        let cli = Cli::try_parse_from(&[
+            "test",
+            "--max-response-bytes",
+            "5",
+            "--post-process",
+            "cat",
+            "call-tool",
+            "echo",
+        ])
+        .unwrap();
+        let opts = Options::from_cli(&cli);
+        let printed = JOB_OUTPUT
+            .scope(Default::default(), async {
+                print_call_result(&opts, "echo", result, "").await.unwrap();
+                JOB_OUTPUT.with(|output| output.take())
+            })
+            .await;
+        assert_eq!(printed, "hello... (truncated, 6 more bytes)\n");
+    }
+
+    #[test]
+    fn test_phase_timings() {
+        let timing = ConnectTiming {
+            transport: Duration::from_millis(95),
+            handshake: Duration::from_millis(35),
+        };
+        let request = Duration::from_millis(412);
+        assert_eq!(
+            phase_timings(&[("server", timing)], request, false),
+            "(transport 95ms, initialize 35ms; request 412ms)"
+        );
+        assert_eq!(
+            phase_timings(&[("db", timing), ("files", ConnectTiming::default())], request, false),
+            "(db: transport 95ms, initialize 35ms; files: transport 0ms, initialize 0ms; request 412ms)"
+        );
+        assert_eq!(phase_timings(&[], request, false), "(request 412ms)");
+
+        let report: Value =
+            serde_json::from_str(&phase_timings(&[("server", timing)], request, true)).unwrap();
+        assert_eq!(
+            report,
+            json!({
+                "timing": {
+                    "connections": [{ "server": "server", "transportMs": 95, "initializeMs": 35 }],
+                    "requestMs": 412,
+                }
+            })
+        );
+    }
+
+    #[test]
+    fn test_missing_prompt_args() {
+        let prompt = json!({
+            "name": "review",
+            "arguments": [
+                { "name": "code", "description": "Code to review", "required": true },
+                { "name": "style" },
+            ],
+        });
+        let names = |given: HashMap<String, Value>| -> Vec<String> {
+            missing_prompt_args(&prompt, &given)
+                .into_iter()
+                .map(|argument| argument.name)
+                .collect()
+        };
+
+        assert_eq!(names(HashMap::new()), ["code", "style"]);
+        assert_eq!(
+            names(HashMap::from([("style".to_string(), json!("terse"))])),
+            ["code"]
+        );
+        assert!(names(HashMap::from([("code".to_string(), json!("fn main() {}"))])).is_empty());
+
+        let code = &missing_prompt_args(&prompt, &HashMap::new())[0];
+        assert!(code.required);
+        assert_eq!(code.description.as_deref(), Some("Code to review"));
+        assert!(missing_prompt_args(&json!({ "name": "plain" }), &HashMap::new()).is_empty());
+    }
+
+    #[test]
+    fn test_json_rpc_id_is_hidden() {
+        let cli = Cli::try_parse_from(&["test", "--json-rpc-id", "ci-run-42", "ping"]).unwrap();
+        assert_eq!(cli.json_rpc_id.as_deref(), Some("ci-run-42"));
+        assert!(!Cli::command()
+            .render_help()
+            .to_string()
+            .contains("--json-rpc-id"));
+    }
+
+    #[test]
+    fn test_arg_files() {
+        let cli =
+            Cli::try_parse_from(&["test", "call-tool", "ocr", "--arg-file", "image=scan.png"])
+                .unwrap();
+        let Commands::CallTool { arg_files, .. } = cli.command else {
+            panic!("expected call-tool");
+        };
+        let expected = ArgFile {
+            name: "image".to_string(),
+            path: PathBuf::from("scan.png"),
+        };
+        assert_eq!(arg_files, vec![expected]);
+        assert!(parse_arg_file("scan.png").is_err());
+        assert!(parse_arg_file("=scan.png").is_err());
+
+        let path = std::env::temp_dir().join(format!("arg-file-test-{}.bin", std::process::id()));
+        std::fs::write(&path, [0xff, 0x00, 0x10]).unwrap();
+        let files = [ArgFile {
+            name: "image".to_string(),
+            path: path.clone(),
+        }];
+        let args: Value =
+            serde_json::from_str(&with_arg_files(r#"{"lang": "en"}"#, &files).unwrap()).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(args, json!({ "lang": "en", "image": "/wAQ" }));
+
+        let schema = json!({
+            "properties": {
+                "image": { "type": "string" },
+                "size": { "type": "integer" },
+                "data": { "type": ["string", "null"] },
+                "any": {},
+            }
+        });
+        assert_eq!(
+            non_string_type(&schema, "size").as_deref(),
+            Some("\"integer\"")
+        );
+        assert_eq!(non_string_type(&schema, "image"), None);
+        assert_eq!(non_string_type(&schema, "data"), None);
+        assert_eq!(non_string_type(&schema, "any"), None);
+        assert_eq!(non_string_type(&schema, "missing"), None);
+    }
+
+    #[test]
+    fn test_stdin_read_once() {
+        let files = |paths: &[&str]| -> Vec<ArgFile> {
+            paths
+                .iter()
+                .map(|arg| parse_arg_file(arg).unwrap())
+                .collect()
+        };
+        assert!(check_stdin_readers(Some(Path::new("-")), &files(&["image=scan.png"])).is_ok());
+        assert!(check_stdin_readers(None, &files(&["image=-", "mask=mask.png"])).is_ok());
+
+        let error = check_stdin_readers(Some(Path::new("-")), &files(&["image=-"])).unwrap_err();
+        assert!(
+            error
+                .to_string()
+                .contains("--args-file -, --arg-file image=-"),
+            "{}",
+            error
+        );
+        assert!(check_stdin_readers(None, &files(&["image=-", "mask=-"])).is_err());
+    }
+
+    #[test]
+    fn test_listing_summary() {
+        assert_eq!(
+            listing_summary(
+                12,
+                "tool",
+                "tools",
+                &[(3, "destructive"), (2, "with no description")]
+            ),
+            "12 tools, 3 destructive, 2 with no description"
+        );
+        assert_eq!(
+            listing_summary(1, "prompt", "prompts", &[(0, "with no description")]),
+            "1 prompt"
+        );
+    }
+
+    #[test]
+    fn test_decode_args() {
+        let latin1 = parse_encoding("latin1").unwrap();
+        assert_eq!(latin1.name(), "windows-1252");
+        assert!(parse_encoding("klingon").is_err());
+
+        let bytes = b"{\"city\": \"M\xfcnchen\"}";
+        assert_eq!(
+            decode_args(bytes, latin1).unwrap(),
+            "{\"city\": \"M\u{fc}nchen\"}"
+        );
+        let error = decode_args(bytes, encoding_rs::UTF_8)
+            .unwrap_err()
+            .to_string();
+        assert!(error.contains("byte 11"), "{}", error);
+        assert_eq!(
+            decode_args("\u{feff}{}".as_bytes(), encoding_rs::UTF_8).unwrap(),
+            "{}"
+        );
+    }
+
+    #[test]
+    fn test_binary_file_naming() {
+        assert_eq!(extension_for_mime(Some("image/png")), "png");
+        assert_eq!(extension_for_mime(Some("image/JPEG; q=0.9")), "jpg");
+        assert_eq!(extension_for_mime(None), "bin");
+        assert_eq!(sanitize_file_name("my tool/1"), "my_tool_1");
+        assert_eq!(resource_file_stem("file:///tmp/report.pdf"), "report");
+        assert_eq!(resource_file_stem("db://tables/users/"), "users");
+    }
+
+    #[test]
+    fn test_repl_completion_candidates() {
+        let cache = CompletionCache {
+            tools: vec!["echo".to_string(), "eval".to_string(), "query".to_string()],
+            resources: vec!["file:///notes.txt".to_string()],
+            ..Default::default()
+        };
+        let helper = ReplHelper {
+            cache: Arc::new(std::sync::Mutex::new(cache)),
+            client: None,
+            timeout: Duration::from_secs(1),
+        };
+
+        assert_eq!(
+            helper.candidates("re"),
+            (0, vec!["resources".to_string(), "read".to_string()])
+        );
+        assert_eq!(
+            helper.candidates("call e"),
+            (5, vec!["echo".to_string(), "eval".to_string()])
+        );
+        assert_eq!(
+            helper.candidates("read file"),
+            (5, vec!["file:///notes.txt".to_string()])
+        );
+        assert_eq!(
+            helper.candidates("subscribe f"),
+            (10, vec!["file:///notes.txt".to_string()])
+        );
+        assert_eq!(helper.candidates("call echo {"), (11, Vec::new()));
+    }
+
+    #[test]
+    fn test_pending_json_string() {
+        assert_eq!(pending_json_string(r#"{"lang": "py"#), Some(("lang", "py")));
+        assert_eq!(
+            pending_json_string(r#"{"a": "x", "lang":""#),
+            Some(("lang", ""))
+        );
+        assert_eq!(pending_json_string(r#"{"lang": "python"}"#), None);
+        assert_eq!(pending_json_string("{"), None);
+    }
+
+    #[test]
+    fn test_format_progress() {
+        let params =
+            json!({ "progressToken": 1, "progress": 25, "total": 100, "message": "indexing" });
+        assert_eq!(
+            format_progress(&params).as_deref(),
+            Some("Progress: 25% (25/100) - indexing")
+        );
+        assert_eq!(
+            format_progress(&json!({ "progress": 3 })).as_deref(),
+            Some("Progress: 3")
+        );
+        assert_eq!(format_progress(&json!({})), None);
+    }
+
+    #[test]
+    fn test_format_duration() {
+        assert_eq!(format_duration(Duration::from_millis(412)), "412ms");
+        assert_eq!(format_duration(Duration::from_millis(1500)), "1.50s");
+    }
+
+    #[test]
+    fn test_format_sampling_request() {
+        let params = json!({
+            "systemPrompt": "Be brief",
+            "messages": [
+                { "role": "user", "content": { "type": "text", "text": "Summarize this" } },
+                { "role": "user", "content": { "type": "image", "data": "", "mimeType": "image/png" } }
+            ],
+            "maxTokens": 100
+        });
+        assert_eq!(
+            format_sampling_request(&params),
+            "The server is asking for a model response:\n  [system] Be brief\n  [user] Summarize this\n  [user] <image content>"
+        );
+        assert_eq!(sampling_text_result("ok", "human")["content"]["text"], "ok");
+    }
+
+    #[test]
+    fn test_format_server_log() {
+        let params = json!({ "level": "warning", "logger": "db", "data": "slow query" });
+        assert_eq!(
+            format_server_log("db-server", &params),
+            "[db-server warning] db: slow query"
+        );
+
+        let params = json!({ "level": "error", "data": { "code": 7 } });
+        assert_eq!(
+            format_server_log("files", &params),
+            r#"[files error] {"code":7}"#
+        );
+    }
+
+    #[test]
+    fn test_strict_warning_levels() {
+        for level in ["warning", "error", "critical", "alert", "emergency"] {
+            assert!(is_warning_level(level), "{}", level);
+        }
+        for level in ["debug", "info", "notice"] {
+            assert!(!is_warning_level(level), "{}", level);
+        }
+    }
+
+    #[test]
+    fn test_listing_cache_round_trip() {
+        let dir = std::env::temp_dir().join(format!(
+            "{{project-name}}-cache-test-{}",
+            std::process::id()
+        ));
+        let endpoint = Endpoint {
+            transport: Transport::Stdio,
+            target: "./db-server".to_string(),
+            server_args: vec!["--db".to_string(), "test.sqlite".to_string()],
+            server_env: Vec::new(),
+            working_dir: None,
+            headers: Vec::new(),
+            init_timeout: None,
+            ready_probe: None,
+            server_stderr: ServerStderr::Inherit,
+        };
+
+        assert!(read_cache::<Value>(&dir, &endpoint, ListingKind::Tools).is_err());
+        write_cache(
+            &dir,
+            &endpoint,
+            ListingKind::Tools,
+            &json!({ "tools": [] }),
+            unix_now(),
+        )
+        .unwrap();
+        write_cache(
+            &dir,
+            &endpoint,
+            ListingKind::Prompts,
+            &json!({ "prompts": [] }),
+            0,
+        )
+        .unwrap();
+
+        let tools: Value = read_cache(&dir, &endpoint, ListingKind::Tools).unwrap();
+        assert_eq!(tools, json!({ "tools": [] }));
+        // Stale entries are still returned, just with a warning
+        let prompts: Value = read_cache(&dir, &endpoint, ListingKind::Prompts).unwrap();
+        assert_eq!(prompts, json!({ "prompts": [] }));
+        assert!(read_cache::<Value>(&dir, &endpoint, ListingKind::Resources).is_err());
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_expand_env() {
+        let lookup = |name: &str| match name {
+            "USER_NAME" => Some("ada".to_string()),
+            "QUOTED" => Some(r#"say "hi""#.to_string()),
+            _ => None,
+        };
+        assert_eq!(
+            expand_env(r#"{"user": "${USER_NAME}", "limit": ${LIMIT:-10}}"#, lookup).unwrap(),
+            r#"{"user": "ada", "limit": 10}"#
+        );
+        assert_eq!(
+            expand_env(r#"{"m": "${QUOTED}"}"#, lookup).unwrap(),
+            r#"{"m": "say \"hi\""}"#
+        );
+        assert!(expand_env(r#"{"m": "${MISSING}"}"#, lookup).is_err());
+        assert!(expand_env(r#"{"m": "${USER_NAME"}"#, lookup).is_err());
+    }
+
+    #[test]
+    fn test_line_range() {
+        let log = "one\ntwo\nthree\nfour\n";
+        assert_eq!(LineRange::Head(2).select(log), "one\ntwo");
+        assert_eq!(LineRange::Tail(2).select(log), "three\nfour");
+        assert_eq!(LineRange::Tail(10).select(log), "one\ntwo\nthree\nfour");
+        assert_eq!(LineRange::Head(0).select(log), "");
+    }
+
+    #[test]
+    fn test_latency_summary() {
+        let latencies = [
+            Duration::from_millis(12),
+            Duration::from_millis(41),
+            Duration::from_millis(7),
+        ];
+        assert_eq!(
+            latency_summary(&latencies, 1),
+            "3 runs (1 failed): min 7ms, mean 20ms, max 41ms"
+        );
+        assert_eq!(latency_summary(&[], 0), "0 runs");
+    }
+
+    #[test]
+    fn test_stream_chunk_text() {
+        assert_eq!(
+            stream_chunk_text(&json!({ "text": "partial " })),
+            "partial "
+        );
+        assert_eq!(
+            stream_chunk_text(&json!({
+                "content": [
+                    { "type": "text", "text": "a" },
+                    { "type": "image", "data": "", "mimeType": "image/png" },
+                    { "type": "text", "text": "b" }
+                ]
+            })),
+            "ab"
+        );
+        assert_eq!(stream_chunk_text(&json!({ "progress": 1 })), "");
+    }
+
+    #[tokio::test]
+    async fn test_streamed_text_is_clipped() {
+        let mut streamed = Streamed::new(Some(8));
+        assert_eq!(streamed.push("hello "), "hello ");
+        // Cut at a character boundary, and nothing is printed after the cut
+        assert_eq!(streamed.push("wörld"), "w");
+        assert_eq!(streamed.push("!"), "");
+        assert_eq!(streamed.text, "hello wörld!");
+        let finished = JOB_OUTPUT
+            .scope(Default::default(), async {
+                streamed.finish();
+                JOB_OUTPUT.with(|output| output.take())
+            })
+            .await;
+        assert_eq!(finished, "... (truncated, 6 more bytes)\n");
+
+        let mut streamed = Streamed::new(None);
+        assert_eq!(streamed.push("hello "), "hello ");
+        assert_eq!(streamed.push("world"), "world");
+    }
+
+    #[test]
+    fn test_truncate_text() {
+        assert_eq!(truncate_text("short", 10), "short");
+        assert_eq!(
+            truncate_text("hello world", 5),
+            "hello... (truncated, 6 more bytes)"
+        );
+        // Never split a multi-byte character
+        assert_eq!(truncate_text("héllo", 2), "h... (truncated, 5 more bytes)");
+    }
+
+    #[test]
+    fn test_piped_argument() {
+        let single_required = json!({
+            "type": "object",
+            "properties": { "text": { "type": "string" }, "lang": { "type": "string" } },
+            "required": ["text"]
+        });
+        assert_eq!(piped_argument(&single_required), Some("text".to_string()));
+
+        let single_optional = json!({ "type": "object", "properties": { "query": {} } });
+        assert_eq!(piped_argument(&single_optional), Some("query".to_string()));
+
+        let ambiguous = json!({ "type": "object", "properties": { "a": {}, "b": {} } });
+        assert_eq!(piped_argument(&ambiguous), None);
+
+        assert_eq!(json_escape("say \"hi\"\n"), r#"say \"hi\"\n"#);
+    }
+
+    #[test]
+    fn test_named_params() {
+        assert_eq!(
+            named_params("echo", HashMap::new()),
+            json!({ "name": "echo" })
+        );
+        let args = parse_args(r#"{"message": "hi"}"#).unwrap();
+        assert_eq!(
+            named_params("echo", args),
+            json!({ "name": "echo", "arguments": { "message": "hi" } })
+        );
+        assert!(parse_args("not json").is_err());
+    }
+
+    #[test]
+    fn test_syntax_token_for_mime() {
+        assert_eq!(syntax_token_for_mime("application/json"), Some("json"));
+        assert_eq!(
+            syntax_token_for_mime("text/x-rust; charset=utf-8"),
+            Some("rs")
+        );
+        assert_eq!(syntax_token_for_mime("text/x-go"), Some("go"));
+        assert_eq!(syntax_token_for_mime("text/plain"), None);
+        assert!(highlight("plain words", "text/plain").is_none());
+    }
+
+    #[test]
+    fn test_error_envelope() {
+        let error =
+            anyhow::Error::from(TimedOut(Duration::from_secs(30))).context("Error calling tool");
+        assert_eq!(
+            error_envelope(&error),
+            json!({
+                "error": {
+                    "kind": "timeout",
+                    "code": null,
+                    "message": "Error calling tool: request timed out after 30 seconds"
+                }
+            })
+        );
+        assert_eq!(
+            error_envelope(&anyhow!("bad args"))["error"]["kind"],
+            "client"
+        );
+    }
+
+    #[test]
+    fn test_tool_query() {
+        let query = ToolQuery::new("FILE", false).unwrap();
+        assert!(query.matches("read_file", None));
+        assert!(query.matches("cat", Some("Print a file's contents")));
+        assert!(!query.matches("echo", Some("Echo the input")));
+
+        let pattern = ToolQuery::new("^(get|list)_", true).unwrap();
+        assert!(pattern.matches("List_users", None));
+        assert!(!pattern.matches("user_list", Some("Lists users")));
+
+        assert!(ToolQuery::new("(", true).is_err());
+    }
+
+    #[test]
+    fn test_repl_args() {
+        assert_eq!(repl_args("").unwrap(), "");
+        assert_eq!(repl_args(r#"{"a": 1}"#).unwrap(), r#"{"a": 1}"#);
+
+        let args: Value = serde_json::from_str(
+            &repl_args(r#"path=/tmp/x.txt limit=42 recursive=true title="two words" tags=["a"]"#)
+                .unwrap(),
+        )
+        .unwrap();
+        assert_eq!(
+            args,
+            json!({
+                "path": "/tmp/x.txt",
+                "limit": 42,
+                "recursive": true,
+                "title": "two words",
+                "tags": ["a"]
+            })
+        );
+
+        assert!(repl_args("no-equals").is_err());
+        assert!(repl_args(r#"title="open"#).is_err());
+    }
+
+    #[test]
+    fn test_tool_hints() {
+        assert_eq!(
+            ToolHints::from_annotations(&Value::Null),
+            ToolHints::default()
+        );
+
+        let hints = ToolHints::from_annotations(
+            &json!({ "destructiveHint": true, "idempotentHint": true }),
+        );
+        assert!(hints.destructive);
+        assert_eq!(hints.labels(), vec!["destructive", "idempotent"]);
+
+        let hints =
+            ToolHints::from_annotations(&json!({ "readOnlyHint": true, "destructiveHint": true }));
+        assert!(hints.read_only);
+        assert!(!hints.destructive);
+    }
+
+    #[test]
+    fn test_listing_supported_by() {
+        let init: InitializeResult = serde_json::from_value(json!({
+            "protocolVersion": "2025-06-18",
+            "capabilities": { "tools": {} },
+            "serverInfo": { "name": "minimal", "version": "0.1.0" },
+        }))
+        .unwrap();
+        assert!(ListingKind::Tools.supported_by(&init));
+        assert!(!ListingKind::Resources.supported_by(&init));
+        assert!(!ListingKind::ResourceTemplates.supported_by(&init));
+        assert!(!ListingKind::Prompts.supported_by(&init));
+    }
+
+    #[test]
+    fn test_tool_surface() {
+        let tools = vec![
+            json!({ "name": "search", "description": "Find things", "inputSchema": { "type": "object" } }),
+            json!({
+                "name": "delete",
+                "inputSchema": { "type": "object" },
+                "annotations": { "destructiveHint": true },
+            }),
+        ];
+
+        let surface = tool_surface("files", "1.2.0", tools.clone(), false);
+        assert_eq!(
+            surface["server"],
+            json!({ "name": "files", "version": "1.2.0" })
+        );
+        assert_eq!(surface["tools"][0]["name"], "delete");
+        assert_eq!(surface["tools"][0]["annotations"]["destructiveHint"], true);
+        assert_eq!(surface["tools"][1]["description"], "Find things");
+        assert_eq!(surface["tools"][1]["annotations"], Value::Null);
+
+        let openapi = tool_surface("files", "1.2.0", tools, true);
+        assert_eq!(openapi["info"]["title"], "files");
+        let search = &openapi["paths"]["/tools/search"]["post"];
+        assert_eq!(search["operationId"], "search");
+        assert_eq!(
+            search["requestBody"]["content"]["application/json"]["schema"],
+            json!({ "type": "object" })
+        );
+        assert!(search.get("x-mcp-annotations").is_none());
+        assert!(search.get("summary").is_none());
+        assert_eq!(
+            openapi["paths"]["/tools/delete"]["post"]["x-mcp-annotations"]["destructiveHint"],
+            true
+        );
+    }
+
+    #[test]
+    fn test_describe_capability() {
+        assert_eq!(describe_capability(None), "not supported");
+        assert_eq!(describe_capability(Some(&json!({}))), "supported");
+        assert_eq!(
+            describe_capability(Some(&json!({ "listChanged": true, "subscribe": false }))),
+            "supported (listChanged)"
+        );
+    }
+
+    #[test]
+    fn test_args_parsing() {
+        let args_json = r#"{"key": "value", "number": 42}"#;
+        let args: HashMap<String, Value> = serde_json::from_str(args_json).unwrap();
         assert_eq!(args.len(), 2);
         assert_eq!(args.get("key").unwrap().as_str().unwrap(), "value");
         assert_eq!(args.get("number").unwrap().as_i64().unwrap(), 42);
     }
+
+    /// A tool result holding just `text`
+    fn text_result(text: &str) -> Value {
+        json!({ "content": [{ "type": "text", "text": text }] })
+    }
+
+    /// The `initialize` result of a fake server called `name`
+    fn fake_init(name: &str, capabilities: Value) -> Value {
+        json!({
+            "protocolVersion": "2024-11-05",
+            "capabilities": capabilities,
+            "serverInfo": { "name": name, "version": "1.0" },
+        })
+    }
+
+    /// Start an MCP server on a local TCP port, returning its address.
+    /// `respond` gives the result for each request and how long to wait
+    /// before sending it. Requests are answered concurrently, so overlapping
+    /// calls finish together rather than one after another.
+    async fn fake_server<F>(respond: F) -> String
+    where
+        F: Fn(&str, &Value) -> (Duration, Value) + Send + Sync + 'static,
+    {
+        fake_server_logged(respond).await.0
+    }
+
+    /// Like `fake_server`, also returning every message the server receives
+    async fn fake_server_logged<F>(respond: F) -> (String, Arc<std::sync::Mutex<Vec<Value>>>)
+    where
+        F: Fn(&str, &Value) -> (Duration, Value) + Send + Sync + 'static,
+    {
+        use tokio::io::AsyncWriteExt;
+
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap().to_string();
+        let received = Arc::new(std::sync::Mutex::new(Vec::new()));
+        let log = Arc::clone(&received);
+        let respond = Arc::new(respond);
+        tokio::spawn(async move {
+            while let Ok((stream, _)) = listener.accept().await {
+                let (reader, writer) = stream.into_split();
+                let writer = Arc::new(Mutex::new(writer));
+                let respond = Arc::clone(&respond);
+                let log = Arc::clone(&log);
+                tokio::spawn(async move {
+                    let mut lines = BufReader::new(reader).lines();
+                    while let Ok(Some(line)) = lines.next_line().await {
+                        let Ok(message) = serde_json::from_str::<Value>(&line) else {
+                            continue;
+                        };
+                        log.lock().unwrap().push(message.clone());
+                        // Notifications get no answer
+                        let Some(id) = message.get("id").cloned() else {
+                            continue;
+                        };
+                        let method = message["method"].as_str().unwrap_or_default();
+                        let (delay, result) = respond(method, &message["params"]);
+                        let writer = Arc::clone(&writer);
+                        tokio::spawn(async move {
+                            tokio::time::sleep(delay).await;
+                            let response = json!({ "jsonrpc": "2.0", "id": id, "result": result });
+                            let _ = writer
+                                .lock()
+                                .await
+                                .write_all(format!("{}\n", response).as_bytes())
+                                .await;
+                        });
+                    }
+                });
+            }
+        });
+        (addr, received)
+    }
+
+    fn tcp_endpoint(addr: &str) -> Endpoint {
+        Endpoint {
+            transport: Transport::Tcp,
+            target: addr.to_string(),
+            server_args: Vec::new(),
+            server_env: Vec::new(),
+            working_dir: None,
+            headers: Vec::new(),
+            init_timeout: Some(Duration::from_secs(5)),
+            ready_probe: None,
+            server_stderr: ServerStderr::Inherit,
+        }
+    }
+
+    /// Connect to a fake server the way startup does, calling it `name`
+    async fn fake_connect(addr: &str, name: &str) -> Server {
+        let cli = Cli::try_parse_from(&["test", "list-tools"]).unwrap();
+        Connector::from_cli(&cli)
+            .connect(tcp_endpoint(addr), name.to_string())
+            .await
+            .unwrap()
+    }
+
+    /// Held by tests that make foreground calls, which register in `IN_FLIGHT`
+    static IN_FLIGHT_TESTS: Mutex<()> = Mutex::const_new(());
+
+    #[tokio::test]
+    async fn test_background_job_does_not_block_foreground() {
+        let addr = fake_server(|method, params| match (method, params["name"].as_str()) {
+            ("initialize", _) => (Duration::ZERO, fake_init("fake", json!({ "tools": {} }))),
+            ("tools/call", Some("slow")) => (Duration::from_secs(3), text_result("slow")),
+            ("tools/call", _) => (Duration::ZERO, text_result("fast")),
+            _ => (Duration::ZERO, json!({})),
+        })
+        .await;
+        let servers = Arc::new(vec![fake_connect(&addr, "fake").await]);
+        let cli =
+            Cli::try_parse_from(&["test", "--no-validate", "--assume-yes", "interactive"]).unwrap();
+        let opts = Options::from_cli(&cli);
+        let notify: Arc<dyn Fn(String) + Send + Sync> = Arc::new(|_| {});
+
+        let _in_flight = IN_FLIGHT_TESTS.lock().await;
+        let mut jobs = Jobs::new(1);
+        jobs.spawn("call slow", &servers, &opts, &notify);
+        // Give the job time to send its request
+        tokio::time::sleep(Duration::from_millis(200)).await;
+        let started = Instant::now();
+        let text = execute_line(&servers, &opts, "call fast").await.unwrap();
+        assert_eq!(text.as_deref(), Some("fast"));
+        assert!(
+            started.elapsed() < Duration::from_secs(2),
+            "{:?}",
+            started.elapsed()
+        );
+        jobs.abort_all().await;
+    }
+
+    #[tokio::test]
+    async fn test_call_many_overlaps_calls() {
+        let addr = fake_server(|method, _| match method {
+            "initialize" => (Duration::ZERO, fake_init("fake", json!({ "tools": {} }))),
+            "tools/call" => (Duration::from_secs(1), text_result("done")),
+            _ => (Duration::ZERO, json!({})),
+        })
+        .await;
+        let servers = vec![fake_connect(&addr, "fake").await];
+        let cli = Cli::try_parse_from(&["test", "call-many", "work"]).unwrap();
+        let opts = Options::from_cli(&cli);
+
+        // Four one-second calls, all allowed to run at once
+        let input: &[u8] = b"{\"n\": 1}\n{\"n\": 2}\n{\"n\": 3}\n{\"n\": 4}\n";
+        let started = Instant::now();
+        call_many(&servers, &opts, "work", 4, input).await.unwrap();
+        assert!(
+            started.elapsed() < Duration::from_secs(3),
+            "{:?}",
+            started.elapsed()
+        );
+    }
+
+    #[tokio::test]
+    async fn test_subscribe_resolves_server() {
+        let mut servers = Vec::new();
+        for name in ["docs", "logs"] {
+            let addr = fake_server(move |method, _| match method {
+                "initialize" => (Duration::ZERO, fake_init(name, json!({ "resources": { "subscribe": true } }))),
+                "resources/list" => (
+                    Duration::ZERO,
+                    json!({ "resources": [{ "uri": format!("file:///{}.txt", name), "name": name }] }),
+                ),
+                _ => (Duration::ZERO, json!({})),
+            })
+            .await;
+            servers.push(fake_connect(&addr, name).await);
+        }
+        let cli = Cli::try_parse_from(&["test", "interactive"]).unwrap();
+        let opts = Options::from_cli(&cli);
+
+        let (server, uri) = resolve_resource(&servers, &opts, "logs::file:///other.txt")
+            .await
+            .unwrap();
+        assert_eq!(
+            (server.name.as_str(), uri.as_str()),
+            ("logs", "file:///other.txt")
+        );
+        let (server, _) = resolve_resource(&servers, &opts, "file:///logs.txt")
+            .await
+            .unwrap();
+        assert_eq!(server.name, "logs");
+        assert!(resolve_resource(&servers, &opts, "file:///missing.txt")
+            .await
+            .is_err());
+
+        execute_line(&servers, &opts, "subscribe file:///logs.txt")
+            .await
+            .unwrap();
+        assert!(servers[1]
+            .subscriptions
+            .lock()
+            .unwrap()
+            .contains("file:///logs.txt"));
+        assert!(servers[0].subscriptions.lock().unwrap().is_empty());
+        assert!(
+            execute_line(&servers, &opts, "unsubscribe file:///docs.txt")
+                .await
+                .is_err()
+        );
+        execute_line(&servers, &opts, "unsubscribe file:///logs.txt")
+            .await
+            .unwrap();
+        assert!(servers[1].subscriptions.lock().unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_watch_parsing() {
+        let cli = Cli::try_parse_from(&["test", "watch", "file:///log.txt"]).unwrap();
+        assert!(matches!(
+            cli.command,
+            Commands::Watch { ref uri, interval: 5 } if uri == "file:///log.txt"
+        ));
+        let cli =
+            Cli::try_parse_from(&["test", "watch", "file:///log.txt", "--interval", "1"]).unwrap();
+        assert!(matches!(cli.command, Commands::Watch { interval: 1, .. }));
+        assert!(
+            Cli::try_parse_from(&["test", "watch", "file:///log.txt", "--interval", "0"]).is_err()
+        );
+    }
+
+    #[tokio::test]
+    async fn test_watch_resource_polls_for_changes() {
+        use std::sync::atomic::{AtomicUsize, Ordering};
+
+        // No subscribe capability, so watch polls; the text changes every second read
+        let reads = AtomicUsize::new(0);
+        let addr = fake_server(move |method, params| match method {
+            "initialize" => (
+                Duration::ZERO,
+                fake_init("fake", json!({ "resources": {} })),
+            ),
+            "resources/read" => {
+                let version = reads.fetch_add(1, Ordering::SeqCst) / 2;
+                let contents = json!([{ "uri": params["uri"], "text": format!("v{}", version) }]);
+                (Duration::ZERO, json!({ "contents": contents }))
+            }
+            _ => (Duration::ZERO, json!({})),
+        })
+        .await;
+        let server = fake_connect(&addr, "fake").await;
+        let cli = Cli::try_parse_from(&["test", "watch", "file:///log.txt"]).unwrap();
+        let opts = Options::from_cli(&cli);
+
+        let printed = JOB_OUTPUT
+            .scope(Default::default(), async {
+                let watch =
+                    watch_resource(&server, &opts, "file:///log.txt", Duration::from_millis(50));
+                assert!(tokio::time::timeout(Duration::from_millis(280), watch)
+                    .await
+                    .is_err());
+                JOB_OUTPUT.with(|output| output.take())
+            })
+            .await;
+        // Unchanged polls print nothing, so each version shows up once
+        assert_eq!(printed.matches("v0").count(), 1, "{}", printed);
+        assert_eq!(printed.matches("v1").count(), 1, "{}", printed);
+        assert!(printed.find("v0") < printed.find("v1"));
+    }
+
+    #[tokio::test]
+    async fn test_tool_listing_is_fetched_once() {
+        use std::sync::atomic::{AtomicUsize, Ordering};
+
+        let listings = Arc::new(AtomicUsize::new(0));
+        let counted = Arc::clone(&listings);
+        let addr = fake_server(move |method, _| match method {
+            "initialize" => (Duration::ZERO, fake_init("fake", json!({ "tools": {} }))),
+            "tools/list" => {
+                counted.fetch_add(1, Ordering::SeqCst);
+                let tools = json!([{ "name": "echo", "inputSchema": { "type": "object" } }]);
+                (Duration::ZERO, json!({ "tools": tools }))
+            }
+            "tools/call" => (Duration::ZERO, text_result("done")),
+            _ => (Duration::ZERO, json!({})),
+        })
+        .await;
+        let server = fake_connect(&addr, "fake").await;
+        let cli = Cli::try_parse_from(&["test", "call-tool", "echo"]).unwrap();
+        let opts = Options::from_cli(&cli);
+
+        JOB_OUTPUT
+            .scope(Default::default(), async {
+                for _ in 0..3 {
+                    let text = call_tool(&server, &opts, "echo", "{}").await.unwrap();
+                    assert_eq!(text.as_deref(), Some("done"));
+                }
+            })
+            .await;
+        assert_eq!(listings.load(Ordering::SeqCst), 1);
+    }
+
+    #[tokio::test]
+    async fn test_destructive_call_refused_in_background_job() {
+        use std::sync::atomic::{AtomicUsize, Ordering};
+
+        let calls = Arc::new(AtomicUsize::new(0));
+        let counted = Arc::clone(&calls);
+        let addr = fake_server(move |method, _| match method {
+            "initialize" => (Duration::ZERO, fake_init("fake", json!({ "tools": {} }))),
+            "tools/list" => {
+                let wipe = json!({
+                    "name": "wipe",
+                    "inputSchema": { "type": "object" },
+                    "annotations": { "destructiveHint": true },
+                });
+                (Duration::ZERO, json!({ "tools": [wipe] }))
+            }
+            "tools/call" => {
+                counted.fetch_add(1, Ordering::SeqCst);
+                (Duration::ZERO, text_result("wiped"))
+            }
+            _ => (Duration::ZERO, json!({})),
+        })
+        .await;
+        let server = fake_connect(&addr, "fake").await;
+        let cli = Cli::try_parse_from(&["test", "call-tool", "wipe"]).unwrap();
+        let opts = Options::from_cli(&cli);
+
+        let job = JOB_OUTPUT.scope(Default::default(), call_tool(&server, &opts, "wipe", "{}"));
+        let error = BACKGROUND_JOB.scope((), job).await.unwrap_err();
+        assert!(error.to_string().contains("foreground"), "{}", error);
+        assert_eq!(calls.load(Ordering::SeqCst), 0);
+    }
+
+    #[tokio::test]
+    async fn test_repeat_runs_are_not_background_jobs() {
+        // `--repeat` holds back the output of all but its last run, which
+        // still run in the foreground and may prompt
+        let held_back = JOB_OUTPUT.scope(Default::default(), async { in_background_job() });
+        assert!(!held_back.await);
+        assert!(
+            BACKGROUND_JOB
+                .scope((), async { in_background_job() })
+                .await
+        );
+    }
+
+    #[test]
+    fn test_record_covers_every_request() {
+        // The recording file can only be set once per process, so this runs
+        // itself again in a child that records to the path it is given
+        if let Ok(path) = std::env::var("RECORD_COVERS_EVERY_REQUEST_CHILD") {
+            init_recording(Some(Path::new(&path))).unwrap();
+            tokio::runtime::Runtime::new().unwrap().block_on(async {
+                let addr = fake_server(|method, _| match method {
+                    "initialize" => (Duration::ZERO, fake_init("fake", json!({ "tools": {} }))),
+                    _ => (Duration::ZERO, json!({})),
+                })
+                .await;
+                let server = fake_connect(&addr, "recorded").await;
+                let cli = Cli::try_parse_from(&["test", "ping"]).unwrap();
+                ping(&server.session, &Options::from_cli(&cli))
+                    .await
+                    .unwrap();
+            });
+            return;
+        }
+        let path = std::env::temp_dir().join(format!("record-{}.ndjson", std::process::id()));
+        let output = std::process::Command::new(std::env::current_exe().unwrap())
+            .args([
+                "tests::test_record_covers_every_request",
+                "--exact",
+                "--nocapture",
+                "--test-threads=1",
+            ])
+            .env("RECORD_COVERS_EVERY_REQUEST_CHILD", &path)
+            .output()
+            .unwrap();
+        let recording = std::fs::read_to_string(&path).unwrap_or_default();
+        let _ = std::fs::remove_file(&path);
+        assert!(
+            output.status.success(),
+            "{}",
+            String::from_utf8_lossy(&output.stderr)
+        );
+
+        let entries: Vec<Value> = recording
+            .lines()
+            .map(|line| serde_json::from_str(line).unwrap())
+            .collect();
+        // The handshake the SDK makes by itself is recorded along with the ping
+        assert!(
+            entries.iter().any(|entry| entry["method"] == "initialize"
+                && entry["result"]["serverInfo"]["name"] == "recorded"),
+            "{}",
+            recording
+        );
+        assert!(
+            entries.iter().any(|entry| entry["method"] == "ping"),
+            "{}",
+            recording
+        );
+    }
+
+    #[tokio::test]
+    async fn test_cancel_in_flight_targets_foreground_call() {
+        let (addr, received) = fake_server_logged(|method, _| match method {
+            "initialize" => (Duration::ZERO, fake_init("fake", json!({ "tools": {} }))),
+            "tools/call" => (Duration::from_secs(5), text_result("done")),
+            _ => (Duration::ZERO, json!({})),
+        })
+        .await;
+        let server = fake_connect(&addr, "fake").await;
+        let session = &server.session;
+        let call = |name: &'static str| {
+            {{crate_name}}::call_tool(session, Duration::from_secs(10), name, HashMap::new())
+        };
+        let _in_flight = IN_FLIGHT_TESTS.lock().await;
+
+        // A background job sends its request after the foreground call has
+        // sent its own, so the client's latest request is the job's
+        let foreground = track_call(session, call("foreground"));
+        let background = BACKGROUND_JOB.scope((), async {
+            tokio::time::sleep(Duration::from_millis(200)).await;
+            track_call(session, call("background")).await
+        });
+        tokio::select! {
+            _ = foreground => panic!("the call should still be running"),
+            _ = background => panic!("the job should still be running"),
+            _ = tokio::time::sleep(Duration::from_millis(500)) => {}
+        }
+        cancel_in_flight().await;
+        tokio::time::sleep(Duration::from_millis(200)).await;
+
+        let received = received.lock().unwrap().clone();
+        let id_of = |name: &str| {
+            received
+                .iter()
+                .find(|message| {
+                    message["method"] == "tools/call" && message["params"]["name"] == name
+                })
+                .map(|message| message["id"].clone())
+                .unwrap()
+        };
+        let cancelled: Vec<&Value> = received
+            .iter()
+            .filter(|message| message["method"] == "notifications/cancelled")
+            .map(|message| &message["params"]["requestId"])
+            .collect();
+        assert_eq!(cancelled, [&id_of("foreground")]);
+        assert_ne!(id_of("foreground"), id_of("background"));
+    }
+
+    #[tokio::test]
+    async fn test_cancel_in_flight_covers_held_back_repeat_runs() {
+        let (addr, received) = fake_server_logged(|method, _| match method {
+            "initialize" => (Duration::ZERO, fake_init("fake", json!({ "tools": {} }))),
+            "tools/call" => (Duration::from_secs(5), text_result("done")),
+            _ => (Duration::ZERO, json!({})),
+        })
+        .await;
+        let server = fake_connect(&addr, "fake").await;
+        let session = &server.session;
+        let _in_flight = IN_FLIGHT_TESTS.lock().await;
+
+        // Like a `--repeat` run before the last, whose output is held back
+        let call =
+            {{crate_name}}::call_tool(session, Duration::from_secs(10), "slow", HashMap::new());
+        let run = JOB_OUTPUT.scope(Default::default(), track_call(session, call));
+        tokio::select! {
+            _ = run => panic!("the call should still be running"),
+            _ = tokio::time::sleep(Duration::from_millis(300)) => {}
+        }
+        cancel_in_flight().await;
+        tokio::time::sleep(Duration::from_millis(200)).await;
+
+        let received = received.lock().unwrap().clone();
+        let call_id = received
+            .iter()
+            .find(|message| message["method"] == "tools/call")
+            .map(|message| message["id"].clone())
+            .unwrap();
+        assert!(
+            received
+                .iter()
+                .any(|message| message["method"] == "notifications/cancelled"
+                    && message["params"]["requestId"] == call_id),
+            "{:?}",
+            received
+        );
+    }
+
+    #[tokio::test]
+    async fn test_reconnect_updates_init() {
+        use std::sync::atomic::{AtomicUsize, Ordering};
+
+        // Each connection gets a server one version newer, as after a restart
+        let connections = AtomicUsize::new(0);
+        let addr = fake_server(move |method, _| match method {
+            "initialize" => {
+                let version = connections.fetch_add(1, Ordering::SeqCst) + 1;
+                let mut init = fake_init("fake", json!({ "tools": {} }));
+                init["serverInfo"]["version"] = json!(format!("{}.0", version));
+                (Duration::ZERO, init)
+            }
+            _ => (Duration::ZERO, json!({})),
+        })
+        .await;
+        let server = fake_connect(&addr, "fake").await;
+        assert_eq!(server.init().server_info.version, "1.0");
+
+        let cli = Cli::try_parse_from(&["test", "interactive"]).unwrap();
+        reconnect(&server, &Options::from_cli(&cli)).await.unwrap();
+        assert_eq!(server.init().server_info.version, "2.0");
+        assert!(ListingKind::Tools.supported_by(&server.init()));
+    }
+
+    #[tokio::test]
+    async fn test_switch_server() {
+        let (addr, received) = fake_server_logged(|method, _| match method {
+            "initialize" => (
+                Duration::ZERO,
+                fake_init("logger", json!({ "logging": {} })),
+            ),
+            _ => (Duration::ZERO, json!({})),
+        })
+        .await;
+        let cli =
+            Cli::try_parse_from(&["test", "--server-log-level", "warning", "interactive"]).unwrap();
+        let opts = Options::from_cli(&cli);
+        let connector = Connector::from_cli(&cli);
+        let mut servers = Vec::new();
+
+        let target = format!("tcp://{}", addr);
+        let printed = JOB_OUTPUT
+            .scope(Default::default(), async {
+                switch_server(
+                    &mut servers,
+                    &opts,
+                    &connector,
+                    "connect",
+                    &[target.as_str()],
+                )
+                .await
+                .unwrap();
+                JOB_OUTPUT.with(|output| output.take())
+            })
+            .await;
+        assert_eq!(printed, "Connected to logger v1.0\n");
+        assert_eq!(servers.len(), 1);
+        // The new server gets --server-log-level like the ones connected at startup
+        let set_level: Vec<Value> = received
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|message| message["method"] == "logging/setLevel")
+            .map(|message| message["params"].clone())
+            .collect();
+        assert_eq!(set_level, [json!({ "level": "warning" })]);
+
+        JOB_OUTPUT
+            .scope(
+                Default::default(),
+                switch_server(&mut servers, &opts, &connector, "disconnect", &[]),
+            )
+            .await
+            .unwrap();
+        assert!(servers.is_empty());
+        assert!(
+            switch_server(&mut servers, &opts, &connector, "connect", &[])
+                .await
+                .is_err()
+        );
+    }
+
+    #[tokio::test]
+    async fn test_split_resources_keeps_going_past_bad_base64() {
+        let addr = fake_server(|method, params| match method {
+            "initialize" => (
+                Duration::ZERO,
+                fake_init("fake", json!({ "resources": {} })),
+            ),
+            "resources/read" => {
+                let uri = params["uri"].as_str().unwrap_or_default();
+                let content = if uri.starts_with("blob") {
+                    json!({ "uri": uri, "blob": "not base64!" })
+                } else {
+                    json!({ "uri": uri, "text": "hello" })
+                };
+                (Duration::ZERO, json!({ "contents": [content] }))
+            }
+            _ => (Duration::ZERO, json!({})),
+        })
+        .await;
+        let server = fake_connect(&addr, "fake").await;
+        let cli = Cli::try_parse_from(&["test", "--output", "json", "ping"]).unwrap();
+        let opts = Options::from_cli(&cli);
+        let dir = std::env::temp_dir().join(format!(
+            "{{project-name}}-split-test-{}",
+            std::process::id()
+        ));
+        let uris = ["blob://broken".to_string(), "file:///after.txt".to_string()];
+
+        let (result, printed) = JOB_OUTPUT
+            .scope(Default::default(), async {
+                let result = split_resources(&server.session, &opts, &uris, None, &dir).await;
+                (result, JOB_OUTPUT.with(|output| output.take()))
+            })
+            .await;
+        let written = std::fs::read_to_string(dir.join("after.txt"));
+        let _ = std::fs::remove_dir_all(&dir);
+
+        let error = result.unwrap_err();
+        assert!(error.to_string().contains("1 of 2 resources"), "{}", error);
+        assert_eq!(written.unwrap(), "hello");
+        let manifest: Vec<Value> = serde_json::from_str(&printed).unwrap();
+        assert_eq!(manifest.len(), 2);
+        assert_eq!(manifest[0]["uri"], "blob://broken");
+        assert!(
+            manifest[0]["error"].as_str().unwrap().contains("base64"),
+            "{}",
+            printed
+        );
+        assert_eq!(manifest[1]["kind"], "text");
+    }
+
+    #[tokio::test]
+    async fn test_count_everything_marks_unsupported_kinds() {
+        let addr = fake_server(|method, _| match method {
+            "initialize" => (
+                Duration::ZERO,
+                fake_init("fake", json!({ "tools": {}, "prompts": {} })),
+            ),
+            "tools/list" => {
+                let tool = json!({ "name": "echo", "inputSchema": { "type": "object" } });
+                (Duration::ZERO, json!({ "tools": [tool] }))
+            }
+            // Advertised, but answered with something that isn't a listing
+            "prompts/list" => (Duration::ZERO, json!({ "prompts": "none" })),
+            _ => (Duration::ZERO, json!({})),
+        })
+        .await;
+        let server = fake_connect(&addr, "fake").await;
+        let cli = Cli::try_parse_from(&["test", "interactive"]).unwrap();
+        let opts = Options::from_cli(&cli);
+
+        let printed = JOB_OUTPUT
+            .scope(Default::default(), async {
+                count_everything(&server, &opts).await.unwrap();
+                JOB_OUTPUT.with(|output| output.take())
+            })
+            .await;
+        assert_eq!(
+            printed,
+            "  tools: 1\n  resources: not supported\n  resource templates: not supported\n  prompts: unavailable\n"
+        );
+        assert_eq!(
+            count_listing(&server, &opts, ListingKind::Resources)
+                .await
+                .unwrap(),
+            None
+        );
+    }
+
+    #[tokio::test]
+    async fn test_json_rpc_id_prefixes_request_ids() {
+        let (addr, received) = fake_server_logged(|method, _| match method {
+            "initialize" => (Duration::ZERO, fake_init("fake", json!({ "tools": {} }))),
+            _ => (Duration::ZERO, json!({})),
+        })
+        .await;
+        let cli = Cli::try_parse_from(&["test", "--json-rpc-id", "ci-run", "ping"]).unwrap();
+        let server = Connector::from_cli(&cli)
+            .connect(tcp_endpoint(&addr), "fake".to_string())
+            .await
+            .unwrap();
+        ping(&server.session, &Options::from_cli(&cli))
+            .await
+            .unwrap();
+
+        let received = received.lock().unwrap().clone();
+        let ids: Vec<&Value> = received
+            .iter()
+            .filter_map(|message| message.get("id"))
+            .collect();
+        assert!(ids.len() >= 2, "{:?}", received);
+        for id in ids {
+            assert!(
+                id.as_str().is_some_and(|id| id.starts_with("ci-run-")),
+                "{}",
+                id
+            );
+        }
+    }
 }
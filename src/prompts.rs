@@ -0,0 +1,85 @@
+//! Getting prompts and passing them on
+
+use anyhow::Result;
+use mcp_protocol_sdk::{
+    client::ClientSession,
+    protocol::types::{GetPromptResult, PromptMessage},
+};
+use serde_json::{json, Value};
+use std::collections::HashMap;
+use std::time::Duration;
+
+use crate::{client_handle, with_timeout};
+
+/// Get a prompt, filled in with `args`
+pub async fn get_prompt(
+    session: &ClientSession,
+    timeout: Duration,
+    name: &str,
+    args: HashMap<String, Value>,
+) -> Result<GetPromptResult> {
+    let client = client_handle(session).await;
+    let args = if args.is_empty() { None } else { Some(args) };
+    with_timeout(timeout, client.get_prompt(name.to_string(), args)).await
+}
+
+/// Prompt messages as a chat API `messages` array. Text becomes plain string
+/// content; images and embedded resources become content blocks.
+pub fn chat_messages(messages: &[PromptMessage]) -> Value {
+    use mcp_protocol_sdk::protocol::types::PromptContent;
+
+    let messages = messages.iter().map(|message| {
+        let content = match &message.content {
+            PromptContent::Text { text } => json!(text),
+            PromptContent::Image { data, mime_type } => json!([{
+                "type": "image",
+                "source": { "type": "base64", "media_type": mime_type, "data": data },
+            }]),
+            PromptContent::Resource { resource } => match &resource.text {
+                Some(text) => json!([{ "type": "text", "text": text }]),
+                None => json!([{
+                    "type": "document",
+                    "source": {
+                        "type": "base64",
+                        "media_type": resource.mime_type.as_deref().unwrap_or("application/octet-stream"),
+                        "data": resource.blob.as_deref().unwrap_or_default(),
+                    },
+                    "title": resource.uri,
+                }]),
+            },
+            // Newer content types are passed through as-is
+            #[allow(unreachable_patterns)]
+            other => json!([other]),
+        };
+        json!({ "role": message.role, "content": content })
+    });
+    Value::Array(messages.collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_chat_messages() {
+        let messages: Vec<PromptMessage> = serde_json::from_value(json!([
+            { "role": "user", "content": { "type": "text", "text": "Review this" } },
+            { "role": "assistant", "content": { "type": "image", "data": "aGk=", "mimeType": "image/png" } }
+        ]))
+        .unwrap();
+
+        assert_eq!(
+            chat_messages(&messages),
+            json!([
+                { "role": "user", "content": "Review this" },
+                {
+                    "role": "assistant",
+                    "content": [{
+                        "type": "image",
+                        "source": { "type": "base64", "media_type": "image/png", "data": "aGk=" }
+                    }]
+                }
+            ])
+        );
+    }
+}
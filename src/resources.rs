@@ -0,0 +1,34 @@
+//! Reading resources
+
+use anyhow::Result;
+use mcp_protocol_sdk::{client::ClientSession, protocol::types::ReadResourceResult};
+use std::time::Duration;
+use tracing::info;
+
+use crate::{client_handle, with_timeout};
+
+/// Read the contents of the resource at `uri`
+pub async fn read_resource(
+    session: &ClientSession,
+    timeout: Duration,
+    uri: &str,
+) -> Result<ReadResourceResult> {
+    let client = client_handle(session).await;
+    with_timeout(timeout, client.read_resource(uri.to_string(), None)).await
+}
+
+/// Read each of `uris` in turn over the one session, returning their
+/// results in the same order. A resource that can't be read doesn't stop
+/// the others; its error takes its place.
+pub async fn read_resources(
+    session: &ClientSession,
+    timeout: Duration,
+    uris: &[String],
+) -> Vec<Result<ReadResourceResult>> {
+    let mut results = Vec::with_capacity(uris.len());
+    for uri in uris {
+        info!("Reading resource: {}", uri);
+        results.push(read_resource(session, timeout, uri).await);
+    }
+    results
+}
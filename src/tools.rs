@@ -0,0 +1,277 @@
+//! Calling tools: checking arguments, and retrying calls that fail transiently
+
+use anyhow::{anyhow, bail, Context, Result};
+use mcp_protocol_sdk::{
+    client::ClientSession,
+    core::error::McpError,
+    protocol::types::{CallToolResult, Content, Tool},
+};
+use serde_json::Value;
+use std::collections::HashMap;
+use std::future::Future;
+use std::time::Duration;
+use tracing::warn;
+
+use crate::{client_handle, list_tools, with_timeout, TimedOut};
+
+/// Call a tool. A result with `isError` set is still `Ok`; check
+/// `result.is_error` to tell whether the tool itself failed.
+pub async fn call_tool(
+    session: &ClientSession,
+    timeout: Duration,
+    name: &str,
+    args: HashMap<String, Value>,
+) -> Result<CallToolResult> {
+    let client = client_handle(session).await;
+    let args = if args.is_empty() { None } else { Some(args) };
+    with_timeout(timeout, client.call_tool(name.to_string(), args)).await
+}
+
+/// How [`call_tool_with`] makes a call
+#[derive(Clone, Debug)]
+pub struct CallOptions {
+    /// Timeout for each attempt
+    pub timeout: Duration,
+    /// Further attempts to make after a transient failure
+    pub retries: u32,
+    /// Check the arguments against the tool's input schema before calling
+    pub validate: bool,
+}
+
+/// Call a tool, first checking `args` against its input schema if
+/// `options.validate` is set, and retrying transient failures. A tool the
+/// server doesn't list, or a listing that fails, leaves the check to the
+/// server.
+pub async fn call_tool_with(
+    session: &ClientSession,
+    options: &CallOptions,
+    name: &str,
+    args: HashMap<String, Value>,
+) -> Result<CallToolResult> {
+    if options.validate {
+        match list_tools(session, options.timeout, None).await {
+            Ok(tools) => {
+                if let Some(tool) = tools.tools.iter().find(|tool| tool.name == name) {
+                    validate_tool_args(tool, &args)
+                        .with_context(|| format!("invalid arguments for tool '{}'", name))?;
+                }
+            }
+            Err(e) => warn!(
+                "Skipping argument validation, could not list tools: {:#}",
+                e
+            ),
+        }
+    }
+    retry_tool_call(
+        name,
+        options.retries,
+        || call_tool(session, options.timeout, name, args.clone()),
+        |_| {},
+    )
+    .await
+}
+
+/// Base delay before retrying a tool call, growing with each attempt
+pub const RETRY_DELAY: Duration = Duration::from_millis(500);
+
+/// Make a tool call with `call`, making it again up to `retries` more times
+/// while it fails transiently and waiting a little longer before each retry.
+/// `on_retry` is given the number of each retry before it is made. Once the
+/// retries run out, the last outcome is returned as it is.
+pub async fn retry_tool_call<F, Fut>(
+    name: &str,
+    retries: u32,
+    mut call: F,
+    mut on_retry: impl FnMut(u32),
+) -> Result<CallToolResult>
+where
+    F: FnMut() -> Fut,
+    Fut: Future<Output = Result<CallToolResult>>,
+{
+    let mut attempt = 0;
+    loop {
+        let result = call().await;
+        let transient = match &result {
+            Ok(result) => is_transient_tool_error(result),
+            Err(e) => is_transient_error(e),
+        };
+        if !transient || attempt >= retries {
+            return result;
+        }
+        attempt += 1;
+        on_retry(attempt);
+        warn!(
+            "Tool '{}' failed with a transient error, retrying (attempt {}/{})",
+            name, attempt, retries
+        );
+        tokio::time::sleep(RETRY_DELAY * attempt).await;
+    }
+}
+
+/// Whether a failed request is worth retrying: it timed out, or the server
+/// answered with an internal error (-32603) or request timeout (-32001)
+pub fn is_transient_error(error: &anyhow::Error) -> bool {
+    error.chain().any(|cause| {
+        cause.is::<TimedOut>()
+            || matches!(
+                cause.downcast_ref::<McpError>(),
+                Some(McpError::Protocol {
+                    code: -32603 | -32001,
+                    ..
+                })
+            )
+    })
+}
+
+/// Whether a tool result with `isError` marks itself as safe to retry with
+/// `"retryable": true` in its structured content
+pub fn is_transient_tool_error(result: &CallToolResult) -> bool {
+    result.is_error.unwrap_or(false)
+        && result
+            .structured_content
+            .as_ref()
+            .and_then(|content| content.get("retryable"))
+            .and_then(Value::as_bool)
+            .unwrap_or(false)
+}
+
+/// The first text block of a tool result, if it has one
+pub fn tool_text(result: &CallToolResult) -> Option<String> {
+    result.content.iter().find_map(|content| match content {
+        Content::Text { text } => Some(text.clone()),
+        _ => None,
+    })
+}
+
+/// Parse a JSON object of arguments; empty input means no arguments
+pub fn parse_args(args_json: &str) -> Result<HashMap<String, Value>> {
+    if args_json.trim().is_empty() || args_json == "{}" {
+        Ok(HashMap::new())
+    } else {
+        Ok(serde_json::from_str(args_json)?)
+    }
+}
+
+/// Check `args` against a JSON schema, listing every field that is wrong or missing
+pub fn validate_args(schema: &Value, args: &Value) -> Result<()> {
+    let validator = jsonschema::validator_for(schema)
+        .map_err(|e| anyhow!("tool has an invalid input schema: {}", e))?;
+    let problems: Vec<String> = validator
+        .iter_errors(args)
+        .map(|error| {
+            let path = error.instance_path.to_string();
+            if path.is_empty() {
+                error.to_string()
+            } else {
+                format!("{}: {}", path, error)
+            }
+        })
+        .collect();
+
+    if problems.is_empty() {
+        Ok(())
+    } else {
+        bail!("{}", problems.join("; "))
+    }
+}
+
+/// Check `args` against `tool`'s input schema
+pub fn validate_tool_args(tool: &Tool, args: &HashMap<String, Value>) -> Result<()> {
+    let schema = serde_json::to_value(&tool.input_schema)?;
+    let args = Value::Object(args.clone().into_iter().collect());
+    validate_args(&schema, &args)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn test_validate_args() {
+        let schema = json!({
+            "type": "object",
+            "properties": {
+                "message": { "type": "string" },
+                "count": { "type": "integer" }
+            },
+            "required": ["message"]
+        });
+
+        assert!(validate_args(&schema, &json!({ "message": "hi", "count": 2 })).is_ok());
+
+        let error = validate_args(&schema, &json!({ "count": "two" }))
+            .unwrap_err()
+            .to_string();
+        assert!(error.contains("message"), "{}", error);
+        assert!(error.contains("/count"), "{}", error);
+    }
+
+    #[test]
+    fn test_transient_errors() {
+        let timeout =
+            anyhow::Error::from(TimedOut(Duration::from_secs(30))).context("Error calling tool");
+        assert!(is_transient_error(&timeout));
+        assert!(!is_transient_error(&anyhow!("bad args")));
+
+        let result: CallToolResult = serde_json::from_value(json!({
+            "content": [],
+            "isError": true,
+            "structuredContent": { "retryable": true }
+        }))
+        .unwrap();
+        assert!(is_transient_tool_error(&result));
+        let result: CallToolResult =
+            serde_json::from_value(json!({ "content": [], "isError": true })).unwrap();
+        assert!(!is_transient_tool_error(&result));
+    }
+
+    #[tokio::test]
+    async fn test_retry_tool_call() {
+        let transient = || Err(anyhow::Error::from(TimedOut(Duration::from_secs(1))));
+        let ok: CallToolResult = serde_json::from_value(json!({ "content": [] })).unwrap();
+
+        // Transient failures are retried until a call gets through
+        let mut outcomes = vec![Ok(ok), transient()];
+        let mut retries = Vec::new();
+        let result = retry_tool_call(
+            "flaky",
+            3,
+            || std::future::ready(outcomes.pop().unwrap()),
+            |attempt| retries.push(attempt),
+        )
+        .await;
+        assert!(result.is_ok());
+        assert_eq!(retries, [1]);
+
+        // ...and given up on once the retries run out
+        let mut calls = 0;
+        let result = retry_tool_call(
+            "down",
+            1,
+            || {
+                calls += 1;
+                std::future::ready(transient())
+            },
+            |_| {},
+        )
+        .await;
+        assert!(result.is_err());
+        assert_eq!(calls, 2);
+
+        // Other errors aren't retried at all
+        let mut calls = 0;
+        let result = retry_tool_call(
+            "broken",
+            3,
+            || {
+                calls += 1;
+                std::future::ready(Err(anyhow!("bad args")))
+            },
+            |_| {},
+        )
+        .await;
+        assert!(result.is_err());
+        assert_eq!(calls, 1);
+    }
+}